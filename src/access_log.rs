@@ -0,0 +1,314 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hyper::header::{HOST, REFERER, USER_AGENT};
+use hyper::{Body, Request, StatusCode, Version};
+use log::warn;
+use serde_json::json;
+
+use crate::config::AccessLogFormat;
+
+/// Request extension holding the address of the client, if known
+#[derive(Clone, Copy, Debug)]
+pub struct RemoteAddr(pub SocketAddr);
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Split `time` into UTC year, month, day, hours, minutes and seconds
+fn civil_time(time: SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400) as u32);
+    // Days to civil date, from https://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Format `time` as in the common log format, e.g. `10/Oct/2000:13:55:36 +0000`
+fn clf_time(time: SystemTime) -> String {
+    let (year, month, day, h, m, s) = civil_time(time);
+    let month = MONTHS[month as usize - 1];
+    format!("{day:02}/{month}/{year}:{h:02}:{m:02}:{s:02} +0000")
+}
+
+/// Format `time` as RFC 3339, e.g. `2000-10-10T13:55:36Z`
+fn rfc3339_time(time: SystemTime) -> String {
+    let (year, month, day, h, m, s) = civil_time(time);
+    format!("{year}-{month:02}-{day:02}T{h:02}:{m:02}:{s:02}Z")
+}
+
+/// Quote a value for the common log format, or return `-` if it is missing
+fn quote(value: Option<&str>) -> String {
+    match value {
+        Some(value) => format!("\"{}\"", value.replace('\\', r"\\").replace('"', r#"\""#)),
+        None => "\"-\"".to_string(),
+    }
+}
+
+/// A request, captured before it is handled
+#[derive(Debug)]
+pub struct Entry {
+    remote_addr: Option<SocketAddr>,
+    time: SystemTime,
+    start: Instant,
+    method: String,
+    uri: String,
+    version: Version,
+    host: Option<String>,
+    user_agent: Option<String>,
+    referer: Option<String>,
+    pub tileset: Option<String>,
+    /// XYZ coordinates of a requested tile
+    pub tile: Option<(u32, u32, u32)>,
+}
+
+impl Entry {
+    pub fn new(request: &Request<Body>) -> Entry {
+        let header = |name| {
+            let value = request.headers().get(name)?.to_str().ok()?;
+            Some(value.to_string())
+        };
+        let uri = request.uri();
+        Entry {
+            remote_addr: request.extensions().get::<RemoteAddr>().map(|addr| addr.0),
+            time: SystemTime::now(),
+            start: Instant::now(),
+            method: request.method().to_string(),
+            uri: uri
+                .path_and_query()
+                .map_or(uri.path(), |p| p.as_str())
+                .to_string(),
+            version: request.version(),
+            host: uri.host().map(String::from).or_else(|| header(HOST)),
+            user_agent: header(USER_AGENT),
+            referer: header(REFERER),
+            tileset: None,
+            tile: None,
+        }
+    }
+
+    /// Format the entry of a completed request
+    fn format(
+        &self,
+        format: AccessLogFormat,
+        status: StatusCode,
+        bytes: Option<u64>,
+        duration: Duration,
+    ) -> String {
+        let status = status.as_u16();
+        let remote_addr = self.remote_addr.map(|addr| addr.ip().to_string());
+        if format == AccessLogFormat::Json {
+            return json!({
+                "time": rfc3339_time(self.time),
+                "remote_addr": remote_addr,
+                "method": self.method,
+                "path": self.uri,
+                "protocol": format!("{:?}", self.version),
+                "host": self.host,
+                "user_agent": self.user_agent,
+                "referer": self.referer,
+                "tileset": self.tileset,
+                "z": self.tile.map(|t| t.0),
+                "x": self.tile.map(|t| t.1),
+                "y": self.tile.map(|t| t.2),
+                "status": status,
+                "bytes": bytes,
+                "duration_ms": duration.as_secs_f64() * 1000.0,
+            })
+            .to_string();
+        }
+        let request = format!("{} {} {:?}", self.method, self.uri, self.version);
+        let mut line = format!(
+            "{} - - [{}] {} {status} {}",
+            remote_addr.as_deref().unwrap_or("-"),
+            clf_time(self.time),
+            quote(Some(&request)),
+            bytes
+                .filter(|&b| b > 0)
+                .map_or("-".to_string(), |b| b.to_string()),
+        );
+        if format == AccessLogFormat::Combined {
+            line.push(' ');
+            line.push_str(&quote(self.referer.as_deref()));
+            line.push(' ');
+            line.push_str(&quote(self.user_agent.as_deref()));
+        }
+        line
+    }
+}
+
+#[derive(Debug)]
+enum Writer {
+    Stderr,
+    File(PathBuf, LineWriter<File>),
+}
+
+fn open(path: &Path) -> io::Result<LineWriter<File>> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(LineWriter::new(file))
+}
+
+/// Writes one line per request to stderr or a file
+#[derive(Debug)]
+pub struct AccessLog {
+    writer: Mutex<Writer>,
+    format: AccessLogFormat,
+    /// One in `sample` successful tile requests is logged
+    sample: u64,
+    tile_requests: AtomicU64,
+}
+
+impl AccessLog {
+    /// Log to stderr if `destination` is `stderr`, or else append to the file it names
+    pub fn open(destination: &str, format: AccessLogFormat, sample: u64) -> io::Result<AccessLog> {
+        let writer = match destination {
+            "stderr" => Writer::Stderr,
+            path => Writer::File(PathBuf::from(path), open(Path::new(path))?),
+        };
+        Ok(AccessLog {
+            writer: Mutex::new(writer),
+            format,
+            sample: sample.max(1),
+            tile_requests: AtomicU64::new(0),
+        })
+    }
+
+    /// Reopen the log file, e.g. after it was moved away to rotate it
+    pub fn reopen(&self) {
+        let mut writer = self.writer.lock().unwrap();
+        if let Writer::File(path, file) = &mut *writer {
+            match open(path) {
+                Ok(reopened) => *file = reopened,
+                Err(err) => warn!("Cannot reopen access log {}: {err}", path.display()),
+            }
+        }
+    }
+
+    /// Log a completed request, unless it is a successful tile request left out by sampling
+    #[allow(clippy::manual_is_multiple_of)] // is_multiple_of needs Rust 1.87
+    pub fn log(&self, entry: &Entry, status: StatusCode, bytes: Option<u64>) {
+        let duration = entry.start.elapsed();
+        if entry.tile.is_some() && status.as_u16() < 400 && self.sample > 1 {
+            let count = self.tile_requests.fetch_add(1, Ordering::Relaxed);
+            if count % self.sample != 0 {
+                return;
+            }
+        }
+        let line = entry.format(self.format, status, bytes, duration);
+        let result = match &mut *self.writer.lock().unwrap() {
+            Writer::Stderr => writeln!(io::stderr(), "{line}"),
+            Writer::File(_, file) => writeln!(file, "{line}"),
+        };
+        if let Err(err) = result {
+            warn!("Cannot write access log: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn entry() -> Entry {
+        let mut request = Request::get("/services/world_cities/tiles/1/0/1.pbf?key=a")
+            .header(HOST, "localhost:3000")
+            .header(USER_AGENT, "curl/\"7\"")
+            .body(Body::empty())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(RemoteAddr("127.0.0.1:50000".parse().unwrap()));
+        let mut entry = Entry::new(&request);
+        entry.time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        entry.tileset = Some("world_cities".to_string());
+        entry.tile = Some((1, 0, 1));
+        entry
+    }
+
+    #[test]
+    fn format_times() {
+        let time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        assert_eq!(clf_time(time), "10/Oct/2000:13:55:36 +0000");
+        assert_eq!(rfc3339_time(time), "2000-10-10T13:55:36Z");
+        assert_eq!(rfc3339_time(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(1_709_210_096);
+        assert_eq!(rfc3339_time(leap_day), "2024-02-29T12:34:56Z");
+    }
+
+    #[test]
+    fn format_entries() {
+        let entry = entry();
+        let duration = Duration::from_millis(5);
+        assert_eq!(
+            entry.format(AccessLogFormat::Common, StatusCode::OK, Some(512), duration),
+            r#"127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /services/world_cities/tiles/1/0/1.pbf?key=a HTTP/1.1" 200 512"#
+        );
+        assert_eq!(
+            entry.format(
+                AccessLogFormat::Combined,
+                StatusCode::NO_CONTENT,
+                Some(0),
+                duration
+            ),
+            r#"127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /services/world_cities/tiles/1/0/1.pbf?key=a HTTP/1.1" 204 - "-" "curl/\"7\"""#
+        );
+        let line = entry.format(AccessLogFormat::Json, StatusCode::OK, Some(512), duration);
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["time"], "2000-10-10T13:55:36Z");
+        assert_eq!(json["remote_addr"], "127.0.0.1");
+        assert_eq!(json["path"], "/services/world_cities/tiles/1/0/1.pbf?key=a");
+        assert_eq!(json["host"], "localhost:3000");
+        assert_eq!(json["tileset"], "world_cities");
+        assert_eq!(
+            (&json["z"], &json["x"], &json["y"]),
+            (&1.into(), &0.into(), &1.into())
+        );
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["bytes"], 512);
+        assert_eq!(json["duration_ms"], 5.0);
+    }
+
+    #[test]
+    fn sample_and_reopen() {
+        let dir = TempDir::new("access-log").unwrap();
+        let path = dir.path().join("access.log");
+        let log = AccessLog::open(path.to_str().unwrap(), AccessLogFormat::Common, 3).unwrap();
+        let entry = entry();
+        for _ in 0..6 {
+            log.log(&entry, StatusCode::OK, Some(10));
+        }
+        // Failed requests are always logged
+        log.log(&entry, StatusCode::NOT_FOUND, None);
+        let lines = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(lines.lines().count(), 3);
+        assert!(lines.lines().last().unwrap().ends_with(" 404 -"));
+
+        let rotated = dir.path().join("access.log.1");
+        std::fs::rename(&path, &rotated).unwrap();
+        log.reopen();
+        log.log(&entry, StatusCode::NOT_FOUND, None);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+        assert_eq!(
+            std::fs::read_to_string(&rotated).unwrap().lines().count(),
+            3
+        );
+    }
+}
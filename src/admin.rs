@@ -0,0 +1,449 @@
+use std::path::PathBuf;
+
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::{body, Body, Method, Request, Response, StatusCode};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::directory::is_tile_directory;
+use crate::errors::Result;
+use crate::service::{bad_request, not_found, Context};
+use crate::tiles::{load_tileset, tileset_name, MetadataWarning, TileMeta};
+use crate::utils::DataFormat;
+
+static UNAUTHORIZED: &[u8] = b"Unauthorized";
+static METHOD_NOT_ALLOWED: &[u8] = b"Method Not Allowed";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminTilesetJSON {
+    pub id: String,
+    pub path: PathBuf,
+    pub image_type: DataFormat,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<MetadataWarning>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RegisterTileset {
+    path: PathBuf,
+    id: Option<String>,
+}
+
+impl AdminTilesetJSON {
+    fn new(id: &str, tile_meta: &TileMeta) -> AdminTilesetJSON {
+        AdminTilesetJSON {
+            id: id.to_string(),
+            path: tile_meta.path.clone(),
+            image_type: tile_meta.tile_format,
+            warnings: tile_meta.warnings.clone(),
+        }
+    }
+}
+
+fn unauthorized() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(WWW_AUTHENTICATE, "Bearer")
+        .body(UNAUTHORIZED.into())
+        .unwrap()
+}
+
+fn method_not_allowed() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .body(METHOD_NOT_ALLOWED.into())
+        .unwrap()
+}
+
+fn json_response<T: Serialize>(status: StatusCode, data: &T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(data).unwrap()))
+        .unwrap()
+}
+
+/// Compare two byte strings in constant time so the token can't be guessed by timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub(crate) fn is_authorized(request: &Request<Body>, token: &str) -> bool {
+    match request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    {
+        Some(provided) => constant_time_eq(provided.trim().as_bytes(), token.as_bytes()),
+        None => false,
+    }
+}
+
+/// Open the tileset at `path` on a blocking thread, so requests to other tilesets don't
+/// wait for SQLite or file system work
+async fn load(path: PathBuf, id: String) -> Result<TileMeta> {
+    tokio::task::spawn_blocking(move || load_tileset(&path, &id))
+        .await
+        .unwrap()
+}
+
+/// Open the tileset file or tile directory at `path` (relative paths are resolved against
+/// the tiles directory)
+/// and add it to the registry, replacing any tileset with the same id
+async fn register(context: &Context, path: PathBuf, id: Option<String>) -> Response<Body> {
+    let path = if path.is_relative() {
+        context.directory.join(path)
+    } else {
+        path
+    };
+    if !path.is_file() && !is_tile_directory(&path) {
+        return bad_request(format!("File does not exist: {}", path.display()));
+    }
+    let id = match id.or_else(|| tileset_name(&path).map(String::from)) {
+        Some(id) if !id.is_empty() && !id.contains(',') => id,
+        _ => return bad_request("Invalid tileset id".to_string()),
+    };
+    if context.tilesets.resolve(&id) != id {
+        return bad_request(format!("Tileset id is an alias: {id}"));
+    }
+    let tile_meta = match load(path.clone(), id.clone()).await {
+        Ok(tile_meta) => tile_meta,
+        Err(err) => return bad_request(format!("{err}")),
+    };
+
+    let data = AdminTilesetJSON::new(&id, &tile_meta);
+    let mut replaced = false;
+    context.tilesets.update(|tilesets| {
+        replaced = tilesets.insert(id.clone(), tile_meta).is_some();
+    });
+    info!("Registered tileset {id} from {}", path.display());
+    let status = if replaced {
+        StatusCode::OK
+    } else {
+        StatusCode::CREATED
+    };
+    json_response(status, &data)
+}
+
+fn unregister(context: &Context, id: &str) -> Response<Body> {
+    let id = context.tilesets.resolve(id);
+    let mut removed = false;
+    context.tilesets.update(|tilesets| {
+        removed = tilesets.remove(id).is_some();
+    });
+    if !removed {
+        return not_found();
+    }
+    info!("Unregistered tileset {id}");
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Re-open a registered tileset from its file, replacing its metadata and connection pool
+async fn reload(context: &Context, id: &str) -> Response<Body> {
+    let id = context.tilesets.resolve(id);
+    let current = match context.tilesets.get(id) {
+        Some(tile_meta) => tile_meta,
+        None => return not_found(),
+    };
+    let tile_meta = match load(current.path, current.id).await {
+        Ok(tile_meta) => tile_meta,
+        Err(err) => return bad_request(format!("{err}")),
+    };
+    let data = AdminTilesetJSON::new(id, &tile_meta);
+    context.tilesets.update(|tilesets| {
+        tilesets.insert(id.to_string(), tile_meta);
+    });
+    info!("Reloaded tileset {id}");
+    json_response(StatusCode::OK, &data)
+}
+
+/// Handle requests to `/admin/...`.
+///
+/// `GET /admin/tilesets` lists the registered tilesets, `POST /admin/tilesets` registers
+/// the file given in the JSON body (`{"path": "...", "id": "..."}`),
+/// `DELETE /admin/tilesets/<id>` unregisters a tileset and
+/// `POST /admin/tilesets/reload/<id>` re-opens it from disk.
+/// `GET /admin/cache` returns the statistics of the tile cache.
+pub async fn get_admin_service(
+    request: Request<Body>,
+    context: &Context,
+) -> Result<Response<Body>> {
+    let token = match &context.admin_token {
+        Some(token) => token,
+        None => return Ok(not_found()),
+    };
+    if !is_authorized(&request, token) {
+        return Ok(unauthorized());
+    }
+
+    let path = format!("{}/", request.uri().path().trim_end_matches('/'));
+    if path == "/admin/cache/" {
+        if request.method() != Method::GET {
+            return Ok(method_not_allowed());
+        }
+        return match context.tilesets.cache() {
+            Some(cache) => Ok(json_response(StatusCode::OK, &cache.stats())),
+            None => Ok(not_found()),
+        };
+    }
+    let route = match path.strip_prefix("/admin/tilesets/") {
+        Some(route) => route.trim_end_matches('/').to_string(),
+        None => return Ok(not_found()),
+    };
+
+    if route.is_empty() {
+        return match *request.method() {
+            Method::GET => {
+                let mut tilesets: Vec<AdminTilesetJSON> = context
+                    .tilesets
+                    .snapshot()
+                    .iter()
+                    .map(|(id, tile_meta)| AdminTilesetJSON::new(id, tile_meta))
+                    .collect();
+                tilesets.sort_by(|a, b| a.id.cmp(&b.id));
+                Ok(json_response(StatusCode::OK, &tilesets))
+            }
+            Method::POST => {
+                let data = match body::to_bytes(request.into_body()).await {
+                    Ok(data) => data,
+                    Err(err) => return Ok(bad_request(format!("{err}"))),
+                };
+                match serde_json::from_slice::<RegisterTileset>(&data) {
+                    Ok(r) => Ok(register(context, r.path, r.id).await),
+                    Err(err) => Ok(bad_request(format!("Invalid request body: {err}"))),
+                }
+            }
+            _ => Ok(method_not_allowed()),
+        };
+    }
+
+    match (request.method(), route.strip_prefix("reload/")) {
+        (&Method::POST, Some(id)) => Ok(reload(context, id).await),
+        (&Method::DELETE, _) => Ok(unregister(context, &route)),
+        _ => Ok(method_not_allowed()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TilesetConfig;
+    use crate::registry::Registry;
+    use crate::tiles::discover_tilesets;
+    use std::collections::HashMap;
+
+    fn context(admin_token: Option<&str>) -> Context {
+        Context {
+            tilesets: Registry::new(discover_tilesets(String::new(), &PathBuf::from("./tiles"))),
+            directory: PathBuf::from("./tiles"),
+            allowed_hosts: vec!["*".to_string()],
+            admin_token: admin_token.map(|t| t.to_string()),
+            ..Default::default()
+        }
+    }
+
+    async fn request(
+        context: &Context,
+        method: Method,
+        path: &str,
+        token: Option<&str>,
+        data: &str,
+    ) -> Response<Body> {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(format!("http://localhost{path}"));
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let request = request.body(Body::from(data.to_string())).unwrap();
+        get_admin_service(request, context).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn admin_disabled_without_token() {
+        let context = context(None);
+        let response = request(&context, Method::GET, "/admin/tilesets", None, "").await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn admin_requires_token() {
+        let context = context(Some("secret"));
+        let response = request(&context, Method::GET, "/admin/tilesets", None, "").await;
+        assert_eq!(response.status(), 401);
+        let response = request(&context, Method::GET, "/admin/tilesets", Some("wrong"), "").await;
+        assert_eq!(response.status(), 401);
+    }
+
+    #[tokio::test]
+    async fn list_tilesets() {
+        let context = context(Some("secret"));
+        let response = request(&context, Method::GET, "/admin/tilesets", Some("secret"), "").await;
+        assert_eq!(response.status(), 200);
+        let data: Vec<AdminTilesetJSON> =
+            serde_json::from_slice(&body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_eq!(data.len(), context.tilesets.snapshot().len());
+    }
+
+    #[tokio::test]
+    async fn register_unregister_and_reload() {
+        let context = context(Some("secret"));
+        let response = request(
+            &context,
+            Method::POST,
+            "/admin/tilesets",
+            Some("secret"),
+            r#"{"path": "world_cities.mbtiles", "id": "cities"}"#,
+        )
+        .await;
+        assert_eq!(response.status(), 201);
+        assert!(context.tilesets.contains("cities"));
+
+        let response = request(
+            &context,
+            Method::POST,
+            "/admin/tilesets/reload/cities",
+            Some("secret"),
+            "",
+        )
+        .await;
+        assert_eq!(response.status(), 200);
+
+        let response = request(
+            &context,
+            Method::DELETE,
+            "/admin/tilesets/cities",
+            Some("secret"),
+            "",
+        )
+        .await;
+        assert_eq!(response.status(), 204);
+        assert!(!context.tilesets.contains("cities"));
+        assert!(context.tilesets.contains("world_cities"));
+
+        let response = request(
+            &context,
+            Method::DELETE,
+            "/admin/tilesets/cities",
+            Some("secret"),
+            "",
+        )
+        .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn reload_and_unregister_alias() {
+        let config = TilesetConfig {
+            alias: Some("cities".to_string()),
+            ..Default::default()
+        };
+        let mut context = context(Some("secret"));
+        context.tilesets = context
+            .tilesets
+            .with_config(HashMap::from([("world_cities".to_string(), config)]));
+        let count = context.tilesets.snapshot().len();
+
+        let response = request(
+            &context,
+            Method::POST,
+            "/admin/tilesets/reload/cities",
+            Some("secret"),
+            "",
+        )
+        .await;
+        assert_eq!(response.status(), 200);
+        let data: AdminTilesetJSON =
+            serde_json::from_slice(&body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_eq!(data.id, "world_cities");
+        assert_eq!(context.tilesets.snapshot().len(), count);
+
+        // Aliases can't be registered as ids
+        let response = request(
+            &context,
+            Method::POST,
+            "/admin/tilesets",
+            Some("secret"),
+            r#"{"path": "world_cities.mbtiles", "id": "cities"}"#,
+        )
+        .await;
+        assert_eq!(response.status(), 400);
+
+        let response = request(
+            &context,
+            Method::DELETE,
+            "/admin/tilesets/cities",
+            Some("secret"),
+            "",
+        )
+        .await;
+        assert_eq!(response.status(), 204);
+        assert!(!context.tilesets.contains("world_cities"));
+    }
+
+    #[tokio::test]
+    async fn unregister_reload_directory() {
+        let context = context(Some("secret"));
+        let response = request(
+            &context,
+            Method::POST,
+            "/admin/tilesets",
+            Some("secret"),
+            r#"{"path": "world_cities.mbtiles", "id": "reload/cities"}"#,
+        )
+        .await;
+        assert_eq!(response.status(), 201);
+
+        let response = request(
+            &context,
+            Method::GET,
+            "/admin/tilesets/reload/cities",
+            Some("secret"),
+            "",
+        )
+        .await;
+        assert_eq!(response.status(), 405);
+
+        let response = request(
+            &context,
+            Method::DELETE,
+            "/admin/tilesets/reload/cities",
+            Some("secret"),
+            "",
+        )
+        .await;
+        assert_eq!(response.status(), 204);
+        assert!(!context.tilesets.contains("reload/cities"));
+    }
+
+    #[tokio::test]
+    async fn register_invalid_tileset() {
+        let context = context(Some("secret"));
+        let response = request(
+            &context,
+            Method::POST,
+            "/admin/tilesets",
+            Some("secret"),
+            r#"{"path": "invalid.mbtiles"}"#,
+        )
+        .await;
+        assert_eq!(response.status(), 400);
+        let response = request(
+            &context,
+            Method::POST,
+            "/admin/tilesets",
+            Some("secret"),
+            r#"{"path": "missing.mbtiles"}"#,
+        )
+        .await;
+        assert_eq!(response.status(), 400);
+    }
+}
@@ -0,0 +1,142 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use hyper::http::response::Builder;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use xxhash_rust::xxh3::xxh3_128;
+
+/// Validators used to answer conditional requests for a response body
+#[derive(Clone, Debug, PartialEq)]
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: Option<SystemTime>,
+}
+
+/// Strip the sub-second part, as HTTP dates only have a precision of one second
+fn truncate_to_seconds(time: SystemTime) -> SystemTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => UNIX_EPOCH + Duration::from_secs(duration.as_secs()),
+        Err(_) => time,
+    }
+}
+
+/// Check whether any of the entity tags in an `If-None-Match` header matches `etag`.
+/// Uses the weak comparison function, as required for `If-None-Match`.
+fn etag_matches(header: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    header
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+impl Validators {
+    /// Derive a strong ETag from the response body
+    pub fn new(data: &[u8], last_modified: Option<SystemTime>) -> Validators {
+        Validators {
+            etag: format!("\"{:032x}\"", xxh3_128(data)),
+            last_modified: last_modified.map(truncate_to_seconds),
+        }
+    }
+
+    /// Evaluate `If-None-Match` and `If-Modified-Since` (RFC 9110, section 13.2.2).
+    /// `If-Modified-Since` is ignored when `If-None-Match` is present.
+    pub fn is_not_modified(&self, headers: &HeaderMap) -> bool {
+        if let Some(if_none_match) = headers.get(IF_NONE_MATCH) {
+            return match if_none_match.to_str() {
+                Ok(value) => etag_matches(value, &self.etag),
+                Err(_) => false,
+            };
+        }
+        match (
+            self.last_modified,
+            headers
+                .get(IF_MODIFIED_SINCE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| httpdate::parse_http_date(v).ok()),
+        ) {
+            (Some(last_modified), Some(since)) => last_modified <= since,
+            _ => false,
+        }
+    }
+
+    pub fn apply(&self, mut response: Builder) -> Builder {
+        response = response.header(ETAG, &self.etag);
+        if let Some(last_modified) = self.last_modified {
+            response = response.header(LAST_MODIFIED, httpdate::fmt_http_date(last_modified));
+        }
+        response
+    }
+}
+
+/// Build the response for `data`, or a `304 Not Modified` response if the client's cached
+/// copy is still valid. `response` should already contain the other response headers.
+pub fn conditional_response(
+    request: &Request<Body>,
+    response: Builder,
+    data: impl Into<Bytes>,
+    last_modified: Option<SystemTime>,
+) -> Response<Body> {
+    let data = data.into();
+    let validators = Validators::new(&data, last_modified);
+    let response = validators.apply(response);
+    let cacheable = matches!(*request.method(), Method::GET | Method::HEAD);
+    if cacheable && validators.is_not_modified(request.headers()) {
+        return response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap();
+    }
+    response.body(Body::from(data)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn if_none_match() {
+        let validators = Validators::new(b"tile", None);
+        let mut headers = HeaderMap::new();
+        assert!(!validators.is_not_modified(&headers));
+
+        headers.insert(
+            IF_NONE_MATCH,
+            HeaderValue::from_str(&validators.etag).unwrap(),
+        );
+        assert!(validators.is_not_modified(&headers));
+
+        let etags = format!("\"other\", W/{}", validators.etag);
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_str(&etags).unwrap());
+        assert!(validators.is_not_modified(&headers));
+
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_static("*"));
+        assert!(validators.is_not_modified(&headers));
+
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_static("\"other\""));
+        assert!(!validators.is_not_modified(&headers));
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let modified = UNIX_EPOCH + Duration::from_millis(1_600_000_000_500);
+        let validators = Validators::new(b"tile", Some(modified));
+        let mut headers = HeaderMap::new();
+
+        let date = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        headers.insert(IF_MODIFIED_SINCE, HeaderValue::from_str(&date).unwrap());
+        assert!(validators.is_not_modified(&headers));
+
+        let date = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(1_599_999_999));
+        headers.insert(IF_MODIFIED_SINCE, HeaderValue::from_str(&date).unwrap());
+        assert!(!validators.is_not_modified(&headers));
+
+        // If-None-Match takes precedence
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_static("\"other\""));
+        let date = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        headers.insert(IF_MODIFIED_SINCE, HeaderValue::from_str(&date).unwrap());
+        assert!(!validators.is_not_modified(&headers));
+    }
+}
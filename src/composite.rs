@@ -0,0 +1,127 @@
+use serde_json::Value as JSONValue;
+use tilejson::{tilejson, Bounds, TileJSON};
+
+use crate::tiles::TileMeta;
+
+/// Return the union of the `vector_layers` in the `json` metadata of `tilesets`.
+/// Layers are identified by their `id`, the first tileset declaring a layer wins.
+fn merge_vector_layers(tilesets: &[TileMeta]) -> Vec<JSONValue> {
+    let mut layers: Vec<JSONValue> = Vec::new();
+    for tile_meta in tilesets {
+        let tileset_layers = tile_meta
+            .json
+            .as_ref()
+            .and_then(|json| json.get("vector_layers"))
+            .and_then(JSONValue::as_array);
+        for layer in tileset_layers.into_iter().flatten() {
+            let id = layer.get("id");
+            if !layers.iter().any(|l| l.get("id") == id) {
+                layers.push(layer.clone());
+            }
+        }
+    }
+    layers
+}
+
+fn merge_bounds(a: Bounds, b: Bounds) -> Bounds {
+    Bounds::new(
+        a.left.min(b.left),
+        a.bottom.min(b.bottom),
+        a.right.max(b.right),
+        a.top.max(b.top),
+    )
+}
+
+/// Build the TileJSON of a composite of `tilesets`: bounds and zoom range cover all of them,
+/// and `vector_layers` lists the layers of all of them (if any). The `tiles` URL is left empty.
+pub fn merge_tilejson(tilesets: &[TileMeta]) -> TileJSON {
+    let mut tilejson = tilejson! {
+        tilejson: "2.1.0".to_string(),
+        tiles: vec!["".to_string()],
+    };
+    let join = |values: Vec<&String>| match values.is_empty() {
+        true => None,
+        false => Some(
+            values
+                .into_iter()
+                .map(|v| v.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+    };
+    tilejson.name = join(
+        tilesets
+            .iter()
+            .filter_map(|t| t.tilejson.name.as_ref())
+            .collect(),
+    );
+    tilejson.attribution = join(
+        tilesets
+            .iter()
+            .filter_map(|t| t.tilejson.attribution.as_ref())
+            .collect(),
+    );
+    // Zoom ranges and bounds only restrict the composite if every tileset declares them
+    tilejson.minzoom = tilesets
+        .iter()
+        .map(|t| t.tilejson.minzoom)
+        .reduce(|a, b| Some(a?.min(b?)))
+        .flatten();
+    tilejson.maxzoom = tilesets
+        .iter()
+        .map(|t| t.tilejson.maxzoom)
+        .reduce(|a, b| Some(a?.max(b?)))
+        .flatten();
+    tilejson.bounds = tilesets
+        .iter()
+        .map(|t| t.tilejson.bounds)
+        .reduce(|a, b| Some(merge_bounds(a?, b?)))
+        .flatten();
+    tilejson.center = tilesets.iter().find_map(|t| t.tilejson.center);
+    let vector_layers = merge_vector_layers(tilesets);
+    if !vector_layers.is_empty() {
+        tilejson
+            .other
+            .insert("vector_layers".to_string(), JSONValue::Array(vector_layers));
+    }
+    tilejson
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiles::{get_directory_details, get_tile_details};
+    use std::path::PathBuf;
+
+    #[test]
+    fn merge_tileset_metadata() {
+        let cities = get_tile_details(
+            &PathBuf::from("./tiles/world_cities.mbtiles"),
+            "world_cities",
+        )
+        .unwrap();
+        let mut streets = get_directory_details(
+            &PathBuf::from("./tiles/directory/world_cities"),
+            "world_cities",
+        )
+        .unwrap();
+        streets.tilejson.bounds = Some(Bounds::new(-180.0, -10.0, 0.0, 70.0));
+        streets.tilejson.maxzoom = Some(8);
+        streets.json = Some(serde_json::json!({
+            "vector_layers": [{"id": "cities"}, {"id": "streets"}]
+        }));
+
+        let tilejson = merge_tilejson(&[cities, streets]);
+        assert_eq!(tilejson.minzoom, Some(0));
+        assert_eq!(tilejson.maxzoom, Some(8));
+        assert_eq!(
+            tilejson.bounds.unwrap(),
+            Bounds::new(-180.0, -37.818085, 174.763027, 70.0)
+        );
+        let layers = tilejson.other["vector_layers"].as_array().unwrap();
+        let ids: Vec<&JSONValue> = layers.iter().map(|l| &l["id"]).collect();
+        assert_eq!(ids, ["cities", "streets"]);
+        // The first tileset's description of a shared layer is kept
+        assert!(layers[0].get("fields").is_some());
+    }
+}
@@ -1,22 +1,282 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs::{read, read_to_string};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
 
-use clap::Parser;
+use clap::{ArgEnum, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use hyper::body::Bytes;
+use hyper::header::{HeaderName, HeaderValue};
 use log::warn;
+use serde::Deserialize;
 
 use crate::errors::{Error, Result};
-use crate::tiles;
+use crate::raster::is_raster_format;
+use crate::tiles::{self, TileMeta};
+use crate::tls::load_certified_key;
+use crate::utils::get_data_format;
+use crate::validate::validate_mbtiles;
+
+/// Response for tiles outside of a tileset's declared zoom range or bounds
+#[derive(ArgEnum, Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutOfBounds {
+    #[default]
+    NoContent,
+    NotFound,
+}
+
+/// Format of access log lines
+#[derive(ArgEnum, Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccessLogFormat {
+    /// The common log format
+    Common,
+    /// The combined log format, which adds the referer and user agent
+    #[default]
+    Combined,
+    Json,
+}
+
+/// Response for tiles missing from a tileset
+#[derive(Clone, Debug, PartialEq)]
+pub enum MissingTile {
+    NotFound,
+    NoContent,
+    /// A transparent tile of the tileset's size, or an empty vector tile
+    Blank,
+    /// A raster tile served in place of missing tiles of raster tilesets
+    Image(Bytes),
+}
+
+impl MissingTile {
+    /// Parse `not-found`, `no-content`, `blank` or the path of an image file
+    fn parse(value: &str) -> Result<MissingTile> {
+        Ok(match value {
+            "not-found" => MissingTile::NotFound,
+            "no-content" => MissingTile::NoContent,
+            "blank" => MissingTile::Blank,
+            path => {
+                let data = read(path)
+                    .map_err(|err| Error::Config(format!("Missing tile image {path}: {err}")))?;
+                if !is_raster_format(get_data_format(&data)) {
+                    return Err(Error::Config(format!(
+                        "Missing tile image {path} is not a PNG, JPEG or WebP image"
+                    )));
+                }
+                MissingTile::Image(data.into())
+            }
+        })
+    }
+}
+
+/// Responses for missing tiles, by tileset id
+#[derive(Clone, Debug, Default)]
+pub struct MissingTiles {
+    pub default: Option<MissingTile>,
+    pub tilesets: HashMap<String, MissingTile>,
+}
+
+impl MissingTiles {
+    /// Return the response for tiles missing from tileset `id`. Without a configured response,
+    /// raster tilesets serve blank tiles and others `204 No Content`, which is also used
+    /// in place of images for vector tilesets.
+    pub fn policy(&self, id: &str, tile_meta: &TileMeta) -> MissingTile {
+        let raster = is_raster_format(tile_meta.tile_format);
+        match self.tilesets.get(id).or(self.default.as_ref()) {
+            Some(MissingTile::Image(_)) if !raster => MissingTile::NoContent,
+            Some(policy) => policy.clone(),
+            None if raster => MissingTile::Blank,
+            None => MissingTile::NoContent,
+        }
+    }
+}
+
+/// Address the server listens on
+#[derive(Clone, Debug, PartialEq)]
+pub enum Bind {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Bind {
+    /// Parse `host:port`, `[ipv6]:port` or `unix:/path/to/socket`. Host names are resolved
+    /// and can stand for several addresses, e.g. `localhost:3000`.
+    fn parse(value: &str) -> Result<Vec<Bind>> {
+        if let Some(path) = value.strip_prefix("unix:") {
+            if !cfg!(unix) {
+                return Err(Error::Config(format!(
+                    "Unix sockets are not supported on this platform: {value}"
+                )));
+            }
+            if path.is_empty() {
+                return Err(Error::Config(format!("Missing socket path: {value}")));
+            }
+            return Ok(vec![Bind::Unix(PathBuf::from(path))]);
+        }
+        let addrs: Vec<SocketAddr> = value
+            .to_socket_addrs()
+            .map_err(|err| Error::Config(format!("Invalid bind address {value}: {err}")))?
+            .collect();
+        if addrs.is_empty() {
+            return Err(Error::Config(format!("{value} has no addresses")));
+        }
+        Ok(addrs.into_iter().map(Bind::Tcp).collect())
+    }
+}
+
+impl fmt::Display for Bind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bind::Tcp(addr) => write!(f, "http://{addr}"),
+            Bind::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Parse octal file permissions, e.g. `660`
+fn parse_mode(value: &str) -> Result<u32> {
+    match u32::from_str_radix(value, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(Error::Config(format!(
+            "Invalid socket mode {value}: expected octal permissions such as 660"
+        ))),
+    }
+}
+
+/// Settings of a single tileset in the configuration file
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TilesetConfig {
+    /// Id the tileset is served and listed under, besides the id derived from its path
+    pub alias: Option<String>,
+    /// Headers added to the tileset's tile and TileJSON responses
+    pub headers: BTreeMap<String, String>,
+    /// Whether the tileset's tiles are kept in the tile cache
+    pub cache: bool,
+    /// Response for missing tiles, as for `--missing-tile`
+    pub missing_tile: Option<String>,
+    /// Leave the tileset out of the `/services` listing. It is still served.
+    pub hidden: bool,
+    /// Whether `/ready` fails while the tileset can't be read
+    pub required: bool,
+}
+
+impl Default for TilesetConfig {
+    fn default() -> Self {
+        TilesetConfig {
+            alias: None,
+            headers: BTreeMap::new(),
+            cache: true,
+            missing_tile: None,
+            hidden: false,
+            required: true,
+        }
+    }
+}
+
+/// Contents of the `--config` file. Global settings have the names of the command line
+/// options, which take precedence over them.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+    directory: Option<PathBuf>,
+    port: Option<u16>,
+    bind: Option<Vec<String>>,
+    socket_mode: Option<String>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    allowed_hosts: Option<Vec<String>>,
+    headers: Option<BTreeMap<String, String>>,
+    disable_preview: Option<bool>,
+    strict: Option<bool>,
+    out_of_bounds: Option<OutOfBounds>,
+    overzoom: Option<u8>,
+    missing_tile: Option<String>,
+    cache_size: Option<usize>,
+    watch: Option<bool>,
+    watch_interval: Option<u64>,
+    shutdown_timeout: Option<u64>,
+    access_log: Option<String>,
+    access_log_format: Option<AccessLogFormat>,
+    access_log_sample: Option<u64>,
+    admin_token: Option<String>,
+    tilesets: HashMap<String, TilesetConfig>,
+}
+
+impl ConfigFile {
+    /// Read a TOML or YAML file, depending on its extension
+    fn read(path: &Path) -> Result<ConfigFile> {
+        let error = |err: &dyn std::fmt::Display| {
+            Error::Config(format!(
+                "Invalid configuration file {}: {err}",
+                path.display()
+            ))
+        };
+        let data = read_to_string(path).map_err(|err| error(&err))?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&data).map_err(|err| error(&err)),
+            Some("yaml" | "yml") => serde_yaml::from_str(&data).map_err(|err| error(&err)),
+            _ => Err(Error::Config(format!(
+                "Configuration file {} must have a .toml, .yaml or .yml extension",
+                path.display()
+            ))),
+        }
+    }
+}
+
+/// Check that a header from the configuration file can be sent
+fn validate_header(name: &str, value: &str) -> Result<(String, String)> {
+    if HeaderName::from_bytes(name.as_bytes()).is_err() || HeaderValue::from_str(value).is_err() {
+        return Err(Error::Config(format!("Invalid header: {name}: {value}")));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
 
 #[derive(Parser, Default, Debug)]
 #[clap(about = "A simple mbtiles server")]
 #[clap(version)]
 pub struct Args {
+    #[clap(
+        long,
+        short,
+        env = "MBTILESERVER_CONFIG",
+        help = "Configuration file in TOML or YAML format. Command line options take precedence over it."
+    )]
+    pub config: Option<PathBuf>,
     #[clap(long, short, default_value = "./tiles", help = "Tiles directory")]
     pub directory: PathBuf,
     #[clap(skip)]
     pub tilesets: HashMap<String, tiles::TileMeta>,
     #[clap(short, long, default_value_t = 3000, help = "Server port")]
     pub port: u16,
+    #[clap(
+        long,
+        short,
+        help = "Address to listen on, as host:port or unix:/path/to/socket, instead of port --port on all interfaces. Can be used multiple times."
+    )]
+    pub bind: Vec<String>,
+    #[clap(skip)]
+    pub binds: Vec<Bind>,
+    #[clap(
+        long,
+        parse(try_from_str = parse_mode),
+        help = "Permissions of Unix sockets in octal, e.g. 660"
+    )]
+    pub socket_mode: Option<u32>,
+    #[clap(
+        long,
+        requires = "tls-key",
+        help = "PEM certificate chain to serve HTTPS with. Reloaded when it changes."
+    )]
+    pub tls_cert: Option<PathBuf>,
+    #[clap(
+        long,
+        requires = "tls-cert",
+        help = "PEM private key of the TLS certificate"
+    )]
+    pub tls_key: Option<PathBuf>,
     #[clap(
         long,
         default_value = "localhost,127.0.0.1,[::1]",
@@ -34,18 +294,316 @@ pub struct Args {
     pub headers: Vec<(String, String)>,
     #[clap(long, help = "Disable preview map")]
     pub disable_preview: bool,
+    #[clap(
+        long,
+        arg_enum,
+        default_value = "no-content",
+        help = "Response for tiles outside of a tileset's zoom range or bounds"
+    )]
+    pub out_of_bounds: OutOfBounds,
+    #[clap(
+        long,
+        default_value_t = 0,
+        help = "Number of zoom levels past a tileset's maxzoom served by scaling up tiles at maxzoom. Overzoom is disabled when 0."
+    )]
+    pub overzoom: u8,
+    #[clap(
+        long,
+        help = "Response for missing tiles: not-found, no-content, blank or the path of an image file. Prefix with <tileset>= to set it for one tileset. Can be used multiple times."
+    )]
+    pub missing_tile: Vec<String>,
+    #[clap(skip)]
+    pub missing_tiles: MissingTiles,
+    #[clap(skip)]
+    pub tileset_config: HashMap<String, TilesetConfig>,
+    #[clap(
+        long,
+        default_value_t = 0,
+        help = "Size of the in-memory tile cache in megabytes. The cache is disabled when 0."
+    )]
+    pub cache_size: usize,
+    #[clap(
+        long,
+        help = "Reload tilesets when files in the tiles directory change"
+    )]
+    pub watch: bool,
+    #[clap(
+        long,
+        default_value_t = 5,
+        help = "Interval in seconds between scans of the tiles directory when watching"
+    )]
+    pub watch_interval: u64,
+    #[clap(
+        long,
+        default_value_t = 30,
+        help = "Seconds to wait for in-flight requests to complete after SIGTERM or SIGINT"
+    )]
+    pub shutdown_timeout: u64,
+    #[clap(
+        long,
+        help = "Write an access log to stderr (\"stderr\") or to a file, which is reopened on SIGHUP"
+    )]
+    pub access_log: Option<String>,
+    #[clap(
+        long,
+        arg_enum,
+        default_value = "combined",
+        help = "Format of the access log"
+    )]
+    pub access_log_format: AccessLogFormat,
+    #[clap(
+        long,
+        default_value_t = 1,
+        help = "Log one in this many successful tile requests. Other requests are always logged."
+    )]
+    pub access_log_sample: u64,
+    #[clap(
+        long,
+        env = "MBTILESERVER_ADMIN_TOKEN",
+        hide_env_values = true,
+        help = "Bearer token required by the /admin endpoints. The admin API is disabled when not set."
+    )]
+    pub admin_token: Option<String>,
+    #[clap(
+        long,
+        help = "Validate mbtiles files at startup and refuse to start if one is invalid"
+    )]
+    pub strict: bool,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    #[clap(
+        about = "Check mbtiles files for corruption and print a JSON report. Exits with status 1 if a file is invalid."
+    )]
+    Validate {
+        #[clap(help = "mbtiles files or directories to search for them [default: --directory]")]
+        paths: Vec<PathBuf>,
+    },
 }
 
 impl Args {
+    /// Parse the command line and merge in the `--config` file
+    pub fn load() -> Result<Self> {
+        Args::load_from(std::env::args_os())
+    }
+
+    pub fn load_from<I, T>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = Args::command()
+            .try_get_matches_from(args)
+            .unwrap_or_else(|err| err.exit());
+        let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+        if let Some(path) = &args.config {
+            let file = ConfigFile::read(path)?;
+            args.merge(file, &matches)?;
+        }
+        Ok(args)
+    }
+
+    /// Use the settings of the configuration `file` which are not set on the command line
+    fn merge(&mut self, file: ConfigFile, matches: &ArgMatches) -> Result<()> {
+        // Clap names arguments after the kebab-case field names
+        let from_cli = |field: &str| matches.occurrences_of(field.replace('_', "-").as_str()) > 0;
+        macro_rules! merge {
+            ($($field:ident),*) => {$(
+                if let Some(value) = file.$field {
+                    if !from_cli(stringify!($field)) {
+                        self.$field = value;
+                    }
+                }
+            )*};
+        }
+        merge!(
+            directory,
+            port,
+            bind,
+            allowed_hosts,
+            disable_preview,
+            strict,
+            out_of_bounds,
+            overzoom,
+            cache_size,
+            watch,
+            watch_interval,
+            shutdown_timeout,
+            access_log_format,
+            access_log_sample
+        );
+        if self.tls_cert.is_none() && self.tls_key.is_none() {
+            self.tls_cert = file.tls_cert;
+            self.tls_key = file.tls_key;
+        }
+        if let Some(mode) = file.socket_mode {
+            if !from_cli("socket_mode") {
+                self.socket_mode = Some(parse_mode(&mode)?);
+            }
+        }
+        if self.access_log.is_none() {
+            self.access_log = file.access_log;
+        }
+        if self.admin_token.is_none() {
+            self.admin_token = file.admin_token;
+        }
+        if let Some(headers) = file.headers {
+            if !from_cli("header") {
+                self.headers = headers
+                    .iter()
+                    .map(|(name, value)| validate_header(name, value))
+                    .collect::<Result<_>>()?;
+            }
+        }
+        // Missing tile responses from the command line are applied last, so they win
+        let mut missing_tile: Vec<String> = file.missing_tile.into_iter().collect();
+        for (id, config) in &file.tilesets {
+            if let Some(policy) = &config.missing_tile {
+                missing_tile.push(format!("{id}={policy}"));
+            }
+            for (name, value) in &config.headers {
+                validate_header(name, value)?;
+            }
+        }
+        missing_tile.append(&mut self.missing_tile);
+        self.missing_tile = missing_tile;
+        self.tileset_config = file.tilesets;
+        Ok(())
+    }
+
+    /// Validate all mbtiles files of the tiles directory, including those that can't be
+    /// loaded, and fail if any of them is invalid
+    fn validate_tilesets(&self) -> Result<()> {
+        let mut paths: Vec<(String, PathBuf)> =
+            tiles::discover_tileset_paths(String::new(), &self.directory)
+                .into_iter()
+                .filter(|(_, path)| path.extension().and_then(OsStr::to_str) == Some("mbtiles"))
+                .collect();
+        paths.sort();
+        let mut invalid = Vec::new();
+        for (id, path) in paths {
+            let report = validate_mbtiles(&path);
+            for issue in &report.warnings {
+                warn!("{id}: {}: {}", issue.check, issue.message);
+            }
+            for issue in &report.errors {
+                invalid.push(format!("{id}: {}: {}", issue.check, issue.message));
+            }
+        }
+        match invalid.is_empty() {
+            true => Ok(()),
+            false => Err(Error::Config(format!(
+                "Invalid tilesets:\n  {}",
+                invalid.join("\n  ")
+            ))),
+        }
+    }
+
     /// Update args after the initially parsing them with Clap
     pub fn post_parse(mut self) -> Result<Self> {
+        if self.watch && self.watch_interval == 0 {
+            return Err(Error::Config(
+                "Watch interval must be greater than 0".to_string(),
+            ));
+        }
+        if self.access_log_sample == 0 {
+            return Err(Error::Config(
+                "Access log sample must be greater than 0".to_string(),
+            ));
+        }
+        if self.overzoom > 16 {
+            return Err(Error::Config(
+                "Overzoom must be at most 16 zoom levels".to_string(),
+            ));
+        }
+        if self.cache_size.checked_mul(1024 * 1024).is_none() {
+            return Err(Error::Config(format!(
+                "Cache size of {} megabytes is too large",
+                self.cache_size
+            )));
+        }
         if !self.directory.is_dir() {
             return Err(Error::Config(format!(
                 "Directory does not exists: {}",
                 self.directory.display()
             )));
         }
+        self.binds = match self.bind.is_empty() {
+            true => vec![Bind::Tcp(SocketAddr::from(([0, 0, 0, 0], self.port)))],
+            false => {
+                let binds = self.bind.iter().map(|value| Bind::parse(value.trim()));
+                binds.collect::<Result<Vec<_>>>()?.concat()
+            }
+        };
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => {
+                load_certified_key(cert, key)?;
+            }
+            (None, None) => (),
+            _ => {
+                return Err(Error::Config(
+                    "TLS needs both a certificate and a key".to_string(),
+                ))
+            }
+        }
+        if self.strict {
+            self.validate_tilesets()?;
+        }
         self.tilesets = tiles::discover_tilesets(String::new(), &self.directory);
+        let mut aliases: HashMap<&str, &str> = HashMap::new();
+        for (id, config) in &self.tileset_config {
+            if !self.tilesets.contains_key(id) {
+                warn!("Configuration for unknown tileset {id}");
+            }
+            if let Some(alias) = &config.alias {
+                if alias.is_empty() || alias.contains(',') {
+                    return Err(Error::Config(format!(
+                        "Invalid alias {alias:?} for {id}: aliases must not be empty or contain ','"
+                    )));
+                }
+                if self.tilesets.contains_key(alias) {
+                    return Err(Error::Config(format!(
+                        "Alias {alias} for {id} is the id of another tileset"
+                    )));
+                }
+                if let Some(other) = aliases.insert(alias, id) {
+                    return Err(Error::Config(format!(
+                        "Alias {alias} is used for both {other} and {id}"
+                    )));
+                }
+            }
+        }
+        for value in &self.missing_tile {
+            match value.split_once('=') {
+                Some((tileset, policy)) => {
+                    let policy = MissingTile::parse(policy.trim())?;
+                    // Policies are looked up by tileset id, so aliases are resolved here
+                    let tileset = tileset.trim();
+                    let tileset = aliases.get(tileset).copied().unwrap_or(tileset);
+                    if let (MissingTile::Image(_), Some(tile_meta)) =
+                        (&policy, self.tilesets.get(tileset))
+                    {
+                        if !is_raster_format(tile_meta.tile_format) {
+                            return Err(Error::Config(format!(
+                                "Missing tile image for {tileset}, which is not a raster tileset"
+                            )));
+                        }
+                    }
+                    self.missing_tiles
+                        .tilesets
+                        .insert(tileset.to_string(), policy);
+                }
+                None => self.missing_tiles.default = Some(MissingTile::parse(value.trim())?),
+            }
+        }
+        if let Some(token) = &self.admin_token {
+            if token.trim().is_empty() {
+                return Err(Error::Config("Admin token must not be empty".to_string()));
+            }
+        }
         self.allowed_hosts
             .iter_mut()
             .for_each(|v| *v = v.trim().to_string());
@@ -70,6 +628,7 @@ impl Args {
 }
 
 #[cfg(test)]
+#[allow(clippy::needless_borrows_for_generic_args)]
 mod tests {
     use super::*;
     use tempdir::TempDir;
@@ -132,4 +691,318 @@ mod tests {
             .unwrap();
         assert_eq!(args.headers, vec![]);
     }
+
+    #[test]
+    fn test_cache_size() {
+        let args = Args::try_parse_from(["", "--cache-size", "64"])
+            .unwrap()
+            .post_parse()
+            .unwrap();
+        assert_eq!(args.cache_size, 64);
+        let too_large = usize::MAX.to_string();
+        let args = Args::try_parse_from(["", "--cache-size", &too_large])
+            .unwrap()
+            .post_parse();
+        assert!(matches!(args, Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_missing_tiles() {
+        let args = Args::try_parse_from([
+            "",
+            "--missing-tile",
+            "not-found",
+            "--missing-tile",
+            "geography-class-jpg=./tiles/world.png",
+            "--missing-tile",
+            "world_cities = blank",
+        ])
+        .unwrap()
+        .post_parse()
+        .unwrap();
+        let missing_tiles = args.missing_tiles;
+        assert_eq!(missing_tiles.default, Some(MissingTile::NotFound));
+        let policy = |id: &str| missing_tiles.policy(id, &args.tilesets[id]);
+        assert!(matches!(
+            policy("geography-class-jpg"),
+            MissingTile::Image(_)
+        ));
+        assert_eq!(policy("world_cities"), MissingTile::Blank);
+        assert_eq!(policy("geography-class-png"), MissingTile::NotFound);
+        assert_eq!(
+            MissingTiles::default()
+                .policy("geography-class-png", &args.tilesets["geography-class-png"]),
+            MissingTile::Blank
+        );
+
+        for value in [
+            "./tiles/world_cities.mbtiles",
+            "world_cities=./tiles/world.png",
+        ] {
+            let args = Args::try_parse_from(["", "--missing-tile", value])
+                .unwrap()
+                .post_parse();
+            assert!(matches!(args, Err(Error::Config(_))));
+        }
+    }
+
+    #[test]
+    fn test_missing_tile_aliases() {
+        let dir = TempDir::new("config").unwrap();
+        let toml = dir.path().join("config.toml");
+        std::fs::write(
+            &toml,
+            "[tilesets.world_cities]\nalias = \"cities\"\n\n\
+             [tilesets.geography-class-jpg]\nalias = \"jpg\"\n",
+        )
+        .unwrap();
+        let config = toml.to_str().unwrap();
+        let args = Args::load_from([
+            "",
+            "--config",
+            config,
+            "--missing-tile",
+            "cities=not-found",
+            "--missing-tile",
+            "jpg=./tiles/world.png",
+        ])
+        .unwrap()
+        .post_parse()
+        .unwrap();
+        let missing_tiles = args.missing_tiles;
+        assert_eq!(
+            missing_tiles.policy("world_cities", &args.tilesets["world_cities"]),
+            MissingTile::NotFound
+        );
+        assert!(matches!(
+            missing_tiles.policy("geography-class-jpg", &args.tilesets["geography-class-jpg"]),
+            MissingTile::Image(_)
+        ));
+        assert!(!missing_tiles.tilesets.contains_key("cities"));
+
+        let args = Args::load_from([
+            "",
+            "--config",
+            config,
+            "--missing-tile",
+            "cities=./tiles/world.png",
+        ])
+        .unwrap()
+        .post_parse();
+        assert!(matches!(args, Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_config_file() {
+        let dir = TempDir::new("config").unwrap();
+        let toml = dir.path().join("config.toml");
+        std::fs::write(
+            &toml,
+            r#"
+port = 4000
+allowed-hosts = ["example.com"]
+out-of-bounds = "not-found"
+missing-tile = "no-content"
+
+[headers]
+cache-control = "public, max-age=3600"
+
+[tilesets.world_cities]
+alias = "cities"
+cache = false
+missing-tile = "blank"
+headers = { x-tileset = "cities" }
+
+[tilesets.geography-class-png]
+hidden = true
+"#,
+        )
+        .unwrap();
+        let config = toml.to_str().unwrap();
+        let args = Args::load_from(["", "--config", config, "-p", "5000"])
+            .unwrap()
+            .post_parse()
+            .unwrap();
+        // The command line takes precedence over the file
+        assert_eq!(args.port, 5000);
+        assert_eq!(args.allowed_hosts, ["example.com"]);
+        assert_eq!(args.out_of_bounds, OutOfBounds::NotFound);
+        assert_eq!(
+            args.headers,
+            [(
+                "cache-control".to_string(),
+                "public, max-age=3600".to_string()
+            )]
+        );
+        assert_eq!(args.missing_tiles.default, Some(MissingTile::NoContent));
+        assert_eq!(
+            args.missing_tiles.tilesets["world_cities"],
+            MissingTile::Blank
+        );
+        let cities = &args.tileset_config["world_cities"];
+        assert_eq!(cities.alias.as_deref(), Some("cities"));
+        assert!(!cities.cache);
+        assert!(args.tileset_config["geography-class-png"].hidden);
+        assert!(args.tileset_config["geography-class-png"].cache);
+
+        let args = Args::load_from(["", "--config", config, "--missing-tile", "not-found"])
+            .unwrap()
+            .post_parse()
+            .unwrap();
+        assert_eq!(args.port, 4000);
+        assert_eq!(args.missing_tiles.default, Some(MissingTile::NotFound));
+
+        let yaml = dir.path().join("config.yaml");
+        std::fs::write(
+            &yaml,
+            "watch: true\nwatch-interval: 10\ntilesets:\n  world_cities:\n    hidden: true\n",
+        )
+        .unwrap();
+        let args = Args::load_from(["", "-c", yaml.to_str().unwrap()])
+            .unwrap()
+            .post_parse()
+            .unwrap();
+        assert!(args.watch);
+        assert_eq!(args.watch_interval, 10);
+        assert!(args.tileset_config["world_cities"].hidden);
+    }
+
+    #[test]
+    fn test_invalid_config_file() {
+        let dir = TempDir::new("config").unwrap();
+        let load = |name: &str, contents: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, contents).unwrap();
+            Args::load_from(["", "--config", path.to_str().unwrap()])
+                .and_then(Args::post_parse)
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(load("config.json", "{}").contains(".toml, .yaml or .yml"));
+        assert!(load("config.toml", "prot = 3000").contains("unknown field `prot`"));
+        assert!(load("config.toml", "port = \"high\"").contains("config.toml"));
+        assert!(load("config.toml", "[headers]\n\"bad header\" = \"v\"").contains("Invalid header"));
+        assert!(load(
+            "config.toml",
+            "[tilesets.world_cities]\nalias = \"geography-class-png\""
+        )
+        .contains("is the id of another tileset"));
+        assert!(load(
+            "config.yaml",
+            "tilesets:\n  world_cities:\n    alias: a\n  geography-class-png:\n    alias: a"
+        )
+        .contains("is used for both"));
+        assert!(load(
+            "config.yaml",
+            "tilesets:\n  world_cities:\n    missing-tile: ./missing.png"
+        )
+        .contains("missing.png"));
+    }
+
+    #[test]
+    fn test_bind() {
+        let args = Args::try_parse_from(["", "-p", "4000"])
+            .unwrap()
+            .post_parse()
+            .unwrap();
+        assert_eq!(
+            args.binds,
+            [Bind::Tcp(SocketAddr::from(([0, 0, 0, 0], 4000)))]
+        );
+
+        let args = Args::try_parse_from([
+            "",
+            "--bind",
+            "127.0.0.1:4000",
+            "--bind",
+            "[::1]:4001",
+            "-b",
+            "unix:/run/mbtileserver.sock",
+            "--socket-mode",
+            "660",
+        ])
+        .unwrap()
+        .post_parse()
+        .unwrap();
+        assert_eq!(
+            args.binds,
+            [
+                Bind::Tcp("127.0.0.1:4000".parse().unwrap()),
+                Bind::Tcp("[::1]:4001".parse().unwrap()),
+                Bind::Unix(PathBuf::from("/run/mbtileserver.sock")),
+            ]
+        );
+        assert_eq!(args.socket_mode, Some(0o660));
+        assert_eq!(args.binds[2].to_string(), "unix:/run/mbtileserver.sock");
+
+        for bind in ["localhost", "unix:", "127.0.0.1:http"] {
+            let args = Args::try_parse_from(["", "--bind", bind])
+                .unwrap()
+                .post_parse();
+            assert!(matches!(args, Err(Error::Config(_))), "{bind}");
+        }
+        assert!(Args::try_parse_from(["", "--socket-mode", "999"]).is_err());
+    }
+
+    #[test]
+    fn test_tls() {
+        let args = Args::try_parse_from([
+            "",
+            "--tls-cert",
+            "./testdata/tls/cert.pem",
+            "--tls-key",
+            "./testdata/tls/key.pem",
+        ])
+        .unwrap()
+        .post_parse()
+        .unwrap();
+        assert_eq!(
+            args.tls_cert,
+            Some(PathBuf::from("./testdata/tls/cert.pem"))
+        );
+
+        assert!(Args::try_parse_from(["", "--tls-cert", "./testdata/tls/cert.pem"]).is_err());
+        let args = Args::try_parse_from([
+            "",
+            "--tls-cert",
+            "./testdata/tls/key.pem",
+            "--tls-key",
+            "./testdata/tls/key.pem",
+        ])
+        .unwrap()
+        .post_parse();
+        assert!(matches!(args, Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_strict() {
+        // ./tiles contains invalid mbtiles files
+        let err = Args::try_parse_from(["", "--strict"])
+            .unwrap()
+            .post_parse()
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("invalid: schema: metadata is missing"),
+            "{err}"
+        );
+
+        let dir = TempDir::new("strict").unwrap();
+        std::fs::copy("./tiles/world_cities.mbtiles", dir.path().join("a.mbtiles")).unwrap();
+        let directory = dir.path().to_str().unwrap();
+        let args = Args::try_parse_from(["", "--strict", "--directory", directory])
+            .unwrap()
+            .post_parse()
+            .unwrap();
+        assert!(args.tilesets.contains_key("a"));
+    }
+
+    #[test]
+    fn test_validate_command() {
+        let args = Args::try_parse_from(["", "-d", "./tiles", "validate", "a.mbtiles"]).unwrap();
+        match args.command {
+            Some(Command::Validate { paths }) => assert_eq!(paths, [PathBuf::from("a.mbtiles")]),
+            _ => panic!("validate command not parsed"),
+        }
+    }
 }
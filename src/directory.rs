@@ -0,0 +1,151 @@
+use std::fs::{read, read_dir, read_to_string};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value as JSONValue;
+
+use crate::errors::{Error, Result};
+use crate::utils::DataFormat;
+
+/// Marker file identifying a directory of tiles, as written by `tippecanoe --output-to-directory`
+pub const METADATA_FILE: &str = "metadata.json";
+
+/// Check whether `path` is a directory tileset
+pub fn is_tile_directory(path: &Path) -> bool {
+    path.join(METADATA_FILE).is_file()
+}
+
+/// A directory of tiles stored as `{z}/{x}/{y}.<extension>` files in the XYZ scheme
+#[derive(Clone, Debug)]
+pub struct TileDirectory {
+    pub path: PathBuf,
+    pub extension: String,
+}
+
+impl TileDirectory {
+    /// Open the directory at `path`. The tile file extension is taken from the `format`
+    /// metadata value, or from the first tile found at the lowest zoom level.
+    pub fn open(path: &Path, name: &str) -> Result<TileDirectory> {
+        let mut directory = TileDirectory {
+            path: PathBuf::from(path),
+            extension: String::new(),
+        };
+        directory.extension = match directory.metadata()?.get("format") {
+            Some(JSONValue::String(format)) => format.clone(),
+            _ => match find_extension(path) {
+                Some(extension) => extension,
+                None => return Err(Error::UnknownTileFormat(name.to_string())),
+            },
+        };
+        Ok(directory)
+    }
+
+    /// Read `metadata.json`
+    pub fn metadata(&self) -> Result<JSONValue> {
+        let data = read_to_string(self.path.join(METADATA_FILE))
+            .map_err(|err| Error::InvalidDataFormat(format!("{}: {err}", self.path.display())))?;
+        match serde_json::from_str(&data) {
+            Ok(value @ JSONValue::Object(_)) => Ok(value),
+            Ok(_) => Err(Error::InvalidDataFormat(format!(
+                "{}: metadata is not an object",
+                self.path.display()
+            ))),
+            Err(err) => Err(Error::InvalidDataFormat(format!(
+                "{}: {err}",
+                self.path.display()
+            ))),
+        }
+    }
+
+    pub fn tile_format(&self) -> DataFormat {
+        DataFormat::new(&self.extension)
+    }
+
+    /// Read any tile of the directory, from the lowest zoom level
+    pub fn sample_tile(&self) -> Result<Option<Vec<u8>>> {
+        match find_tile(&self.path) {
+            Some(path) => read(&path)
+                .map(Some)
+                .map_err(|err| Error::InvalidDataFormat(format!("{}: {err}", path.display()))),
+            None => Ok(None),
+        }
+    }
+
+    /// Read the tile at the given XYZ coordinates, or `None` if there is no such file
+    pub fn get_tile(&self, z: u32, x: u32, y: u32) -> Result<Option<Vec<u8>>> {
+        let path = self.path.join(format!("{z}/{x}/{y}.{}", self.extension));
+        match read(&path) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(Error::InvalidDataFormat(format!(
+                "{}: {err}",
+                path.display()
+            ))),
+        }
+    }
+}
+
+/// Return the path of a tile file at the lowest zoom level found in `path`
+fn find_tile(path: &Path) -> Option<PathBuf> {
+    let numeric_children = |path: &Path| -> Vec<(u32, PathBuf)> {
+        let mut children: Vec<(u32, PathBuf)> = read_dir(path)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| {
+                let path = entry.path();
+                let number = path.file_stem()?.to_str()?.parse().ok()?;
+                Some((number, path))
+            })
+            .collect();
+        children.sort();
+        children
+    };
+    for (_, zoom) in numeric_children(path) {
+        for (_, column) in numeric_children(&zoom) {
+            for (_, tile) in numeric_children(&column) {
+                if tile.extension().is_some() {
+                    return Some(tile);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Return the extension of a tile file at the lowest zoom level found in `path`
+fn find_extension(path: &Path) -> Option<String> {
+    let tile = find_tile(path)?;
+    Some(tile.extension()?.to_str()?.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_tiles() {
+        let directory = TileDirectory::open(
+            &PathBuf::from("./tiles/directory/world_cities"),
+            "world_cities",
+        )
+        .unwrap();
+        assert_eq!(directory.tile_format(), DataFormat::Pbf);
+        assert!(directory.get_tile(0, 0, 0).unwrap().is_some());
+        assert!(directory.get_tile(2, 0, 0).unwrap().is_none());
+        assert!(directory.get_tile(7, 0, 0).unwrap().is_none());
+        assert_eq!(
+            directory.sample_tile().unwrap(),
+            directory.get_tile(0, 0, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn find_tile_extension() {
+        assert_eq!(
+            find_extension(&PathBuf::from("./tiles/directory/world_cities")),
+            Some("pbf".to_string())
+        );
+        assert_eq!(find_extension(&PathBuf::from("./tiles/pmtiles")), None);
+    }
+}
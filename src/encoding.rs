@@ -0,0 +1,261 @@
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, ACCEPT_ENCODING, CONTENT_ENCODING, VARY};
+use hyper::http::response::Builder;
+
+use crate::errors::{Error, Result};
+use crate::utils::{compress, decompress, get_data_format, DataFormat};
+
+/// Content codings the server can send
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Identity,
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl ContentEncoding {
+    /// Preference among equally acceptable codings, best first
+    const PREFERENCE: [ContentEncoding; 4] = [
+        ContentEncoding::Brotli,
+        ContentEncoding::Zstd,
+        ContentEncoding::Gzip,
+        ContentEncoding::Identity,
+    ];
+
+    /// Value of the `Content-Encoding` header, `None` for uncompressed data
+    pub fn header_value(&self) -> Option<&'static str> {
+        match self {
+            ContentEncoding::Identity => None,
+            ContentEncoding::Gzip => Some("gzip"),
+            ContentEncoding::Brotli => Some("br"),
+            ContentEncoding::Zstd => Some("zstd"),
+        }
+    }
+
+    fn token(&self) -> &'static str {
+        self.header_value().unwrap_or("identity")
+    }
+
+    fn data_format(&self) -> Option<DataFormat> {
+        match self {
+            ContentEncoding::Identity => None,
+            ContentEncoding::Gzip => Some(DataFormat::Gzip),
+            ContentEncoding::Brotli => Some(DataFormat::Brotli),
+            ContentEncoding::Zstd => Some(DataFormat::Zstd),
+        }
+    }
+
+    pub fn encode(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self.data_format() {
+            Some(data_format) => compress(data, data_format),
+            None => Ok(data.to_vec()),
+        }
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self.data_format() {
+            Some(data_format) => decompress(data, data_format),
+            None => Ok(data.to_vec()),
+        }
+    }
+}
+
+/// Content coding a stored blob can be sent with as is. Gzip and zstd are recognized from
+/// the data, other blobs are assumed to be stored with `default` (brotli can't be detected).
+/// Zlib data has to be transcoded, as HTTP clients disagree on what `deflate` means.
+pub fn stored_encoding(data: &[u8], default: ContentEncoding) -> Option<ContentEncoding> {
+    match get_data_format(data) {
+        DataFormat::Gzip => Some(ContentEncoding::Gzip),
+        DataFormat::Zstd => Some(ContentEncoding::Zstd),
+        DataFormat::Zlib => None,
+        _ => Some(default),
+    }
+}
+
+/// Parse the quality value of an `Accept-Encoding` element, e.g. `gzip;q=0.5`
+fn quality(params: &str) -> f32 {
+    params
+        .split(';')
+        .filter_map(|param| param.trim().strip_prefix("q="))
+        .find_map(|q| q.trim().parse().ok())
+        .unwrap_or(1.0)
+}
+
+/// Return the quality the client assigned to each coding in `Accept-Encoding`
+/// (RFC 9110, section 12.5.3). Identity is acceptable unless it is excluded explicitly,
+/// but only preferred over the other codings if the client lists it.
+fn accepted(header: &str) -> Vec<(ContentEncoding, f32)> {
+    let mut explicit: Vec<(String, f32)> = Vec::new();
+    for element in header.split(',') {
+        let (coding, params) = element.split_once(';').unwrap_or((element, ""));
+        let coding = coding.trim().to_ascii_lowercase();
+        if !coding.is_empty() {
+            explicit.push((coding, quality(params)));
+        }
+    }
+    let find = |token: &str| explicit.iter().find(|(c, _)| c == token).map(|(_, q)| *q);
+    ContentEncoding::PREFERENCE
+        .iter()
+        .map(|encoding| {
+            let q = match (find(encoding.token()), find("*"), encoding) {
+                (Some(q), _, _) => q,
+                (None, Some(q), _) => q,
+                (None, None, ContentEncoding::Identity) => f32::MIN_POSITIVE,
+                (None, None, _) => 0.0,
+            };
+            (*encoding, q)
+        })
+        .collect()
+}
+
+/// Pick the coding for a response whose data is stored with `stored` (`None` if the stored
+/// coding can't be sent as is). Without an `Accept-Encoding` header any coding is acceptable,
+/// so the data is sent as stored, or uncompressed. Among the codings with the highest
+/// quality, a compressed stored coding is preferred to avoid transcoding, while uncompressed
+/// data is compressed. Returns `None` if no coding is acceptable.
+pub fn negotiate(headers: &HeaderMap, stored: Option<ContentEncoding>) -> Option<ContentEncoding> {
+    let header = match headers.get(ACCEPT_ENCODING) {
+        Some(header) => header.to_str().unwrap_or(""),
+        None => return Some(stored.unwrap_or(ContentEncoding::Identity)),
+    };
+    let accepted = accepted(header);
+    let best = accepted.iter().map(|(_, q)| *q).fold(0.0, f32::max);
+    if best <= 0.0 {
+        return None;
+    }
+    let candidates: Vec<ContentEncoding> = accepted
+        .iter()
+        .filter(|(_, q)| *q == best)
+        .map(|(encoding, _)| *encoding)
+        .collect();
+    match stored {
+        Some(stored) if stored != ContentEncoding::Identity && candidates.contains(&stored) => {
+            Some(stored)
+        }
+        _ => candidates.first().copied(),
+    }
+}
+
+/// Convert data stored with `stored` (`None` for zlib) to the negotiated coding
+pub fn transcode(
+    data: &[u8],
+    stored: Option<ContentEncoding>,
+    encoding: ContentEncoding,
+) -> Result<Vec<u8>> {
+    if stored == Some(encoding) {
+        return Ok(data.to_vec());
+    }
+    let decoded = match stored {
+        Some(stored) => stored.decode(data)?,
+        None => decompress(data, get_data_format(data))?,
+    };
+    encoding.encode(&decoded)
+}
+
+/// Add `Vary` and `Content-Encoding` to `response` and encode `data`, stored with `stored`,
+/// with the coding negotiated from the request `headers`
+pub fn encode_response(
+    headers: &HeaderMap,
+    mut response: Builder,
+    data: Bytes,
+    stored: Option<ContentEncoding>,
+) -> Result<(Builder, Bytes)> {
+    response = response.header(VARY, ACCEPT_ENCODING.as_str());
+    let encoding = negotiate(headers, stored)
+        .ok_or_else(|| Error::NotAcceptable("No acceptable content encoding".to_string()))?;
+    if let Some(value) = encoding.header_value() {
+        response = response.header(CONTENT_ENCODING, value);
+    }
+    if stored == Some(encoding) {
+        return Ok((response, data));
+    }
+    Ok((response, transcode(&data, stored, encoding)?.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn headers(accept_encoding: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            ACCEPT_ENCODING,
+            HeaderValue::from_str(accept_encoding).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn negotiate_encoding() {
+        let gzip = Some(ContentEncoding::Gzip);
+        assert_eq!(negotiate(&HeaderMap::new(), gzip), gzip);
+        assert_eq!(negotiate(&headers("gzip, deflate, br"), gzip), gzip);
+        assert_eq!(
+            negotiate(&headers("br"), gzip),
+            Some(ContentEncoding::Brotli)
+        );
+        assert_eq!(
+            negotiate(&headers("gzip;q=0.5, br"), gzip),
+            Some(ContentEncoding::Brotli)
+        );
+        assert_eq!(
+            negotiate(&headers("identity"), gzip),
+            Some(ContentEncoding::Identity)
+        );
+        assert_eq!(
+            negotiate(&headers(""), gzip),
+            Some(ContentEncoding::Identity)
+        );
+        assert_eq!(negotiate(&headers("*"), gzip), gzip);
+        assert_eq!(
+            negotiate(&headers("*"), Some(ContentEncoding::Identity)),
+            Some(ContentEncoding::Brotli)
+        );
+        assert_eq!(
+            negotiate(&HeaderMap::new(), Some(ContentEncoding::Identity)),
+            Some(ContentEncoding::Identity)
+        );
+        assert_eq!(negotiate(&headers("br;q=0, identity;q=0"), gzip), None);
+        assert_eq!(negotiate(&headers("*;q=0"), gzip), None);
+        assert_eq!(
+            negotiate(&headers("gzip;q=0.5"), Some(ContentEncoding::Identity)),
+            gzip
+        );
+        assert_eq!(
+            negotiate(&headers("zstd, gzip"), None),
+            Some(ContentEncoding::Zstd)
+        );
+    }
+
+    #[test]
+    fn transcode_tiles() {
+        let data = b"tile data".to_vec();
+        let gzip = Some(ContentEncoding::Gzip);
+        let gzipped = ContentEncoding::Gzip.encode(&data).unwrap();
+        assert_eq!(stored_encoding(&gzipped, ContentEncoding::Identity), gzip);
+        assert_eq!(
+            transcode(&gzipped, gzip, ContentEncoding::Gzip).unwrap(),
+            gzipped
+        );
+        assert_eq!(
+            transcode(&gzipped, gzip, ContentEncoding::Identity).unwrap(),
+            data
+        );
+
+        for encoding in [ContentEncoding::Brotli, ContentEncoding::Zstd] {
+            let encoded = transcode(&gzipped, gzip, encoding).unwrap();
+            assert_eq!(encoding.decode(&encoded).unwrap(), data);
+            let transcoded = transcode(&encoded, Some(encoding), ContentEncoding::Gzip).unwrap();
+            assert_eq!(ContentEncoding::Gzip.decode(&transcoded).unwrap(), data);
+        }
+
+        let zlib = compress(&data, DataFormat::Zlib).unwrap();
+        assert_eq!(stored_encoding(&zlib, ContentEncoding::Identity), None);
+        assert_eq!(
+            transcode(&zlib, None, ContentEncoding::Identity).unwrap(),
+            data
+        );
+    }
+}
@@ -2,6 +2,7 @@ use std::error::Error as StdError;
 use std::fmt;
 use std::result::Result as StdResult;
 
+use hyper::StatusCode;
 use r2d2::Error as R2D2Error;
 use rusqlite::Error as DBError;
 
@@ -16,6 +17,11 @@ pub enum Error {
     InvalidDataFormat(String),
     InvalidDataFormatQueryCategory(String),
     UnknownTileFormat(String),
+    TilesetNotFound(String),
+    InvalidTileCoordinates(String),
+    NotAcceptable(String),
+    InvalidComposite(String),
+    InvalidParameter(String),
 }
 
 impl fmt::Display for Error {
@@ -32,10 +38,46 @@ impl fmt::Display for Error {
                 write!(f, "Invalid query category: {tile_name}")
             }
             Error::UnknownTileFormat(tile_name) => write!(f, "Unknown tile format: {tile_name}"),
+            Error::TilesetNotFound(tile_name) => write!(f, "Tileset does not exist: {tile_name}"),
+            Error::InvalidTileCoordinates(message) => {
+                write!(f, "Invalid tile coordinates: {message}")
+            }
+            Error::NotAcceptable(message) => write!(f, "Not acceptable: {message}"),
+            Error::InvalidComposite(message) => write!(f, "Invalid composite tileset: {message}"),
+            Error::InvalidParameter(message) => write!(f, "Invalid parameter: {message}"),
             Error::DBConnection(_) => write!(f, "Database connection error"),
             Error::Pool(_) => write!(f, "Database pool connection error"),
         }
     }
 }
 
-impl StdError for Error {}
+impl Error {
+    /// HTTP status returned to the client when this error ends a request
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Error::TilesetNotFound(_) => StatusCode::NOT_FOUND,
+            Error::InvalidTileCoordinates(_)
+            | Error::InvalidComposite(_)
+            | Error::InvalidParameter(_) => StatusCode::BAD_REQUEST,
+            Error::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            // Pool errors are timeouts waiting for a free connection
+            Error::Pool(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::DBConnection(_)
+            | Error::Config(_)
+            | Error::MissingTable(_)
+            | Error::InvalidDataFormat(_)
+            | Error::InvalidDataFormatQueryCategory(_)
+            | Error::UnknownTileFormat(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::DBConnection(err) => Some(err),
+            Error::Pool(err) => Some(err),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+use crate::admin::is_authorized;
+use crate::registry::Registry;
+use crate::service::Context;
+
+/// Time a readiness check waits for a free database connection of a tileset
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Time the checks of all tilesets, which run concurrently, may take together
+const CHECKS_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Time the result of the checks is reused for
+const READINESS_TTL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Error,
+    /// Configured in the configuration file, but not registered
+    Missing,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TilesetStatus {
+    pub status: Status,
+    pub required: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub tilesets: BTreeMap<String, TilesetStatus>,
+}
+
+/// The latest readiness, shared by the requests of `READINESS_TTL`. Requests arriving
+/// while the tilesets are checked wait for that check instead of starting another one.
+#[derive(Debug, Default)]
+pub struct ReadinessCache {
+    latest: Mutex<Option<(Instant, Arc<Readiness>)>>,
+}
+
+impl ReadinessCache {
+    pub async fn get(&self, tilesets: &Registry) -> Arc<Readiness> {
+        let mut latest = self.latest.lock().await;
+        if let Some((checked, readiness)) = &*latest {
+            if checked.elapsed() < READINESS_TTL {
+                return readiness.clone();
+            }
+        }
+        let readiness = Arc::new(check_tilesets(tilesets).await);
+        *latest = Some((Instant::now(), readiness.clone()));
+        readiness
+    }
+}
+
+fn json_response<T: Serialize>(status: StatusCode, data: &T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .header(CACHE_CONTROL, "no-store")
+        .body(Body::from(serde_json::to_string(data).unwrap()))
+        .unwrap()
+}
+
+/// Check that every registered tileset can be read. The server is ready unless a
+/// required tileset fails its check or a required tileset of the configuration file
+/// isn't registered. Tilesets are checked concurrently, and checks that take longer
+/// than `CHECKS_TIMEOUT` in total fail.
+#[allow(clippy::unnecessary_map_or)] // is_none_or needs Rust 1.82
+pub async fn check_tilesets(tilesets: &Registry) -> Readiness {
+    let required = |id: &str| tilesets.config(id).map_or(true, |c| c.required);
+    let deadline = Instant::now() + CHECKS_TIMEOUT;
+    // Checks block on database connections and file reads
+    let checks: Vec<_> = tilesets
+        .snapshot()
+        .into_iter()
+        .map(|(id, tile_meta)| {
+            let check = tokio::task::spawn_blocking(move || tile_meta.source.check(CHECK_TIMEOUT));
+            (id, check)
+        })
+        .collect();
+    let mut statuses = BTreeMap::new();
+    for (id, check) in checks {
+        let error = match tokio::time::timeout_at(deadline, check).await {
+            Ok(Ok(Ok(()))) => None,
+            Ok(Ok(Err(err))) => Some(match std::error::Error::source(&err) {
+                Some(source) => format!("{err}: {source}"),
+                None => err.to_string(),
+            }),
+            Ok(Err(err)) => Some(err.to_string()),
+            Err(_) => Some("Check timed out".to_string()),
+        };
+        let status = TilesetStatus {
+            status: match error {
+                None => Status::Ok,
+                Some(_) => Status::Error,
+            },
+            required: required(&id),
+            error,
+        };
+        statuses.insert(id, status);
+    }
+    for (id, config) in tilesets.configs() {
+        if !statuses.contains_key(id) {
+            let status = TilesetStatus {
+                status: Status::Missing,
+                required: config.required,
+                error: None,
+            };
+            statuses.insert(id.clone(), status);
+        }
+    }
+    Readiness {
+        ready: statuses
+            .values()
+            .all(|s| s.status == Status::Ok || !s.required),
+        tilesets: statuses,
+    }
+}
+
+/// Liveness: the process is up and serving requests
+pub fn health_response() -> Response<Body> {
+    json_response(StatusCode::OK, &json!({"status": "ok"}))
+}
+
+/// Readiness: all required tilesets can be read. The result of each tileset is only
+/// listed for requests authorized with the admin token.
+pub async fn ready_response(request: &Request<Body>, context: &Context) -> Response<Body> {
+    let readiness = context.readiness.get(&context.tilesets).await;
+    let status = match readiness.ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    let authorized = match &context.admin_token {
+        Some(token) => is_authorized(request, token),
+        None => false,
+    };
+    match authorized {
+        true => json_response(status, &*readiness),
+        false => json_response(status, &json!({"ready": readiness.ready})),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TilesetConfig;
+    use crate::tiles::discover_tilesets;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use tempdir::TempDir;
+
+    #[tokio::test]
+    async fn check_registered_tilesets() {
+        let tilesets = Registry::new(discover_tilesets(String::new(), &PathBuf::from("./tiles")));
+        let readiness = check_tilesets(&tilesets).await;
+        assert!(readiness.ready);
+        assert_eq!(readiness.tilesets["world_cities"].status, Status::Ok);
+        assert_eq!(
+            readiness.tilesets["directory/world_cities"].status,
+            Status::Ok
+        );
+        assert!(readiness.tilesets["world_cities"].required);
+    }
+
+    #[tokio::test]
+    async fn check_broken_tilesets() {
+        let dir = TempDir::new("health").unwrap();
+        let path = dir.path().join("world_cities.mbtiles");
+        std::fs::copy("./tiles/world_cities.mbtiles", &path).unwrap();
+        std::fs::copy(
+            "./tiles/geography-class-png.mbtiles",
+            dir.path().join("optional.mbtiles"),
+        )
+        .unwrap();
+        let config = HashMap::from([
+            (
+                "optional".to_string(),
+                TilesetConfig {
+                    required: false,
+                    ..Default::default()
+                },
+            ),
+            ("unknown".to_string(), TilesetConfig::default()),
+        ]);
+        let tilesets = Registry::new(discover_tilesets(String::new(), &dir.path().to_path_buf()))
+            .with_config(config);
+        let readiness = check_tilesets(&tilesets).await;
+        // A required tileset of the configuration file is not registered
+        assert!(!readiness.ready);
+        assert_eq!(readiness.tilesets["unknown"].status, Status::Missing);
+        assert_eq!(readiness.tilesets["world_cities"].status, Status::Ok);
+
+        // Overwrite the files, so they are no longer valid databases
+        std::fs::write(&path, vec![1; 4096]).unwrap();
+        std::fs::write(dir.path().join("optional.mbtiles"), vec![1; 4096]).unwrap();
+        let readiness = check_tilesets(&tilesets).await;
+        let status = &readiness.tilesets["world_cities"];
+        assert_eq!(status.status, Status::Error);
+        assert!(status.error.is_some());
+        assert_eq!(readiness.tilesets["optional"].status, Status::Error);
+        assert!(!readiness.tilesets["optional"].required);
+    }
+
+    #[tokio::test]
+    async fn reuse_readiness() {
+        let dir = TempDir::new("health").unwrap();
+        let path = dir.path().join("world_cities.mbtiles");
+        std::fs::copy("./tiles/world_cities.mbtiles", &path).unwrap();
+        let tilesets = Registry::new(discover_tilesets(String::new(), &dir.path().to_path_buf()));
+        let cache = ReadinessCache::default();
+        assert!(cache.get(&tilesets).await.ready);
+        std::fs::write(&path, vec![1; 4096]).unwrap();
+        assert!(cache.get(&tilesets).await.ready);
+        *cache.latest.lock().await = None;
+        assert!(!cache.get(&tilesets).await.ready);
+    }
+}
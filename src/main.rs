@@ -1,12 +1,27 @@
-use clap::Parser;
 use log::error;
 
+mod access_log;
+mod admin;
+mod caching;
+mod composite;
 mod config;
+mod directory;
+mod encoding;
 mod errors;
+mod health;
+mod metrics;
+mod mvt;
+mod pmtiles;
+mod raster;
+mod registry;
 mod server;
 mod service;
+mod tile_cache;
 mod tiles;
+mod tls;
 mod utils;
+mod validate;
+mod watcher;
 
 fn main() {
     eprintln!("####################################################################");
@@ -17,10 +32,15 @@ fn main() {
 
     pretty_env_logger::init_timed();
 
-    let args = config::Args::parse().post_parse().unwrap_or_else(|err| {
+    let exit = |err| {
         error!("{err}");
         std::process::exit(1)
-    });
+    };
+    let args = config::Args::load().unwrap_or_else(exit);
+    if let Some(config::Command::Validate { paths }) = &args.command {
+        std::process::exit(validate::run(paths, &args.directory));
+    }
+    let args = args.post_parse().unwrap_or_else(exit);
 
     if let Err(e) = server::run(args) {
         error!("Server error: {e}");
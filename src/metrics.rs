@@ -0,0 +1,312 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Response, StatusCode};
+
+use crate::registry::Registry;
+use crate::tiles::TileSource;
+
+/// Content type of the Prometheus text exposition format
+const CONTENT_TYPE_PROMETHEUS: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Upper bounds of the tile size buckets, in bytes
+const SIZE_BUCKETS: &[f64] = &[256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0];
+
+/// Upper bounds of the tile read duration buckets, in seconds
+const DURATION_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+#[derive(Debug)]
+struct Histogram {
+    buckets: &'static [f64],
+    /// Number of observations per bucket, not cumulative
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Histogram {
+        Histogram {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = self.buckets.iter().position(|&bound| value <= bound) {
+            self.counts[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in self.buckets.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", self.count);
+    }
+}
+
+/// Escape a label value for the text exposition format
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Counters and histograms of tile requests, reported by `/metrics`
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Requests by tileset, format and status
+    requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+    /// Sizes of tiles served, by tileset and format
+    tile_sizes: Mutex<BTreeMap<(String, String), Histogram>>,
+    /// Durations of tile and UTFGrid reads, by tileset and kind
+    read_durations: Mutex<BTreeMap<(String, &'static str), Histogram>>,
+    rejected_hosts: AtomicU64,
+}
+
+impl Metrics {
+    /// Count a request for a tile of `tileset`. `size` is the size of the body of a
+    /// successful response.
+    pub fn record_request(
+        &self,
+        tileset: &str,
+        format: &str,
+        status: StatusCode,
+        size: Option<u64>,
+    ) {
+        let key = (tileset.to_string(), format.to_string(), status.as_u16());
+        *self.requests.lock().unwrap().entry(key).or_default() += 1;
+        if let Some(size) = size {
+            self.tile_sizes
+                .lock()
+                .unwrap()
+                .entry((tileset.to_string(), format.to_string()))
+                .or_insert_with(|| Histogram::new(SIZE_BUCKETS))
+                .observe(size as f64);
+        }
+    }
+
+    /// Count a request rejected because its host isn't allowed
+    pub fn reject_host(&self) {
+        self.rejected_hosts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Run `read` and record its duration as a read of `kind` ("tile" or "grid")
+    pub fn time_read<T, F>(&self, tileset: &str, kind: &'static str, read: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        let start = Instant::now();
+        let result = read();
+        self.read_durations
+            .lock()
+            .unwrap()
+            .entry((tileset.to_string(), kind))
+            .or_insert_with(|| Histogram::new(DURATION_BUCKETS))
+            .observe(start.elapsed().as_secs_f64());
+        result
+    }
+
+    /// Render all metrics, including the state of the connection pools and the cache
+    /// of `tilesets`, in the Prometheus text format
+    pub fn render(&self, tilesets: &Registry) -> String {
+        let mut out = String::new();
+
+        let name = "mbtileserver_tile_requests_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Tile requests by tileset, format and status.",
+        );
+        for ((tileset, format, status), count) in self.requests.lock().unwrap().iter() {
+            let tileset = escape(tileset);
+            let _ = writeln!(
+                out,
+                "{name}{{tileset=\"{tileset}\",format=\"{format}\",status=\"{status}\"}} {count}"
+            );
+        }
+
+        let name = "mbtileserver_tile_size_bytes";
+        header(&mut out, name, "histogram", "Sizes of tiles served.");
+        for ((tileset, format), histogram) in self.tile_sizes.lock().unwrap().iter() {
+            let labels = format!("tileset=\"{}\",format=\"{format}\"", escape(tileset));
+            histogram.write(&mut out, name, &labels);
+        }
+
+        let name = "mbtileserver_tile_read_duration_seconds";
+        header(
+            &mut out,
+            name,
+            "histogram",
+            "Durations of tile and UTFGrid reads.",
+        );
+        for ((tileset, kind), histogram) in self.read_durations.lock().unwrap().iter() {
+            let labels = format!("tileset=\"{}\",kind=\"{kind}\"", escape(tileset));
+            histogram.write(&mut out, name, &labels);
+        }
+
+        let name = "mbtileserver_rejected_hosts_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Requests rejected because of their host.",
+        );
+        let _ = writeln!(
+            out,
+            "{name} {}",
+            self.rejected_hosts.load(Ordering::Relaxed)
+        );
+
+        // Open, idle and maximum connections of the pools of mbtiles tilesets
+        let pools: BTreeMap<String, [u32; 3]> = tilesets
+            .snapshot()
+            .into_iter()
+            .filter_map(|(id, tile_meta)| match tile_meta.source {
+                TileSource::MBTiles(pool) => {
+                    let state = pool.state();
+                    Some((
+                        id,
+                        [state.connections, state.idle_connections, pool.max_size()],
+                    ))
+                }
+                _ => None,
+            })
+            .collect();
+        let gauges = [
+            (
+                "mbtileserver_pool_connections",
+                "Open database connections of mbtiles tilesets.",
+            ),
+            (
+                "mbtileserver_pool_idle_connections",
+                "Idle database connections of mbtiles tilesets.",
+            ),
+            (
+                "mbtileserver_pool_max_connections",
+                "Maximum number of database connections of mbtiles tilesets.",
+            ),
+        ];
+        for (i, (name, help)) in gauges.into_iter().enumerate() {
+            header(&mut out, name, "gauge", help);
+            for (id, values) in &pools {
+                let _ = writeln!(out, "{name}{{tileset=\"{}\"}} {}", escape(id), values[i]);
+            }
+        }
+
+        if let Some(cache) = tilesets.cache() {
+            let stats = cache.stats();
+            let values = [
+                (
+                    "mbtileserver_cache_hits_total",
+                    "counter",
+                    "Tile cache hits.",
+                    stats.hits,
+                ),
+                (
+                    "mbtileserver_cache_misses_total",
+                    "counter",
+                    "Tile cache misses.",
+                    stats.misses,
+                ),
+                (
+                    "mbtileserver_cache_entries",
+                    "gauge",
+                    "Tiles in the cache.",
+                    stats.entries as u64,
+                ),
+                (
+                    "mbtileserver_cache_size_bytes",
+                    "gauge",
+                    "Size of the cached tiles.",
+                    stats.size as u64,
+                ),
+                (
+                    "mbtileserver_cache_capacity_bytes",
+                    "gauge",
+                    "Capacity of the tile cache.",
+                    stats.capacity as u64,
+                ),
+            ];
+            for (name, kind, help, value) in values {
+                header(&mut out, name, kind, help);
+                let _ = writeln!(out, "{name} {value}");
+            }
+        }
+        out
+    }
+}
+
+pub fn metrics_response(metrics: &Metrics, tilesets: &Registry) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, CONTENT_TYPE_PROMETHEUS)
+        .body(Body::from(metrics.render(tilesets)))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tile_cache::TileCache;
+    use crate::tiles::discover_tilesets;
+    use std::path::PathBuf;
+
+    #[test]
+    fn render_metrics() {
+        let tilesets = Registry::new(discover_tilesets(String::new(), &PathBuf::from("./tiles")))
+            .with_cache(TileCache::new(1_000_000));
+        let metrics = Metrics::default();
+        metrics.record_request("world_cities", "pbf", StatusCode::OK, Some(2000));
+        metrics.record_request("world_cities", "pbf", StatusCode::OK, Some(100));
+        metrics.record_request("world_cities", "pbf", StatusCode::NO_CONTENT, None);
+        metrics.time_read("world_cities", "tile", || ());
+        metrics.reject_host();
+
+        let out = metrics.render(&tilesets);
+        let lines: Vec<&str> = out.lines().collect();
+        for line in [
+            r#"mbtileserver_tile_requests_total{tileset="world_cities",format="pbf",status="200"} 2"#,
+            r#"mbtileserver_tile_requests_total{tileset="world_cities",format="pbf",status="204"} 1"#,
+            r#"mbtileserver_tile_size_bytes_bucket{tileset="world_cities",format="pbf",le="256"} 1"#,
+            r#"mbtileserver_tile_size_bytes_bucket{tileset="world_cities",format="pbf",le="4096"} 2"#,
+            r#"mbtileserver_tile_size_bytes_bucket{tileset="world_cities",format="pbf",le="+Inf"} 2"#,
+            r#"mbtileserver_tile_size_bytes_sum{tileset="world_cities",format="pbf"} 2100"#,
+            r#"mbtileserver_tile_read_duration_seconds_count{tileset="world_cities",kind="tile"} 1"#,
+            "mbtileserver_rejected_hosts_total 1",
+            r#"mbtileserver_pool_max_connections{tileset="world_cities"} 10"#,
+            "mbtileserver_cache_capacity_bytes 1000000",
+        ] {
+            assert!(lines.contains(&line), "{line} not in {out}");
+        }
+        // Only mbtiles tilesets have connection pools
+        assert!(!out.contains(r#"mbtileserver_pool_connections{tileset="directory/world_cities"}"#));
+    }
+
+    #[test]
+    fn escape_label_values() {
+        assert_eq!(escape(r#"a"b\c"#), r#"a\"b\\c"#);
+        assert_eq!(escape("a\nb"), r"a\nb");
+    }
+}
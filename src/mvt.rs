@@ -0,0 +1,898 @@
+//! Minimal reader and writer of Mapbox vector tiles, as far as needed to overzoom and
+//! merge them. Layer and feature fields other than the features, keys, values, tags and
+//! geometries are copied as they are.
+
+use std::collections::HashMap;
+
+use crate::errors::{Error, Result};
+
+const LAYER_NAME: u64 = 1;
+const LAYER_FEATURES: u64 = 2;
+const LAYER_KEYS: u64 = 3;
+const LAYER_VALUES: u64 = 4;
+const LAYER_EXTENT: u64 = 5;
+const TILE_LAYERS: u64 = 3;
+const FEATURE_TAGS: u64 = 2;
+const FEATURE_TYPE: u64 = 3;
+const FEATURE_GEOMETRY: u64 = 4;
+
+const DEFAULT_EXTENT: u64 = 4096;
+
+const MOVE_TO: u32 = 1;
+const LINE_TO: u32 = 2;
+const CLOSE_PATH: u32 = 7;
+
+const POINT: u64 = 1;
+const LINESTRING: u64 = 2;
+const POLYGON: u64 = 3;
+
+type Point = (i64, i64);
+
+fn invalid(message: &str) -> Error {
+    Error::InvalidDataFormat(format!("vector tile: {message}"))
+}
+
+fn read_varint(data: &[u8], position: &mut usize) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data
+            .get(*position)
+            .ok_or_else(|| invalid("truncated varint"))?;
+        *position += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("varint too long"))
+}
+
+fn write_varint(data: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        data.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+fn write_bytes(data: &mut Vec<u8>, field: u64, value: &[u8]) {
+    write_varint(data, field << 3 | 2);
+    write_varint(data, value.len() as u64);
+    data.extend_from_slice(value);
+}
+
+/// A protobuf field: its number, its encoded bytes including the key, and its value
+/// (the payload of length delimited fields, the varint of varint fields)
+struct Field<'a> {
+    number: u64,
+    raw: &'a [u8],
+    payload: &'a [u8],
+    varint: u64,
+}
+
+fn read_fields(data: &[u8]) -> Result<Vec<Field<'_>>> {
+    let mut fields = Vec::new();
+    let mut position = 0;
+    while position < data.len() {
+        let start = position;
+        let key = read_varint(data, &mut position)?;
+        let (mut payload, mut varint) = (&data[0..0], 0);
+        match key & 0x7 {
+            0 => varint = read_varint(data, &mut position)?,
+            1 => position += 8,
+            2 => {
+                let length = read_varint(data, &mut position)? as usize;
+                let end = position
+                    .checked_add(length)
+                    .filter(|end| *end <= data.len())
+                    .ok_or_else(|| invalid("truncated field"))?;
+                payload = &data[position..end];
+                position = end;
+            }
+            5 => position += 4,
+            _ => return Err(invalid("unsupported wire type")),
+        }
+        if position > data.len() {
+            return Err(invalid("truncated field"));
+        }
+        fields.push(Field {
+            number: key >> 3,
+            raw: &data[start..position],
+            payload,
+            varint,
+        });
+    }
+    Ok(fields)
+}
+
+/// Reject layer extents of 0, which can't be scaled, and above `u32::MAX`
+fn check_extent(extent: u64) -> Result<u64> {
+    match extent {
+        0 => Err(invalid("layer extent is 0")),
+        extent if extent > u64::from(u32::MAX) => Err(invalid("layer extent is too large")),
+        extent => Ok(extent),
+    }
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+/// Move the coordinate `v` by the zigzag encoded `delta`. Coordinates are kept within 32 bits
+/// as the specification requires, so scaling and clipping them can't overflow.
+fn move_by(v: i64, delta: u64) -> Result<i64> {
+    v.checked_add(unzigzag(delta))
+        .filter(|v| i32::try_from(*v).is_ok())
+        .ok_or_else(|| invalid("coordinates out of range"))
+}
+
+/// Decode a geometry into its parts (points, lines or rings), in tile coordinates
+fn decode_geometry(data: &[u8]) -> Result<Vec<Vec<Point>>> {
+    let mut integers = Vec::new();
+    let mut position = 0;
+    while position < data.len() {
+        integers.push(read_varint(data, &mut position)?);
+    }
+    let mut parts: Vec<Vec<Point>> = Vec::new();
+    let (mut x, mut y) = (0i64, 0i64);
+    let mut i = 0;
+    while i < integers.len() {
+        let command = integers[i] as u32;
+        i += 1;
+        let (id, count) = (command & 0x7, (command >> 3) as usize);
+        match id {
+            MOVE_TO | LINE_TO => {
+                if integers.len() < i + count * 2 {
+                    return Err(invalid("truncated geometry"));
+                }
+                for n in 0..count {
+                    x = move_by(x, integers[i + n * 2])?;
+                    y = move_by(y, integers[i + n * 2 + 1])?;
+                    if id == MOVE_TO || parts.is_empty() {
+                        parts.push(Vec::new());
+                    }
+                    parts.last_mut().unwrap().push((x, y));
+                }
+                i += count * 2;
+            }
+            CLOSE_PATH => (),
+            _ => return Err(invalid("unknown geometry command")),
+        }
+    }
+    Ok(parts)
+}
+
+fn command(id: u32, count: usize) -> u64 {
+    u64::from(id & 0x7) | (count as u64) << 3
+}
+
+/// Encode geometry parts, closing them as rings for polygons
+fn encode_geometry(parts: &[Vec<Point>], geometry_type: u64) -> Vec<u8> {
+    let mut integers = Vec::new();
+    let mut cursor = (0i64, 0i64);
+    let mut push = |integers: &mut Vec<u64>, (x, y): Point| {
+        integers.push(zigzag(x - cursor.0));
+        integers.push(zigzag(y - cursor.1));
+        cursor = (x, y);
+    };
+    if geometry_type == POINT {
+        let points: Vec<Point> = parts.iter().flatten().copied().collect();
+        if !points.is_empty() {
+            integers.push(command(MOVE_TO, points.len()));
+            for point in points {
+                push(&mut integers, point);
+            }
+        }
+    } else {
+        for part in parts {
+            integers.push(command(MOVE_TO, 1));
+            push(&mut integers, part[0]);
+            integers.push(command(LINE_TO, part.len() - 1));
+            for point in &part[1..] {
+                push(&mut integers, *point);
+            }
+            if geometry_type == POLYGON {
+                integers.push(command(CLOSE_PATH, 1));
+            }
+        }
+    }
+    let mut data = Vec::new();
+    for integer in integers {
+        write_varint(&mut data, integer);
+    }
+    data
+}
+
+/// Axis aligned clipping rectangle, in tile coordinates
+#[derive(Clone, Copy)]
+struct Rect {
+    min: i64,
+    max: i64,
+}
+
+impl Rect {
+    fn contains(&self, (x, y): Point) -> bool {
+        x >= self.min && x <= self.max && y >= self.min && y <= self.max
+    }
+}
+
+/// Clip the segment from `a` to `b` (Liang-Barsky), returning the part inside `rect`
+fn clip_segment(a: Point, b: Point, rect: Rect) -> Option<(Point, Point)> {
+    let (dx, dy) = ((b.0 - a.0) as f64, (b.1 - a.1) as f64);
+    let (mut t0, mut t1) = (0.0f64, 1.0f64);
+    let (min, max) = (rect.min as f64, rect.max as f64);
+    for (p, q) in [
+        (-dx, a.0 as f64 - min),
+        (dx, max - a.0 as f64),
+        (-dy, a.1 as f64 - min),
+        (dy, max - a.1 as f64),
+    ] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+        } else {
+            let t = q / p;
+            if p < 0.0 {
+                t0 = t0.max(t);
+            } else {
+                t1 = t1.min(t);
+            }
+        }
+    }
+    if t0 > t1 {
+        return None;
+    }
+    let at = |t: f64| {
+        (
+            (a.0 as f64 + t * dx).round() as i64,
+            (a.1 as f64 + t * dy).round() as i64,
+        )
+    };
+    Some((at(t0), at(t1)))
+}
+
+/// Clip a line to `rect`, which can split it into several lines
+fn clip_line(line: &[Point], rect: Rect) -> Vec<Vec<Point>> {
+    let mut lines: Vec<Vec<Point>> = Vec::new();
+    let mut current: Vec<Point> = Vec::new();
+    for segment in line.windows(2) {
+        match clip_segment(segment[0], segment[1], rect) {
+            Some((a, b)) => {
+                if current.last() != Some(&a) {
+                    if current.len() > 1 {
+                        lines.push(std::mem::take(&mut current));
+                    }
+                    current = vec![a];
+                }
+                current.push(b);
+                // The segment left the rectangle, so the line continues elsewhere
+                if b != segment[1] {
+                    lines.push(std::mem::take(&mut current));
+                }
+            }
+            None => {
+                if current.len() > 1 {
+                    lines.push(std::mem::take(&mut current));
+                }
+                current.clear();
+            }
+        }
+    }
+    if current.len() > 1 {
+        lines.push(current);
+    }
+    lines
+        .into_iter()
+        .map(|mut line| {
+            line.dedup();
+            line
+        })
+        .filter(|line| line.len() > 1)
+        .collect()
+}
+
+/// Clip a polygon ring to `rect` (Sutherland-Hodgman). The result is unclosed.
+fn clip_ring(ring: &[Point], rect: Rect) -> Vec<Point> {
+    let mut points: Vec<Point> = ring.to_vec();
+    if points.first() == points.last() {
+        points.pop();
+    }
+    // Each edge as (inside test, intersection with the edge line)
+    type Inside = fn(Point, Rect) -> bool;
+    let edges: [(Inside, bool, bool); 4] = [
+        (|p, r| p.0 >= r.min, true, false),
+        (|p, r| p.0 <= r.max, true, true),
+        (|p, r| p.1 >= r.min, false, false),
+        (|p, r| p.1 <= r.max, false, true),
+    ];
+    for (inside, vertical, at_max) in edges {
+        if points.is_empty() {
+            break;
+        }
+        let edge = if at_max { rect.max } else { rect.min } as f64;
+        let intersect = |a: Point, b: Point| -> Point {
+            if vertical {
+                let t = (edge - a.0 as f64) / (b.0 - a.0) as f64;
+                (
+                    edge as i64,
+                    (a.1 as f64 + t * (b.1 - a.1) as f64).round() as i64,
+                )
+            } else {
+                let t = (edge - a.1 as f64) / (b.1 - a.1) as f64;
+                (
+                    (a.0 as f64 + t * (b.0 - a.0) as f64).round() as i64,
+                    edge as i64,
+                )
+            }
+        };
+        let input = std::mem::take(&mut points);
+        let mut previous = *input.last().unwrap();
+        for current in input {
+            match (inside(current, rect), inside(previous, rect)) {
+                (true, true) => points.push(current),
+                (true, false) => {
+                    points.push(intersect(previous, current));
+                    points.push(current);
+                }
+                (false, true) => points.push(intersect(previous, current)),
+                (false, false) => (),
+            }
+            previous = current;
+        }
+    }
+    points.dedup();
+    if points.len() > 1 && points.first() == points.last() {
+        points.pop();
+    }
+    points
+}
+
+/// Scale the geometry of a feature and clip it to `rect`, or `None` if nothing is left
+fn overzoom_feature(data: &[u8], scale: i64, offset: Point, rect: Rect) -> Result<Option<Vec<u8>>> {
+    let fields = read_fields(data)?;
+    let geometry_type = fields
+        .iter()
+        .find(|f| f.number == FEATURE_TYPE)
+        .map_or(0, |f| f.varint);
+    let geometry = match fields.iter().find(|f| f.number == FEATURE_GEOMETRY) {
+        Some(field) => decode_geometry(field.payload)?,
+        None => return Ok(None),
+    };
+    let transform = |v: i64, offset: i64| {
+        v.checked_mul(scale)
+            .and_then(|v| v.checked_sub(offset))
+            .ok_or_else(|| invalid("coordinates out of range"))
+    };
+    let parts = geometry
+        .into_iter()
+        .map(|part| {
+            part.into_iter()
+                .map(|(x, y)| Ok((transform(x, offset.0)?, transform(y, offset.1)?)))
+                .collect::<Result<Vec<Point>>>()
+        })
+        .collect::<Result<Vec<Vec<Point>>>>()?;
+    let parts: Vec<Vec<Point>> = match geometry_type {
+        POINT => parts
+            .into_iter()
+            .map(|part| part.into_iter().filter(|p| rect.contains(*p)).collect())
+            .collect(),
+        LINESTRING => parts
+            .iter()
+            .flat_map(|line| clip_line(line, rect))
+            .collect(),
+        POLYGON => parts
+            .iter()
+            .map(|ring| clip_ring(ring, rect))
+            .filter(|ring| ring.len() > 2)
+            .collect(),
+        _ => return Ok(None),
+    };
+    if parts.iter().all(|part| part.is_empty()) {
+        return Ok(None);
+    }
+    let mut feature = Vec::new();
+    for field in fields {
+        if field.number != FEATURE_GEOMETRY {
+            feature.extend_from_slice(field.raw);
+        }
+    }
+    write_bytes(
+        &mut feature,
+        FEATURE_GEOMETRY,
+        &encode_geometry(&parts, geometry_type),
+    );
+    Ok(Some(feature))
+}
+
+/// Build the tile `dz` zoom levels below the uncompressed vector tile `data`, where `x` and
+/// `y` are the position of the requested tile among the `2^dz` by `2^dz` descendants.
+/// Geometries are scaled up and clipped to the requested tile plus a small buffer.
+/// Returns `None` if no features are left.
+pub fn overzoom_tile(data: &[u8], dz: u32, x: u32, y: u32) -> Result<Option<Vec<u8>>> {
+    let scale = 1i64
+        .checked_shl(dz)
+        .filter(|_| dz < 24)
+        .ok_or_else(|| invalid("too many zoom levels"))?;
+    let mut tile = Vec::new();
+    for layer in read_fields(data)? {
+        if layer.number != TILE_LAYERS {
+            tile.extend_from_slice(layer.raw);
+            continue;
+        }
+        let fields = read_fields(layer.payload)?;
+        let extent = fields
+            .iter()
+            .find(|f| f.number == LAYER_EXTENT)
+            .map_or(DEFAULT_EXTENT, |f| f.varint);
+        let extent = check_extent(extent)? as i64;
+        let offset = match (
+            i64::from(x).checked_mul(extent),
+            i64::from(y).checked_mul(extent),
+        ) {
+            (Some(x), Some(y)) => (x, y),
+            _ => return Err(invalid("coordinates out of range")),
+        };
+        let buffer = extent / 64;
+        let rect = Rect {
+            min: -buffer,
+            max: extent + buffer,
+        };
+        let mut output = Vec::new();
+        let mut features = 0;
+        for field in fields {
+            if field.number != LAYER_FEATURES {
+                output.extend_from_slice(field.raw);
+            } else if let Some(feature) = overzoom_feature(field.payload, scale, offset, rect)? {
+                write_bytes(&mut output, LAYER_FEATURES, &feature);
+                features += 1;
+            }
+        }
+        if features > 0 {
+            write_bytes(&mut tile, TILE_LAYERS, &output);
+        }
+    }
+    match tile.is_empty() {
+        true => Ok(None),
+        false => Ok(Some(tile)),
+    }
+}
+
+/// A layer of a merged tile, collecting the features of all layers with its name
+struct MergedLayer<'a> {
+    name: &'a [u8],
+    extent: u64,
+    /// Fields other than the name, features, keys, values and extent, from the first layer
+    other: Vec<u8>,
+    keys: Vec<&'a [u8]>,
+    values: Vec<&'a [u8]>,
+    /// Indexes of the keys and values
+    indexes: [HashMap<&'a [u8], u64>; 2],
+    features: Vec<Vec<u8>>,
+}
+
+impl<'a> MergedLayer<'a> {
+    fn key_index(&mut self, key: &'a [u8]) -> u64 {
+        let keys = &mut self.keys;
+        *self.indexes[0].entry(key).or_insert_with(|| {
+            keys.push(key);
+            keys.len() as u64 - 1
+        })
+    }
+
+    fn value_index(&mut self, value: &'a [u8]) -> u64 {
+        let values = &mut self.values;
+        *self.indexes[1].entry(value).or_insert_with(|| {
+            values.push(value);
+            values.len() as u64 - 1
+        })
+    }
+
+    /// Add a feature of a layer with `keys`, `values` and `extent`, re-indexing its tags
+    /// into the keys and values of this layer and scaling its geometry to this extent
+    fn add_feature(
+        &mut self,
+        data: &'a [u8],
+        keys: &[&'a [u8]],
+        values: &[&'a [u8]],
+        extent: u64,
+    ) -> Result<()> {
+        let fields = read_fields(data)?;
+        let geometry_type = fields
+            .iter()
+            .find(|f| f.number == FEATURE_TYPE)
+            .map_or(0, |f| f.varint);
+        let mut feature = Vec::new();
+        let mut tags = Vec::new();
+        for field in fields {
+            match field.number {
+                // Tags are packed, but may be written as separate varints too
+                FEATURE_TAGS if field.raw[0] & 0x7 == 0 => tags.push(field.varint),
+                FEATURE_TAGS => {
+                    let mut position = 0;
+                    while position < field.payload.len() {
+                        tags.push(read_varint(field.payload, &mut position)?);
+                    }
+                }
+                FEATURE_GEOMETRY if extent != self.extent => {
+                    let (to, from) = (self.extent as f64, extent as f64);
+                    let scale = |v: i64| {
+                        let v = (v as f64 * to / from).round();
+                        match v >= f64::from(i32::MIN) && v <= f64::from(i32::MAX) {
+                            true => Ok(v as i64),
+                            false => Err(invalid("coordinates out of range")),
+                        }
+                    };
+                    let parts = decode_geometry(field.payload)?
+                        .into_iter()
+                        .map(|part| {
+                            part.into_iter()
+                                .map(|(x, y)| Ok((scale(x)?, scale(y)?)))
+                                .collect::<Result<Vec<Point>>>()
+                        })
+                        .collect::<Result<Vec<Vec<Point>>>>()?;
+                    let geometry = encode_geometry(&parts, geometry_type);
+                    write_bytes(&mut feature, FEATURE_GEOMETRY, &geometry);
+                }
+                _ => feature.extend_from_slice(field.raw),
+            }
+        }
+        if !tags.is_empty() {
+            let mut packed = Vec::new();
+            for pair in tags.chunks(2) {
+                let (key, value) = match pair {
+                    [key, value] => (keys.get(*key as usize), values.get(*value as usize)),
+                    _ => (None, None),
+                };
+                let (key, value) = match (key, value) {
+                    (Some(key), Some(value)) => (*key, *value),
+                    _ => return Err(invalid("invalid feature tags")),
+                };
+                write_varint(&mut packed, self.key_index(key));
+                write_varint(&mut packed, self.value_index(value));
+            }
+            write_bytes(&mut feature, FEATURE_TAGS, &packed);
+        }
+        self.features.push(feature);
+        Ok(())
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut layer = self.other.clone();
+        write_bytes(&mut layer, LAYER_NAME, self.name);
+        for feature in &self.features {
+            write_bytes(&mut layer, LAYER_FEATURES, feature);
+        }
+        for key in &self.keys {
+            write_bytes(&mut layer, LAYER_KEYS, key);
+        }
+        for value in &self.values {
+            write_bytes(&mut layer, LAYER_VALUES, value);
+        }
+        write_varint(&mut layer, LAYER_EXTENT << 3);
+        write_varint(&mut layer, self.extent);
+        layer
+    }
+}
+
+/// Merge uncompressed vector tiles into one tile with the layers of all of them, in the
+/// order they first appear. Layers with the same name are merged into one, as the
+/// specification requires layer names to be unique: the features of later layers are
+/// appended to the first one, with the extent and other layer fields of the first one.
+/// A single tile is returned as it is.
+pub fn merge_tiles(tiles: &[Vec<u8>]) -> Result<Vec<u8>> {
+    if let [tile] = tiles {
+        return Ok(tile.clone());
+    }
+    let mut layers: Vec<MergedLayer> = Vec::new();
+    for tile in tiles {
+        for field in read_fields(tile)? {
+            if field.number != TILE_LAYERS {
+                continue;
+            }
+            let fields = read_fields(field.payload)?;
+            let (mut name, mut extent) = (&[][..], DEFAULT_EXTENT);
+            let (mut keys, mut values, mut features) = (Vec::new(), Vec::new(), Vec::new());
+            let mut other = Vec::new();
+            for field in fields {
+                match field.number {
+                    LAYER_NAME => name = field.payload,
+                    LAYER_FEATURES => features.push(field.payload),
+                    LAYER_KEYS => keys.push(field.payload),
+                    LAYER_VALUES => values.push(field.payload),
+                    LAYER_EXTENT => extent = field.varint,
+                    _ => other.extend_from_slice(field.raw),
+                }
+            }
+            let extent = check_extent(extent)?;
+            let index = match layers.iter().position(|layer| layer.name == name) {
+                Some(index) => index,
+                None => {
+                    layers.push(MergedLayer {
+                        name,
+                        extent,
+                        other,
+                        keys: Vec::new(),
+                        values: Vec::new(),
+                        indexes: [HashMap::new(), HashMap::new()],
+                        features: Vec::new(),
+                    });
+                    layers.len() - 1
+                }
+            };
+            for feature in features {
+                layers[index].add_feature(feature, &keys, &values, extent)?;
+            }
+        }
+    }
+    let mut tile = Vec::new();
+    for layer in layers {
+        write_bytes(&mut tile, TILE_LAYERS, &layer.encode());
+    }
+    Ok(tile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feature(geometry_type: u64, parts: &[Vec<Point>]) -> Vec<u8> {
+        let mut feature = Vec::new();
+        write_varint(&mut feature, FEATURE_TYPE << 3);
+        write_varint(&mut feature, geometry_type);
+        write_bytes(
+            &mut feature,
+            FEATURE_GEOMETRY,
+            &encode_geometry(parts, geometry_type),
+        );
+        feature
+    }
+
+    fn tile(features: &[Vec<u8>]) -> Vec<u8> {
+        let mut layer = Vec::new();
+        write_bytes(&mut layer, 1, b"layer");
+        for feature in features {
+            write_bytes(&mut layer, LAYER_FEATURES, feature);
+        }
+        write_varint(&mut layer, LAYER_EXTENT << 3);
+        write_varint(&mut layer, 4096);
+        let mut tile = Vec::new();
+        write_bytes(&mut tile, TILE_LAYERS, &layer);
+        tile
+    }
+
+    /// Return the geometries of all features in a tile
+    fn geometries(data: &[u8]) -> Vec<Vec<Vec<Point>>> {
+        let mut geometries = Vec::new();
+        for layer in read_fields(data).unwrap() {
+            for feature in read_fields(layer.payload).unwrap() {
+                if feature.number == LAYER_FEATURES {
+                    for field in read_fields(feature.payload).unwrap() {
+                        if field.number == FEATURE_GEOMETRY {
+                            geometries.push(decode_geometry(field.payload).unwrap());
+                        }
+                    }
+                }
+            }
+        }
+        geometries
+    }
+
+    #[test]
+    fn geometry_round_trip() {
+        let parts = vec![
+            vec![(0, 0), (10, 0), (10, 10)],
+            vec![(5, 5), (6, 6), (5, 6)],
+        ];
+        let data = encode_geometry(&parts, POLYGON);
+        assert_eq!(decode_geometry(&data).unwrap(), parts);
+        assert_eq!(zigzag(-3), 5);
+        assert_eq!(unzigzag(5), -3);
+    }
+
+    #[test]
+    fn overzoom_points() {
+        let data = tile(&[
+            feature(POINT, &[vec![(100, 100), (3000, 3000)]]),
+            feature(POINT, &[vec![(4000, 100)]]),
+        ]);
+        // Top left quarter of the tile
+        let overzoomed = overzoom_tile(&data, 1, 0, 0).unwrap().unwrap();
+        assert_eq!(geometries(&overzoomed), [[[(200, 200)]]]);
+        // Bottom right quarter of the tile
+        let overzoomed = overzoom_tile(&data, 1, 1, 1).unwrap().unwrap();
+        assert_eq!(geometries(&overzoomed), [[[(1904, 1904)]]]);
+        // Nothing in the bottom left quarter
+        assert!(overzoom_tile(&data, 1, 0, 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn overzoom_lines() {
+        let data = tile(&[feature(LINESTRING, &[vec![(0, 1024), (4096, 1024)]])]);
+        let overzoomed = overzoom_tile(&data, 1, 1, 0).unwrap().unwrap();
+        assert_eq!(geometries(&overzoomed), [[[(-64, 2048), (4096, 2048)]]]);
+    }
+
+    #[test]
+    fn overzoom_polygons() {
+        let square = vec![(1024, 1024), (3072, 1024), (3072, 3072), (1024, 3072)];
+        let data = tile(&[feature(POLYGON, &[square])]);
+        let overzoomed = overzoom_tile(&data, 1, 0, 0).unwrap().unwrap();
+        let geometry = &geometries(&overzoomed)[0];
+        assert_eq!(geometry.len(), 1);
+        let mut ring = geometry[0].clone();
+        ring.sort();
+        assert_eq!(
+            ring,
+            [(2048, 2048), (2048, 4160), (4160, 2048), (4160, 4160)]
+        );
+    }
+
+    #[test]
+    fn overzoom_real_tile() {
+        let data = crate::utils::decompress(
+            &std::fs::read("./tiles/directory/world_cities/0/0/0.pbf").unwrap(),
+            crate::utils::DataFormat::Gzip,
+        )
+        .unwrap();
+        let children: Vec<Vec<u8>> = [(0, 0), (1, 0), (0, 1), (1, 1)]
+            .iter()
+            .filter_map(|(x, y)| overzoom_tile(&data, 1, *x, *y).unwrap())
+            .collect();
+        assert!(!children.is_empty());
+        let points: usize = children
+            .iter()
+            .map(|child| geometries(child).iter().flatten().flatten().count())
+            .sum();
+        assert!(points >= geometries(&data).iter().flatten().flatten().count());
+        assert!(overzoom_tile(b"\x1a\x05", 1, 0, 0).is_err());
+    }
+
+    #[test]
+    fn overzoom_malformed_tile() {
+        // Coordinates beyond 32 bits
+        let mut geometry = Vec::new();
+        for integer in [
+            command(MOVE_TO, 2),
+            zigzag(i64::MAX),
+            0,
+            zigzag(i64::MAX),
+            0,
+        ] {
+            write_varint(&mut geometry, integer);
+        }
+        let mut malformed = Vec::new();
+        write_varint(&mut malformed, FEATURE_TYPE << 3);
+        write_varint(&mut malformed, POINT);
+        write_bytes(&mut malformed, FEATURE_GEOMETRY, &geometry);
+        let err = overzoom_tile(&tile(&[malformed]), 1, 0, 0).unwrap_err();
+        assert!(
+            format!("{err}").contains("coordinates out of range"),
+            "{err}"
+        );
+
+        // Extents which can't be scaled
+        let points = [feature(POINT, &[vec![(1, 1)]])];
+        for extent in [0, u64::from(u32::MAX) + 1] {
+            let data = layer(b"layer", extent, &[], &[], &points);
+            assert!(overzoom_tile(&data, 1, 0, 0).is_err());
+            assert!(merge_tiles(&[data.clone(), data]).is_err());
+        }
+    }
+
+    fn layer(
+        name: &[u8],
+        extent: u64,
+        keys: &[&str],
+        values: &[&str],
+        features: &[Vec<u8>],
+    ) -> Vec<u8> {
+        let mut layer = Vec::new();
+        write_bytes(&mut layer, LAYER_NAME, name);
+        for feature in features {
+            write_bytes(&mut layer, LAYER_FEATURES, feature);
+        }
+        for key in keys {
+            write_bytes(&mut layer, LAYER_KEYS, key.as_bytes());
+        }
+        for value in values {
+            // A string value
+            let mut encoded = Vec::new();
+            write_bytes(&mut encoded, 1, value.as_bytes());
+            write_bytes(&mut layer, LAYER_VALUES, &encoded);
+        }
+        write_varint(&mut layer, LAYER_EXTENT << 3);
+        write_varint(&mut layer, extent);
+        let mut tile = Vec::new();
+        write_bytes(&mut tile, TILE_LAYERS, &layer);
+        tile
+    }
+
+    fn tagged_point(tags: &[u64], point: Point) -> Vec<u8> {
+        let mut packed = Vec::new();
+        for tag in tags {
+            write_varint(&mut packed, *tag);
+        }
+        let mut feature = Vec::new();
+        write_bytes(&mut feature, FEATURE_TAGS, &packed);
+        feature.extend(self::feature(POINT, &[vec![point]]));
+        feature
+    }
+
+    #[test]
+    fn merge_layers_with_same_name() {
+        let a = layer(
+            b"cities",
+            4096,
+            &["name"],
+            &["x"],
+            &[tagged_point(&[0, 0], (10, 10))],
+        );
+        let b = [
+            layer(
+                b"cities",
+                2048,
+                &["kind", "name"],
+                &["y", "x"],
+                &[tagged_point(&[1, 1, 0, 0], (10, 10))],
+            ),
+            layer(b"roads", 4096, &[], &[], &[]),
+        ]
+        .concat();
+        let merged = merge_tiles(&[a, b]).unwrap();
+
+        let layers = read_fields(&merged).unwrap();
+        assert_eq!(layers.len(), 2);
+        let fields = read_fields(layers[0].payload).unwrap();
+        let payloads = |number| -> Vec<&[u8]> {
+            fields
+                .iter()
+                .filter(|f| f.number == number)
+                .map(|f| f.payload)
+                .collect()
+        };
+        assert_eq!(payloads(LAYER_NAME), [b"cities"]);
+        assert_eq!(payloads(LAYER_KEYS), [&b"name"[..], b"kind"]);
+        assert_eq!(payloads(LAYER_VALUES), [b"\x0a\x01x", b"\x0a\x01y"]);
+        let features = payloads(LAYER_FEATURES);
+        assert_eq!(features.len(), 2);
+        let tags: Vec<&[u8]> = features
+            .iter()
+            .map(|f| {
+                let fields = read_fields(f).unwrap();
+                fields
+                    .iter()
+                    .find(|f| f.number == FEATURE_TAGS)
+                    .unwrap()
+                    .payload
+            })
+            .collect();
+        assert_eq!(tags, [&[0, 0][..], &[0, 0, 1, 1]]);
+        // The geometry of the second layer is scaled to the extent of the first one
+        assert_eq!(
+            geometries(&merged),
+            [vec![vec![(10, 10)]], vec![vec![(20, 20)]]]
+        );
+        let roads = read_fields(layers[1].payload).unwrap();
+        assert_eq!(roads[0].payload, b"roads");
+
+        assert!(merge_tiles(&[b"\x1a\x05".to_vec(), vec![]]).is_err());
+    }
+
+    #[test]
+    fn merge_vector_tiles() {
+        // Layers named "a" and "b" with an extent of 4096 and no features
+        let a = vec![0x1a, 0x06, 0x0a, 0x01, b'a', 0x28, 0x80, 0x20];
+        let b = vec![0x1a, 0x06, 0x0a, 0x01, b'b', 0x28, 0x80, 0x20];
+        assert_eq!(merge_tiles(std::slice::from_ref(&a)).unwrap(), a);
+        assert_eq!(
+            merge_tiles(&[a.clone(), b.clone()]).unwrap(),
+            [a.clone(), b].concat()
+        );
+        assert_eq!(merge_tiles(&[a.clone(), a.clone()]).unwrap(), a);
+    }
+}
@@ -0,0 +1,606 @@
+use std::convert::TryInto;
+use std::fs::File;
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use lru::LruCache;
+use serde_json::Value as JSONValue;
+
+use crate::encoding::ContentEncoding;
+use crate::errors::{Error, Result};
+use crate::utils::{self, DataFormat};
+
+const HEADER_SIZE: usize = 127;
+/// Directories are nested at most this deep: root, leaf and leaf of a leaf
+const MAX_DIRECTORY_DEPTH: usize = 3;
+/// Number of decoded leaf directories kept per archive
+const LEAF_CACHE_SIZE: usize = 64;
+/// Largest decompressed root or leaf directory, so small directories can't inflate without limit
+const MAX_DIRECTORY_SIZE: u64 = 16 * 1024 * 1024;
+/// Largest decompressed JSON metadata, read whenever the archive is loaded
+const MAX_METADATA_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Compression of directories, metadata and tiles in a PMTiles archive
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    Unknown,
+    None,
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl Compression {
+    /// Content coding tiles compressed this way are stored with
+    pub fn content_encoding(&self) -> ContentEncoding {
+        match self {
+            Compression::Gzip => ContentEncoding::Gzip,
+            Compression::Brotli => ContentEncoding::Brotli,
+            Compression::Zstd => ContentEncoding::Zstd,
+            Compression::None | Compression::Unknown => ContentEncoding::Identity,
+        }
+    }
+
+    fn new(value: u8) -> Compression {
+        match value {
+            1 => Compression::None,
+            2 => Compression::Gzip,
+            3 => Compression::Brotli,
+            4 => Compression::Zstd,
+            _ => Compression::Unknown,
+        }
+    }
+}
+
+/// Fixed size header at the start of a PMTiles v3 archive
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub root_directory_offset: u64,
+    pub root_directory_length: u64,
+    pub metadata_offset: u64,
+    pub metadata_length: u64,
+    pub leaf_directories_offset: u64,
+    pub leaf_directories_length: u64,
+    pub tile_data_offset: u64,
+    pub tile_data_length: u64,
+    pub internal_compression: Compression,
+    pub tile_compression: Compression,
+    pub tile_type: DataFormat,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    pub min_longitude: f64,
+    pub min_latitude: f64,
+    pub max_longitude: f64,
+    pub max_latitude: f64,
+    pub center_zoom: u8,
+    pub center_longitude: f64,
+    pub center_latitude: f64,
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_coordinate(data: &[u8], offset: usize) -> f64 {
+    f64::from(i32::from_le_bytes(
+        data[offset..offset + 4].try_into().unwrap(),
+    )) / 10_000_000.0
+}
+
+impl Header {
+    pub fn parse(data: &[u8], name: &str) -> Result<Header> {
+        if data.len() < HEADER_SIZE || &data[0..7] != b"PMTiles" {
+            return Err(Error::InvalidDataFormat(format!(
+                "{name}: not a PMTiles archive"
+            )));
+        }
+        if data[7] != 3 {
+            return Err(Error::InvalidDataFormat(format!(
+                "{name}: unsupported PMTiles version {}",
+                data[7]
+            )));
+        }
+        let tile_type = match data[99] {
+            1 => DataFormat::Pbf,
+            2 => DataFormat::Png,
+            3 => DataFormat::Jpg,
+            4 => DataFormat::Webp,
+            _ => return Err(Error::UnknownTileFormat(name.to_string())),
+        };
+        Ok(Header {
+            root_directory_offset: read_u64(data, 8),
+            root_directory_length: read_u64(data, 16),
+            metadata_offset: read_u64(data, 24),
+            metadata_length: read_u64(data, 32),
+            leaf_directories_offset: read_u64(data, 40),
+            leaf_directories_length: read_u64(data, 48),
+            tile_data_offset: read_u64(data, 56),
+            tile_data_length: read_u64(data, 64),
+            internal_compression: Compression::new(data[97]),
+            tile_compression: Compression::new(data[98]),
+            tile_type,
+            min_zoom: data[100],
+            max_zoom: data[101],
+            min_longitude: read_coordinate(data, 102),
+            min_latitude: read_coordinate(data, 106),
+            max_longitude: read_coordinate(data, 110),
+            max_latitude: read_coordinate(data, 114),
+            center_zoom: data[118],
+            center_longitude: read_coordinate(data, 119),
+            center_latitude: read_coordinate(data, 123),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Entry {
+    tile_id: u64,
+    offset: u64,
+    length: u64,
+    /// Number of consecutive tile ids sharing this tile, or 0 for leaf directories
+    run_length: u64,
+}
+
+fn read_varint(data: &[u8], position: &mut usize) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data
+            .get(*position)
+            .ok_or_else(|| Error::InvalidDataFormat("truncated PMTiles directory".to_string()))?;
+        *position += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(Error::InvalidDataFormat(
+        "invalid varint in PMTiles directory".to_string(),
+    ))
+}
+
+fn parse_directory(data: &[u8]) -> Result<Vec<Entry>> {
+    let mut position = 0;
+    let count = read_varint(data, &mut position)? as usize;
+    let mut entries = vec![
+        Entry {
+            tile_id: 0,
+            offset: 0,
+            length: 0,
+            run_length: 0,
+        };
+        count.min(data.len())
+    ];
+    if entries.len() != count {
+        return Err(Error::InvalidDataFormat(
+            "truncated PMTiles directory".to_string(),
+        ));
+    }
+    let overflow = || Error::InvalidDataFormat("PMTiles directory entry overflows".to_string());
+    let mut last_id = 0u64;
+    for entry in entries.iter_mut() {
+        last_id = last_id
+            .checked_add(read_varint(data, &mut position)?)
+            .ok_or_else(overflow)?;
+        entry.tile_id = last_id;
+    }
+    for entry in entries.iter_mut() {
+        entry.run_length = read_varint(data, &mut position)?;
+    }
+    for entry in entries.iter_mut() {
+        entry.length = read_varint(data, &mut position)?;
+    }
+    for i in 0..count {
+        let offset = read_varint(data, &mut position)?;
+        entries[i].offset = if offset == 0 && i > 0 {
+            // Directly after the previous entry
+            entries[i - 1]
+                .offset
+                .checked_add(entries[i - 1].length)
+                .ok_or_else(overflow)?
+        } else {
+            offset.saturating_sub(1)
+        };
+    }
+    Ok(entries)
+}
+
+/// Find the entry for `tile_id`: either the tile itself or the leaf directory containing it
+fn find_entry(entries: &[Entry], tile_id: u64) -> Option<&Entry> {
+    let index = match entries.binary_search_by_key(&tile_id, |e| e.tile_id) {
+        Ok(index) => return Some(&entries[index]),
+        Err(0) => return None,
+        Err(index) => index - 1,
+    };
+    let entry = &entries[index];
+    if entry.run_length == 0 || tile_id - entry.tile_id < entry.run_length {
+        return Some(entry);
+    }
+    None
+}
+
+/// Position of a tile on the Hilbert curve covering all zoom levels, as defined by the spec
+pub fn tile_id(z: u32, x: u32, y: u32) -> u64 {
+    // Number of tiles in all lower zoom levels
+    let base = ((1u64 << (2 * z)) - 1) / 3;
+    let n = 1u64 << z;
+    let (mut x, mut y) = (u64::from(x), u64::from(y));
+    let mut d = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = u64::from(x & s > 0);
+        let ry = u64::from(y & s > 0);
+        d += s * s * ((3 * rx) ^ ry);
+        if ry == 0 {
+            if rx == 1 {
+                x = n - 1 - x;
+                y = n - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    base + d
+}
+
+fn decompress_limited(data: Vec<u8>, compression: Compression, limit: u64) -> Result<Vec<u8>> {
+    let data_type = match compression {
+        Compression::None if data.len() as u64 > limit => {
+            return Err(Error::InvalidDataFormat(format!(
+                "PMTiles data exceeds {limit} bytes"
+            )))
+        }
+        Compression::None => return Ok(data),
+        Compression::Gzip => DataFormat::Gzip,
+        Compression::Brotli => DataFormat::Brotli,
+        Compression::Zstd => DataFormat::Zstd,
+        Compression::Unknown => {
+            return Err(Error::InvalidDataFormat(
+                "unknown PMTiles compression".to_string(),
+            ))
+        }
+    };
+    utils::decompress_limited(&data, data_type, limit)
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, data: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, data, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut data: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !data.is_empty() {
+        match file.seek_read(data, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                data = &mut data[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A local PMTiles v3 archive
+#[derive(Debug)]
+pub struct PMTiles {
+    name: String,
+    file: File,
+    /// Size of the file when it was opened
+    size: u64,
+    pub header: Header,
+    root_directory: Arc<Vec<Entry>>,
+    /// Decoded leaf directories by their offset in the file
+    leaf_directories: Mutex<LruCache<u64, Arc<Vec<Entry>>>>,
+}
+
+impl PMTiles {
+    pub fn open(path: &Path, name: &str) -> Result<PMTiles> {
+        let io_error = |err: io::Error| Error::InvalidDataFormat(format!("{name}: {err}"));
+        let file = File::open(path).map_err(io_error)?;
+        let size = file.metadata().map_err(io_error)?.len();
+        let mut data = [0; HEADER_SIZE];
+        read_exact_at(&file, &mut data, 0).map_err(io_error)?;
+        let header = Header::parse(&data, name)?;
+        let sections = [
+            (header.root_directory_offset, header.root_directory_length),
+            (header.metadata_offset, header.metadata_length),
+            (
+                header.leaf_directories_offset,
+                header.leaf_directories_length,
+            ),
+            (header.tile_data_offset, header.tile_data_length),
+        ];
+        for (offset, length) in sections {
+            match offset.checked_add(length) {
+                Some(end) if end <= size => (),
+                _ => {
+                    return Err(Error::InvalidDataFormat(format!(
+                        "{name}: section of {length} bytes at {offset} extends past the end of the file"
+                    )))
+                }
+            }
+        }
+        let mut archive = PMTiles {
+            name: name.to_string(),
+            file,
+            size,
+            header,
+            root_directory: Arc::new(Vec::new()),
+            leaf_directories: Mutex::new(LruCache::new(
+                NonZeroUsize::new(LEAF_CACHE_SIZE).unwrap(),
+            )),
+        };
+        let length = archive.header.root_directory_length;
+        archive.root_directory =
+            archive.read_directory(archive.header.root_directory_offset, length, 0, length)?;
+        Ok(archive)
+    }
+
+    /// Read `length` bytes at `offset` within the section of the archive starting at
+    /// `start` and `section_length` bytes long. Ranges outside of the section or the
+    /// file are rejected before anything is allocated.
+    fn read(&self, start: u64, section_length: u64, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let invalid = |message: &str| {
+            Error::InvalidDataFormat(format!(
+                "{}: {message} ({length} bytes at {offset} of a section of {section_length} bytes at {start})",
+                self.name
+            ))
+        };
+        let section_end = start
+            .checked_add(section_length)
+            .ok_or_else(|| invalid("invalid section"))?;
+        if section_end > self.size {
+            return Err(invalid("section extends past the end of the file"));
+        }
+        match offset.checked_add(length) {
+            Some(end) if end <= section_length => (),
+            _ => return Err(invalid("range extends past the end of its section")),
+        }
+        let mut data = vec![0; length as usize];
+        read_exact_at(&self.file, &mut data, start + offset)
+            .map_err(|err| Error::InvalidDataFormat(format!("{}: {err}", self.name)))?;
+        Ok(data)
+    }
+
+    fn read_directory(
+        &self,
+        start: u64,
+        section_length: u64,
+        offset: u64,
+        length: u64,
+    ) -> Result<Arc<Vec<Entry>>> {
+        let data = self.read(start, section_length, offset, length)?;
+        let data = decompress_limited(data, self.header.internal_compression, MAX_DIRECTORY_SIZE)?;
+        let entries = parse_directory(&data)?;
+        Ok(Arc::new(entries))
+    }
+
+    /// Return the leaf directory at `offset` in the leaf directories section, decoding
+    /// it unless it was read recently
+    fn leaf_directory(&self, offset: u64, length: u64) -> Result<Arc<Vec<Entry>>> {
+        if let Some(entries) = self.leaf_directories.lock().unwrap().get(&offset) {
+            return Ok(entries.clone());
+        }
+        let entries = self.read_directory(
+            self.header.leaf_directories_offset,
+            self.header.leaf_directories_length,
+            offset,
+            length,
+        )?;
+        self.leaf_directories
+            .lock()
+            .unwrap()
+            .put(offset, entries.clone());
+        Ok(entries)
+    }
+
+    /// Check that the archive can still be read and starts with a valid header
+    pub fn check(&self) -> Result<()> {
+        let size = HEADER_SIZE as u64;
+        let data = self.read(0, size, 0, size)?;
+        Header::parse(&data, &self.name).map(|_| ())
+    }
+
+    /// Read the JSON metadata of the archive
+    pub fn metadata(&self) -> Result<JSONValue> {
+        let length = self.header.metadata_length;
+        if length == 0 {
+            return Ok(JSONValue::Null);
+        }
+        let data = self.read(self.header.metadata_offset, length, 0, length)?;
+        let data = decompress_limited(data, self.header.internal_compression, MAX_METADATA_SIZE)?;
+        serde_json::from_slice(&data)
+            .map_err(|err| Error::InvalidDataFormat(format!("PMTiles metadata: {err}")))
+    }
+
+    /// Read the tile at the given XYZ coordinates. The tile is returned as stored,
+    /// i.e. compressed with `header.tile_compression`.
+    pub fn get_tile(&self, z: u32, x: u32, y: u32) -> Result<Option<Vec<u8>>> {
+        let tile_id = tile_id(z, x, y);
+        let mut entries = self.root_directory.clone();
+        for _ in 0..MAX_DIRECTORY_DEPTH {
+            let (offset, length) = match find_entry(&entries, tile_id) {
+                Some(entry) if entry.run_length > 0 => {
+                    return self
+                        .read(
+                            self.header.tile_data_offset,
+                            self.header.tile_data_length,
+                            entry.offset,
+                            entry.length,
+                        )
+                        .map(Some);
+                }
+                Some(entry) => (entry.offset, entry.length),
+                None => return Ok(None),
+            };
+            entries = self.leaf_directory(offset, length)?;
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiles::get_tile_data;
+    use r2d2_sqlite::SqliteConnectionManager;
+
+    #[test]
+    fn tile_ids() {
+        // Examples from the PMTiles v3 specification
+        assert_eq!(tile_id(0, 0, 0), 0);
+        assert_eq!(tile_id(1, 0, 0), 1);
+        assert_eq!(tile_id(1, 0, 1), 2);
+        assert_eq!(tile_id(1, 1, 1), 3);
+        assert_eq!(tile_id(1, 1, 0), 4);
+        assert_eq!(tile_id(2, 0, 0), 5);
+        assert_eq!(tile_id(3, 0, 0), 21);
+    }
+
+    #[test]
+    fn read_header() {
+        let archive = PMTiles::open(
+            Path::new("./tiles/pmtiles/world_cities.pmtiles"),
+            "world_cities",
+        )
+        .unwrap();
+        assert_eq!(archive.header.tile_type, DataFormat::Pbf);
+        assert_eq!(archive.header.tile_compression, Compression::Gzip);
+        assert_eq!(archive.header.min_zoom, 0);
+        assert_eq!(archive.header.max_zoom, 6);
+        assert_eq!(
+            archive.metadata().unwrap()["name"],
+            "Major cities from Natural Earth data"
+        );
+    }
+
+    #[test]
+    fn read_tiles() {
+        // The archives were converted from the mbtiles files with the same name;
+        // world_cities uses leaf directories
+        for name in ["world_cities", "geography-class-png"] {
+            let archive =
+                PMTiles::open(Path::new(&format!("./tiles/pmtiles/{name}.pmtiles")), name).unwrap();
+            let pool = r2d2::Pool::new(SqliteConnectionManager::file(format!(
+                "./tiles/{name}.mbtiles"
+            )))
+            .unwrap();
+            let connection = pool.get().unwrap();
+            for z in 0..=archive.header.max_zoom as u32 + 1 {
+                for x in 0..1 << z {
+                    for y in 0..1 << z {
+                        let expected = get_tile_data(&connection, z, x, (1 << z) - 1 - y).unwrap();
+                        assert_eq!(archive.get_tile(z, x, y).unwrap(), expected, "{z}/{x}/{y}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_archive() {
+        assert!(PMTiles::open(Path::new("./tiles/world_cities.mbtiles"), "world_cities").is_err());
+    }
+
+    #[test]
+    fn corrupt_archive() {
+        let dir = tempdir::TempDir::new("pmtiles").unwrap();
+        let path = dir.path().join("world_cities.pmtiles");
+        let mut data = std::fs::read("./tiles/pmtiles/world_cities.pmtiles").unwrap();
+        let header = Header::parse(&data, "world_cities").unwrap();
+
+        // A root directory length far past the end of the file is rejected without
+        // allocating it
+        let mut corrupt = data.clone();
+        corrupt[16..24].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        std::fs::write(&path, &corrupt).unwrap();
+        let err = PMTiles::open(&path, "world_cities").unwrap_err();
+        assert!(
+            format!("{err}").contains("past the end of the file"),
+            "{err}"
+        );
+
+        // So is a truncated file
+        data.truncate((header.tile_data_offset + header.tile_data_length / 2) as usize);
+        std::fs::write(&path, &data).unwrap();
+        let err = PMTiles::open(&path, "world_cities").unwrap_err();
+        assert!(
+            format!("{err}").contains("past the end of the file"),
+            "{err}"
+        );
+
+        // Tiles outside of the tile data section are rejected too
+        let archive = PMTiles::open(
+            Path::new("./tiles/pmtiles/world_cities.pmtiles"),
+            "world_cities",
+        )
+        .unwrap();
+        let length = archive.header.tile_data_length;
+        let err = archive
+            .read(archive.header.tile_data_offset, length, length - 1, 1 << 40)
+            .unwrap_err();
+        assert!(
+            format!("{err}").contains("past the end of its section"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn overflowing_directory() {
+        const MAX: [u8; 10] = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        // Tile ids 1 and 1 + u64::MAX
+        let data = [&[2, 1][..], &MAX, &[1, 1, 1, 1, 1, 1, 1]].concat();
+        let err = parse_directory(&data).unwrap_err();
+        assert!(format!("{err}").contains("overflows"), "{err}");
+        // A second tile directly after a first one ending past u64::MAX
+        let data = [&[2, 0, 1, 1, 1, 2, 1][..], &MAX, &[0]].concat();
+        let err = parse_directory(&data).unwrap_err();
+        assert!(format!("{err}").contains("overflows"), "{err}");
+
+        // Directories decompressing to more than MAX_DIRECTORY_SIZE are rejected
+        let data = vec![0; MAX_DIRECTORY_SIZE as usize + 1];
+        let compressed = utils::compress(&data, DataFormat::Gzip).unwrap();
+        let err =
+            decompress_limited(compressed, Compression::Gzip, MAX_DIRECTORY_SIZE).unwrap_err();
+        assert!(format!("{err}").contains("exceeds"), "{err}");
+    }
+
+    #[test]
+    fn oversized_metadata() {
+        let dir = tempdir::TempDir::new("pmtiles").unwrap();
+        let path = dir.path().join("world_cities.pmtiles");
+        let mut data = std::fs::read("./tiles/pmtiles/world_cities.pmtiles").unwrap();
+        let header = Header::parse(&data, "world_cities").unwrap();
+        assert_eq!(header.internal_compression, Compression::Gzip);
+
+        // Metadata decompressing to more than MAX_METADATA_SIZE is appended to the file
+        let metadata = vec![b' '; MAX_METADATA_SIZE as usize + 1];
+        let compressed = utils::compress(&metadata, DataFormat::Gzip).unwrap();
+        let offset = data.len() as u64;
+        data[24..32].copy_from_slice(&offset.to_le_bytes());
+        data[32..40].copy_from_slice(&(compressed.len() as u64).to_le_bytes());
+        data.extend_from_slice(&compressed);
+        std::fs::write(&path, &data).unwrap();
+        let archive = PMTiles::open(&path, "world_cities").unwrap();
+        let err = archive.metadata().unwrap_err();
+        assert!(format!("{err}").contains("exceeds"), "{err}");
+    }
+
+    #[test]
+    fn cache_leaf_directories() {
+        let archive = PMTiles::open(
+            Path::new("./tiles/pmtiles/world_cities.pmtiles"),
+            "world_cities",
+        )
+        .unwrap();
+        let tile = archive.get_tile(6, 18, 24).unwrap();
+        let cached = archive.leaf_directories.lock().unwrap().len();
+        assert!(cached > 0);
+        assert_eq!(archive.get_tile(6, 18, 24).unwrap(), tile);
+        assert_eq!(archive.leaf_directories.lock().unwrap().len(), cached);
+    }
+}
@@ -0,0 +1,295 @@
+use std::io::Cursor;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::{self, FilterType};
+use image::{ImageEncoder, ImageFormat, Rgba, RgbaImage};
+
+use crate::errors::{Error, Result};
+use crate::utils::{get_data_format, DataFormat};
+
+/// Size of tiles generated when there is no tile to take the size from
+pub const TILE_SIZE: u32 = 256;
+const JPEG_QUALITY: u8 = 90;
+
+fn image_format(data_format: DataFormat) -> Option<ImageFormat> {
+    match data_format {
+        DataFormat::Png => Some(ImageFormat::Png),
+        DataFormat::Jpg => Some(ImageFormat::Jpeg),
+        DataFormat::Webp => Some(ImageFormat::WebP),
+        _ => None,
+    }
+}
+
+/// Check whether tiles can be decoded from and encoded to `data_format`
+pub fn is_raster_format(data_format: DataFormat) -> bool {
+    image_format(data_format).is_some()
+}
+
+pub fn decode_image(data: &[u8], data_format: DataFormat) -> Result<RgbaImage> {
+    let format = image_format(data_format)
+        .ok_or_else(|| Error::InvalidDataFormat(format!("{data_format:?} is not an image")))?;
+    image::load_from_memory_with_format(data, format)
+        .map(|image| image.to_rgba8())
+        .map_err(|err| Error::InvalidDataFormat(format!("{data_format:?}: {err}")))
+}
+
+/// Return the width and height of a raster tile without decoding it
+pub fn image_dimensions(data: &[u8]) -> Result<(u32, u32)> {
+    let data_format = get_data_format(data);
+    let format = image_format(data_format)
+        .ok_or_else(|| Error::InvalidDataFormat(format!("{data_format:?} is not an image")))?;
+    image::io::Reader::with_format(Cursor::new(data), format)
+        .into_dimensions()
+        .map_err(|err| Error::InvalidDataFormat(format!("{data_format:?}: {err}")))
+}
+
+/// Encode `image` as `data_format`. `quality` (1 to 100) applies to JPEG and WebP, WebP
+/// images are lossless without it. JPEG has no alpha channel, so transparent parts are
+/// drawn on white.
+pub fn encode_image(
+    image: &RgbaImage,
+    data_format: DataFormat,
+    quality: Option<u8>,
+) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let (width, height) = image.dimensions();
+    let result = match data_format {
+        DataFormat::Png => PngEncoder::new(Cursor::new(&mut data)).write_image(
+            image,
+            width,
+            height,
+            image::ColorType::Rgba8,
+        ),
+        DataFormat::Jpg => {
+            let mut background = RgbaImage::from_pixel(width, height, Rgba([255, 255, 255, 255]));
+            imageops::overlay(&mut background, image, 0, 0);
+            let rgb = image::DynamicImage::ImageRgba8(background).to_rgb8();
+            let quality = quality.unwrap_or(JPEG_QUALITY);
+            JpegEncoder::new_with_quality(Cursor::new(&mut data), quality).write_image(
+                &rgb,
+                width,
+                height,
+                image::ColorType::Rgb8,
+            )
+        }
+        DataFormat::Webp => match quality {
+            // The image crate only encodes lossless WebP
+            Some(quality) => {
+                let encoder = webp::Encoder::from_rgba(image, width, height);
+                return Ok(encoder.encode(f32::from(quality)).to_vec());
+            }
+            None => WebPEncoder::new_lossless(Cursor::new(&mut data)).write_image(
+                image,
+                width,
+                height,
+                image::ColorType::Rgba8,
+            ),
+        },
+        _ => {
+            return Err(Error::InvalidDataFormat(format!(
+                "{data_format:?} is not an image"
+            )))
+        }
+    };
+    result.map_err(|err| Error::InvalidDataFormat(format!("{data_format:?}: {err}")))?;
+    Ok(data)
+}
+
+/// Convert a raster tile to `data_format`
+pub fn convert_image(data: &[u8], data_format: DataFormat, quality: Option<u8>) -> Result<Vec<u8>> {
+    let image = decode_image(data, get_data_format(data))?;
+    encode_image(&image, data_format, quality)
+}
+
+/// Build a tile of twice the size from the four children of a tile at the next zoom level,
+/// given as top left, top right, bottom left and bottom right
+pub fn stitch_tiles(children: &[Vec<u8>; 4]) -> Result<RgbaImage> {
+    let images = children
+        .iter()
+        .map(|data| decode_image(data, get_data_format(data)))
+        .collect::<Result<Vec<RgbaImage>>>()?;
+    let size = images
+        .iter()
+        .map(|image| image.width().max(image.height()))
+        .max()
+        .unwrap_or(TILE_SIZE);
+    let mut result = RgbaImage::new(size * 2, size * 2);
+    for (i, image) in images.iter().enumerate() {
+        let (x, y) = (
+            i as i64 % 2 * i64::from(size),
+            i as i64 / 2 * i64::from(size),
+        );
+        if image.dimensions() == (size, size) {
+            imageops::replace(&mut result, image, x, y);
+        } else {
+            let image = imageops::resize(image, size, size, FilterType::Triangle);
+            imageops::replace(&mut result, &image, x, y);
+        }
+    }
+    Ok(result)
+}
+
+/// Scale a raster tile to twice its size
+pub fn upsample_tile(data: &[u8]) -> Result<RgbaImage> {
+    let image = decode_image(data, get_data_format(data))?;
+    let (width, height) = image.dimensions();
+    Ok(imageops::resize(
+        &image,
+        width * 2,
+        height * 2,
+        FilterType::CatmullRom,
+    ))
+}
+
+/// Build a tile `dz` zoom levels below a raster tile by scaling up the part of it covered by
+/// the descendant at `x`, `y` among its `2^dz` by `2^dz` descendants, to `scale` times the
+/// size of the tile
+pub fn overzoom_image(data: &[u8], dz: u32, x: u32, y: u32, scale: u32) -> Result<RgbaImage> {
+    let image = decode_image(data, get_data_format(data))?;
+    let (width, height) = image.dimensions();
+    let (crop_width, crop_height) = ((width >> dz).max(1), (height >> dz).max(1));
+    let part = imageops::crop_imm(
+        &image,
+        (x * crop_width).min(width - crop_width),
+        (y * crop_height).min(height - crop_height),
+        crop_width,
+        crop_height,
+    )
+    .to_image();
+    Ok(imageops::resize(
+        &part,
+        width * scale,
+        height * scale,
+        FilterType::CatmullRom,
+    ))
+}
+
+/// Alpha-blend raster tiles, given as data and its format, in order: later tiles are drawn
+/// over earlier ones. Tiles are scaled to the size of the largest tile, so e.g. 256 and 512
+/// pixel tilesets can be combined. Without any tiles the result is a transparent tile.
+pub fn blend_tiles(tiles: &[(Vec<u8>, DataFormat)]) -> Result<RgbaImage> {
+    let images = tiles
+        .iter()
+        .map(|(data, data_format)| decode_image(data, *data_format))
+        .collect::<Result<Vec<RgbaImage>>>()?;
+    let size = images
+        .iter()
+        .map(|image| image.width().max(image.height()))
+        .max()
+        .unwrap_or(TILE_SIZE);
+    let mut result = RgbaImage::new(size, size);
+    for image in images {
+        if image.dimensions() == (size, size) {
+            imageops::overlay(&mut result, &image, 0, 0);
+        } else {
+            let image = imageops::resize(&image, size, size, FilterType::Triangle);
+            imageops::overlay(&mut result, &image, 0, 0);
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(color: [u8; 4], size: u32, data_format: DataFormat) -> (Vec<u8>, DataFormat) {
+        let image = RgbaImage::from_pixel(size, size, Rgba(color));
+        (
+            encode_image(&image, data_format, None).unwrap(),
+            data_format,
+        )
+    }
+
+    #[test]
+    fn blend_in_order() {
+        let base = tile([255, 0, 0, 255], 256, DataFormat::Jpg);
+        let overlay = tile([0, 0, 255, 128], 512, DataFormat::Png);
+        let image = blend_tiles(&[base, overlay]).unwrap();
+        assert_eq!(image.dimensions(), (512, 512));
+        let Rgba([r, g, b, a]) = *image.get_pixel(100, 100);
+        assert!(r > 100 && r < 150, "{r}");
+        assert!(g < 20, "{g}");
+        assert!(b > 100 && b < 150, "{b}");
+        assert!(a >= 254, "{a}");
+
+        // An opaque tile hides the tiles below
+        let top = tile([0, 255, 0, 255], 256, DataFormat::Webp);
+        let image = blend_tiles(&[tile([255, 0, 0, 255], 256, DataFormat::Png), top]).unwrap();
+        assert_eq!(*image.get_pixel(0, 0), Rgba([0, 255, 0, 255]));
+    }
+
+    #[test]
+    fn encode_formats() {
+        let image = blend_tiles(&[]).unwrap();
+        assert_eq!(image.dimensions(), (TILE_SIZE, TILE_SIZE));
+        for data_format in [DataFormat::Png, DataFormat::Jpg, DataFormat::Webp] {
+            let data = encode_image(&image, data_format, None).unwrap();
+            assert_eq!(get_data_format(&data), data_format);
+        }
+        assert!(encode_image(&image, DataFormat::Pbf, None).is_err());
+    }
+
+    #[test]
+    fn convert_with_quality() {
+        let (png, _) = tile([10, 100, 200, 255], 256, DataFormat::Png);
+        let low = convert_image(&png, DataFormat::Jpg, Some(10)).unwrap();
+        let high = convert_image(&png, DataFormat::Jpg, Some(100)).unwrap();
+        assert_eq!(get_data_format(&low), DataFormat::Jpg);
+        assert!(low.len() < high.len());
+
+        let lossy = convert_image(&png, DataFormat::Webp, Some(50)).unwrap();
+        assert_eq!(get_data_format(&lossy), DataFormat::Webp);
+        let pixel = *decode_image(&lossy, DataFormat::Webp)
+            .unwrap()
+            .get_pixel(0, 0);
+        assert!(pixel[2] > 180, "{pixel:?}");
+
+        assert!(convert_image(b"not an image", DataFormat::Png, None).is_err());
+    }
+
+    #[test]
+    fn high_dpi_tiles() {
+        let colors = [
+            [255, 0, 0, 255],
+            [0, 255, 0, 255],
+            [0, 0, 255, 255],
+            [255, 255, 255, 255],
+        ];
+        let children = colors.map(|color| tile(color, 256, DataFormat::Png).0);
+        let image = stitch_tiles(&children).unwrap();
+        assert_eq!(image.dimensions(), (512, 512));
+        assert_eq!(*image.get_pixel(10, 10), Rgba(colors[0]));
+        assert_eq!(*image.get_pixel(500, 10), Rgba(colors[1]));
+        assert_eq!(*image.get_pixel(10, 500), Rgba(colors[2]));
+        assert_eq!(*image.get_pixel(500, 500), Rgba(colors[3]));
+
+        let image = upsample_tile(&children[0]).unwrap();
+        assert_eq!(image.dimensions(), (512, 512));
+        assert_eq!(*image.get_pixel(300, 300), Rgba(colors[0]));
+    }
+
+    #[test]
+    fn overzoom_tiles() {
+        let image = stitch_tiles(
+            &[
+                [255, 0, 0, 255],
+                [0, 255, 0, 255],
+                [0, 0, 255, 255],
+                [255, 255, 255, 255],
+            ]
+            .map(|color| tile(color, 128, DataFormat::Png).0),
+        )
+        .unwrap();
+        let data = encode_image(&image, DataFormat::Png, None).unwrap();
+        let top_right = overzoom_image(&data, 1, 1, 0, 1).unwrap();
+        assert_eq!(top_right.dimensions(), (256, 256));
+        assert_eq!(*top_right.get_pixel(128, 128), Rgba([0, 255, 0, 255]));
+        // Two levels down, the bottom left tile is in the blue quarter
+        let image = overzoom_image(&data, 2, 0, 3, 2).unwrap();
+        assert_eq!(image.dimensions(), (512, 512));
+        assert_eq!(*image.get_pixel(0, 511), Rgba([0, 0, 255, 255]));
+    }
+}
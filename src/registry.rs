@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use hyper::body::Bytes;
+
+use crate::config::TilesetConfig;
+use crate::errors::Result;
+use crate::tile_cache::{TileCache, TileKey};
+use crate::tiles::{TileMeta, TileSource};
+
+/// Shared, swappable map of tileset ids to their metadata.
+///
+/// Request handlers clone the `TileMeta` they need out of the registry, so replacing
+/// or removing an entry never affects requests that are already in flight.
+#[derive(Clone, Default, Debug)]
+pub struct Registry {
+    tilesets: Arc<RwLock<HashMap<String, TileMeta>>>,
+    cache: Option<Arc<TileCache>>,
+    /// Incremented by every update, so tiles read before an update aren't cached after it
+    generation: Arc<AtomicU64>,
+    /// Settings from the configuration file, by tileset id
+    config: Arc<HashMap<String, TilesetConfig>>,
+    /// Tileset ids by alias
+    aliases: Arc<HashMap<String, String>>,
+}
+
+/// Changes made to the registry within a single `Registry::update` call
+pub struct Changes<'a> {
+    tilesets: &'a mut HashMap<String, TileMeta>,
+    changed: Vec<String>,
+}
+
+impl<'a> Changes<'a> {
+    pub fn get(&self, id: &str) -> Option<&TileMeta> {
+        self.tilesets.get(id)
+    }
+
+    pub fn insert(&mut self, id: String, tile_meta: TileMeta) -> Option<TileMeta> {
+        self.changed.push(id.clone());
+        self.tilesets.insert(id, tile_meta)
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<TileMeta> {
+        self.changed.push(id.to_string());
+        self.tilesets.remove(id)
+    }
+}
+
+impl Registry {
+    pub fn new(tilesets: HashMap<String, TileMeta>) -> Registry {
+        Registry {
+            tilesets: Arc::new(RwLock::new(tilesets)),
+            cache: None,
+            generation: Arc::new(AtomicU64::new(0)),
+            config: Arc::new(HashMap::new()),
+            aliases: Arc::new(HashMap::new()),
+        }
+    }
+
+    /// Apply the configuration file settings of tilesets, which can be registered later
+    pub fn with_config(mut self, config: HashMap<String, TilesetConfig>) -> Registry {
+        let aliases = config
+            .iter()
+            .filter_map(|(id, c)| Some((c.alias.clone()?, id.clone())))
+            .collect();
+        self.aliases = Arc::new(aliases);
+        self.config = Arc::new(config);
+        self
+    }
+
+    /// Return the id of the tileset `id` refers to, which is `id` unless it is an alias
+    pub fn resolve<'a>(&'a self, id: &'a str) -> &'a str {
+        self.aliases.get(id).map_or(id, String::as_str)
+    }
+
+    /// Return the configuration file settings of tileset `id`
+    pub fn config(&self, id: &str) -> Option<&TilesetConfig> {
+        self.config.get(self.resolve(id))
+    }
+
+    /// Return the configuration file settings of all tilesets, registered or not
+    pub fn configs(&self) -> &HashMap<String, TilesetConfig> {
+        &self.config
+    }
+
+    /// Cache tiles of the registered tilesets in `cache`
+    pub fn with_cache(mut self, cache: TileCache) -> Registry {
+        self.cache = Some(Arc::new(cache));
+        self
+    }
+
+    pub fn cache(&self) -> Option<&TileCache> {
+        self.cache.as_deref()
+    }
+
+    pub fn get(&self, id: &str) -> Option<TileMeta> {
+        self.tilesets.read().unwrap().get(self.resolve(id)).cloned()
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.tilesets.read().unwrap().contains_key(self.resolve(id))
+    }
+
+    /// Return a copy of all registered tilesets
+    pub fn snapshot(&self) -> HashMap<String, TileMeta> {
+        self.tilesets.read().unwrap().clone()
+    }
+
+    /// Check whether tiles of tileset `id` are cached. Tilesets can be excluded from the
+    /// cache in the configuration file, and tiles of tile directories are never cached
+    /// because their files can change without the registry noticing.
+    #[allow(clippy::unnecessary_map_or)] // is_none_or needs Rust 1.82
+    fn is_cached(&self, id: &str) -> bool {
+        let directory = match self.tilesets.read().unwrap().get(self.resolve(id)) {
+            Some(tile_meta) => matches!(tile_meta.source, TileSource::Directory(_)),
+            None => false,
+        };
+        !directory && self.config(id).map_or(true, |c| c.cache)
+    }
+
+    /// Return a tile of tileset `id` from the cache, or read it with `load` and cache it
+    pub fn get_tile<F>(&self, id: &str, z: u32, x: u32, y: u32, load: F) -> Result<Option<Bytes>>
+    where
+        F: FnOnce() -> Result<Option<Vec<u8>>>,
+    {
+        let cache = match &self.cache {
+            Some(cache) if self.is_cached(id) => cache,
+            _ => return Ok(load()?.map(Bytes::from)),
+        };
+        let key = TileKey::new(self.resolve(id), z, x, y);
+        if let Some(data) = cache.get(&key) {
+            return Ok(data);
+        }
+        let generation = self.generation.load(Ordering::SeqCst);
+        let data = load()?.map(Bytes::from);
+        // Holding the read lock keeps updates from running between the check and the insert
+        let _tilesets = self.tilesets.read().unwrap();
+        if self.generation.load(Ordering::SeqCst) == generation {
+            cache.insert(key, data.clone());
+        }
+        Ok(data)
+    }
+
+    /// Apply a batch of changes under a single write lock, so readers observe either
+    /// the old or the new set of tilesets and never a partially updated one.
+    /// Cached tiles of every inserted or removed tileset are dropped.
+    /// Expensive work (e.g. opening mbtiles files) should happen before calling this.
+    pub fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut Changes),
+    {
+        let mut tilesets = self.tilesets.write().unwrap();
+        let mut changes = Changes {
+            tilesets: &mut tilesets,
+            changed: Vec::new(),
+        };
+        f(&mut changes);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        if let Some(cache) = &self.cache {
+            for id in &changes.changed {
+                cache.invalidate(id);
+            }
+        }
+    }
+
+    /// Remove all tilesets, closing their connection pools once no request holds a
+    /// copy of their metadata anymore
+    pub fn close(&self) {
+        self.tilesets.write().unwrap().clear();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiles::{discover_tilesets, get_tile_details};
+    use std::path::PathBuf;
+
+    #[test]
+    fn update_swaps_tilesets() {
+        let registry = Registry::new(discover_tilesets(String::new(), &PathBuf::from("./tiles")));
+        let before = registry.get("geography-class-png").unwrap();
+
+        registry.update(|tilesets| {
+            tilesets.remove("geography-class-png");
+        });
+
+        assert!(!registry.contains("geography-class-png"));
+        assert!(registry.contains("world_cities"));
+        // Previously cloned metadata keeps working after the entry is removed
+        assert!(before.source.get_tile(0, 0, 0).unwrap().is_some());
+    }
+
+    #[test]
+    fn update_invalidates_cached_tiles() {
+        let registry = Registry::new(discover_tilesets(String::new(), &PathBuf::from("./tiles")))
+            .with_cache(TileCache::new(1_000_000));
+        let cache = registry.cache().unwrap();
+        let key = TileKey::new("world_cities", 0, 0, 0);
+        cache.insert(key.clone(), Some(Bytes::from_static(b"tile")));
+        cache.insert(TileKey::new("geography-class-png", 0, 0, 0), None);
+
+        let tile_meta = get_tile_details(
+            &PathBuf::from("./tiles/world_cities.mbtiles"),
+            "world_cities",
+        )
+        .unwrap();
+        registry.update(|tilesets| {
+            tilesets.insert("world_cities".to_string(), tile_meta);
+        });
+
+        assert!(cache.get(&key).is_none());
+        assert!(cache
+            .get(&TileKey::new("geography-class-png", 0, 0, 0))
+            .is_some());
+    }
+
+    #[test]
+    fn get_tile_through_cache() {
+        let registry = Registry::new(HashMap::new()).with_cache(TileCache::new(1_000_000));
+        let data = registry
+            .get_tile("a", 0, 0, 0, || Ok(Some(b"tile".to_vec())))
+            .unwrap();
+        assert_eq!(data, Some(Bytes::from_static(b"tile")));
+        let data = registry
+            .get_tile("a", 0, 0, 0, || panic!("tile should be cached"))
+            .unwrap();
+        assert_eq!(data, Some(Bytes::from_static(b"tile")));
+
+        // Tiles read while the registry is updated are not cached
+        registry
+            .get_tile("a", 1, 0, 0, || {
+                registry.update(|_| ());
+                Ok(None)
+            })
+            .unwrap();
+        assert!(registry
+            .cache()
+            .unwrap()
+            .get(&TileKey::new("a", 1, 0, 0))
+            .is_none());
+    }
+
+    #[test]
+    fn resolve_aliases() {
+        let config = TilesetConfig {
+            alias: Some("cities".to_string()),
+            cache: false,
+            ..Default::default()
+        };
+        let registry = Registry::new(discover_tilesets(String::new(), &PathBuf::from("./tiles")))
+            .with_cache(TileCache::new(1_000_000))
+            .with_config(HashMap::from([("world_cities".to_string(), config)]));
+        assert_eq!(registry.resolve("cities"), "world_cities");
+        assert_eq!(registry.resolve("world_cities"), "world_cities");
+        assert_eq!(registry.get("cities").unwrap().id, "world_cities");
+        assert!(registry.contains("world_cities"));
+        assert!(!registry.config("cities").unwrap().cache);
+
+        // Tiles of the tileset are not cached
+        registry
+            .get_tile("cities", 0, 0, 0, || Ok(Some(b"tile".to_vec())))
+            .unwrap();
+        let key = TileKey::new("world_cities", 0, 0, 0);
+        assert!(registry.cache().unwrap().get(&key).is_none());
+    }
+
+    #[test]
+    fn skip_cache_for_directories() {
+        let registry = Registry::new(discover_tilesets(String::new(), &PathBuf::from("./tiles")))
+            .with_cache(TileCache::new(1_000_000));
+        for id in ["directory/world_cities", "world_cities"] {
+            registry
+                .get_tile(id, 0, 0, 0, || Ok(Some(b"tile".to_vec())))
+                .unwrap();
+        }
+        let cache = registry.cache().unwrap();
+        assert!(cache
+            .get(&TileKey::new("directory/world_cities", 0, 0, 0))
+            .is_none());
+        assert!(cache.get(&TileKey::new("world_cities", 0, 0, 0)).is_some());
+    }
+}
@@ -1,33 +1,607 @@
+use std::error::Error as StdError;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::server::accept::{self, Accept};
+use hyper::server::conn::{AddrIncoming, AddrStream};
 use hyper::service::{make_service_fn, service_fn};
 use hyper::Server;
+use log::{debug, info, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
 
-use crate::config::Args;
-use crate::service;
+use crate::access_log::{AccessLog, RemoteAddr};
+use crate::config::{Args, Bind};
+use crate::health::ReadinessCache;
+use crate::metrics::Metrics;
+use crate::registry::Registry;
+use crate::service::{self, Context, Tls};
+use crate::tile_cache::TileCache;
+use crate::tls::{server_config, CertificateResolver, RELOAD_INTERVAL};
+use crate::watcher;
 
-#[tokio::main]
-pub async fn run(args: Args) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let addr = ([0, 0, 0, 0], args.port).into();
-    let server = Server::try_bind(&addr)?;
-
-    let service = make_service_fn(move |_conn| {
-        let tilesets = args.tilesets.clone();
-        let allowed_hosts = args.allowed_hosts.clone();
-        let headers = args.headers.clone();
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// Time allowed for a TLS handshake, so stalled clients don't hold connections open
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pause after failing to accept a connection, e.g. with too many open files, so
+/// connections can close before the next attempt
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+/// Connections which may know the address of the client
+trait PeerAddr {
+    fn peer_addr(&self) -> Option<SocketAddr>;
+}
+
+impl PeerAddr for AddrStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        Some(self.remote_addr())
+    }
+}
+
+impl PeerAddr for TlsStream<TcpStream> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().0.peer_addr().ok()
+    }
+}
+
+#[cfg(unix)]
+impl PeerAddr for tokio::net::UnixStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// A bound socket, ready to accept connections
+enum Listener {
+    Tcp(AddrIncoming),
+    Tls(TcpListener, TlsAcceptor),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    /// Bind to `bind`. TCP connections are served over TLS when `tls` is given.
+    fn bind(
+        bind: &Bind,
+        socket_mode: Option<u32>,
+        tls: Option<&TlsAcceptor>,
+    ) -> Result<Listener, BoxError> {
+        match bind {
+            Bind::Tcp(addr) => match tls {
+                Some(acceptor) => {
+                    let listener = std::net::TcpListener::bind(addr)?;
+                    listener.set_nonblocking(true)?;
+                    Ok(Listener::Tls(
+                        TcpListener::from_std(listener)?,
+                        acceptor.clone(),
+                    ))
+                }
+                None => Ok(Listener::Tcp(AddrIncoming::bind(addr)?)),
+            },
+            #[cfg(unix)]
+            Bind::Unix(path) => {
+                use std::fs::{remove_file, set_permissions, symlink_metadata, Permissions};
+                use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+                // A socket left behind by a previous run would make binding fail, but one
+                // which still accepts connections belongs to a running server
+                if let Ok(metadata) = symlink_metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        match std::os::unix::net::UnixStream::connect(path) {
+                            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                                remove_file(path)?
+                            }
+                            _ => {
+                                return Err(io::Error::new(
+                                    io::ErrorKind::AddrInUse,
+                                    format!("{} is in use", path.display()),
+                                )
+                                .into())
+                            }
+                        }
+                    }
+                }
+                let listener = tokio::net::UnixListener::bind(path)?;
+                if let Some(mode) = socket_mode {
+                    set_permissions(path, Permissions::from_mode(mode))?;
+                }
+                Ok(Listener::Unix(listener))
+            }
+            #[cfg(not(unix))]
+            Bind::Unix(_) => Err(format!("Unix sockets are not supported: {bind}").into()),
+        }
+    }
+
+    /// Serve requests until `shutdown` changes, then wait for open connections to complete
+    async fn serve(
+        self,
+        context: Arc<Context>,
+        shutdown: watch::Receiver<bool>,
+    ) -> hyper::Result<()> {
+        match self {
+            Listener::Tcp(incoming) => serve(incoming, context, false, shutdown).await,
+            Listener::Tls(listener, acceptor) => {
+                // Handshakes run in their own tasks, so slow clients don't block others
+                let (sender, mut receiver) = mpsc::channel(64);
+                let mut stopped = shutdown.clone();
+                tokio::spawn(async move {
+                    loop {
+                        let accepted = tokio::select! {
+                            accepted = listener.accept() => accepted,
+                            _ = stopped.changed() => break,
+                        };
+                        let (stream, addr) = match accepted {
+                            Ok(connection) => connection,
+                            Err(err) => {
+                                warn!("Accept error: {err}");
+                                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                                continue;
+                            }
+                        };
+                        let (acceptor, sender) = (acceptor.clone(), sender.clone());
+                        tokio::spawn(async move {
+                            let handshake = acceptor.accept(stream);
+                            match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                                Ok(Ok(stream)) => {
+                                    let _ = sender.send(stream).await;
+                                }
+                                Ok(Err(err)) => debug!("TLS handshake with {addr} failed: {err}"),
+                                Err(_) => debug!("TLS handshake with {addr} timed out"),
+                            }
+                        });
+                    }
+                });
+                let incoming = accept::poll_fn(move |cx| {
+                    receiver
+                        .poll_recv(cx)
+                        .map(|stream| stream.map(Ok::<_, io::Error>))
+                });
+                serve(incoming, context, true, shutdown).await
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let incoming = retry_accept(move |cx| {
+                    listener
+                        .poll_accept(cx)
+                        .map(|result| result.map(|(stream, _)| stream))
+                });
+                serve(incoming, context, false, shutdown).await
+            }
+        }
+    }
+}
+
+/// Accept connections with `poll_accept`, logging errors and pausing after them instead
+/// of passing them on to hyper, which would stop the server
+#[cfg(unix)]
+fn retry_accept<S, F>(mut poll_accept: F) -> impl Accept<Conn = S, Error = io::Error>
+where
+    F: FnMut(&mut std::task::Context) -> std::task::Poll<io::Result<S>>,
+{
+    use std::pin::Pin;
+    use std::task::{ready, Poll};
+
+    let mut delay: Option<Pin<Box<tokio::time::Sleep>>> = None;
+    accept::poll_fn(move |cx| loop {
+        if let Some(sleep) = &mut delay {
+            ready!(sleep.as_mut().poll(cx));
+            delay = None;
+        }
+        match ready!(poll_accept(cx)) {
+            Ok(stream) => return Poll::Ready(Some(Ok(stream))),
+            Err(err) => {
+                warn!("Accept error: {err}");
+                delay = Some(Box::pin(tokio::time::sleep(ACCEPT_ERROR_DELAY)));
+            }
+        }
+    })
+}
+
+/// Serve requests for connections accepted by `incoming`, which are encrypted if `tls`.
+/// Once `shutdown` changes, no more connections are accepted, idle connections are
+/// closed and the others are closed after their current request.
+async fn serve<I>(
+    incoming: I,
+    context: Arc<Context>,
+    tls: bool,
+    mut shutdown: watch::Receiver<bool>,
+) -> hyper::Result<()>
+where
+    I: Accept,
+    I::Error: Into<BoxError>,
+    I::Conn: PeerAddr + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = make_service_fn(move |conn: &I::Conn| {
+        let context = context.clone();
+        let remote_addr = conn.peer_addr();
         async move {
-            Ok::<_, hyper::Error>(service_fn(move |req| {
-                service::get_service(
-                    req,
-                    tilesets.clone(),
-                    allowed_hosts.clone(),
-                    headers.clone(),
-                    args.disable_preview,
-                )
+            Ok::<_, hyper::Error>(service_fn(move |mut req| {
+                if tls {
+                    req.extensions_mut().insert(Tls);
+                }
+                if let Some(addr) = remote_addr {
+                    req.extensions_mut().insert(RemoteAddr(addr));
+                }
+                service::get_service(req, context.clone())
             }))
         }
     });
+    Server::builder(incoming)
+        .serve(service)
+        .with_graceful_shutdown(async move {
+            let _ = shutdown.changed().await;
+        })
+        .await
+}
+
+/// Complete when the process receives SIGINT or, on Unix, SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => (),
+                    _ = terminate.recv() => (),
+                }
+                return;
+            }
+            Err(err) => warn!("Cannot handle SIGTERM: {err}"),
+        }
+    }
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        warn!("Cannot handle SIGINT: {err}");
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Reopen the access log file on SIGHUP, so it can be rotated
+#[cfg(unix)]
+async fn reopen_access_log(context: Arc<Context>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => return warn!("Cannot handle SIGHUP: {err}"),
+    };
+    while hangup.recv().await.is_some() {
+        if let Some(access_log) = &context.access_log {
+            access_log.reopen();
+        }
+    }
+}
+
+#[tokio::main]
+pub async fn run(args: Args) -> Result<(), BoxError> {
+    start(args, shutdown_signal()).await
+}
+
+/// Listen on all addresses of `args` and serve requests until one of them fails or
+/// `shutdown` completes. On shutdown, requests in flight get `args.shutdown_timeout`
+/// seconds to complete, after which an error is returned.
+async fn start<F>(args: Args, shutdown: F) -> Result<(), BoxError>
+where
+    F: Future<Output = ()>,
+{
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            let resolver = Arc::new(CertificateResolver::new(cert, key)?);
+            tokio::spawn(resolver.clone().watch(RELOAD_INTERVAL));
+            Some(TlsAcceptor::from(server_config(resolver)))
+        }
+        _ => None,
+    };
+    let access_log = match &args.access_log {
+        Some(destination) => Some(
+            AccessLog::open(destination, args.access_log_format, args.access_log_sample)
+                .map_err(|err| format!("Cannot open access log {destination}: {err}"))?,
+        ),
+        None => None,
+    };
+    // Bind everything first, so no requests are served if an address is unavailable
+    let listeners = args
+        .binds
+        .iter()
+        .map(|bind| Ok((bind, Listener::bind(bind, args.socket_mode, tls.as_ref())?)))
+        .collect::<Result<Vec<_>, BoxError>>()?;
+
+    let mut tilesets = Registry::new(args.tilesets).with_config(args.tileset_config);
+    if args.cache_size > 0 {
+        tilesets = tilesets.with_cache(TileCache::new(args.cache_size * 1024 * 1024));
+    }
+    if args.watch {
+        tokio::spawn(watcher::watch(
+            args.directory.clone(),
+            tilesets.clone(),
+            Duration::from_secs(args.watch_interval),
+        ));
+    }
+
+    let context = Arc::new(Context {
+        tilesets,
+        directory: args.directory,
+        allowed_hosts: args.allowed_hosts,
+        headers: args.headers,
+        disable_preview: args.disable_preview,
+        out_of_bounds: args.out_of_bounds,
+        overzoom: args.overzoom,
+        missing_tiles: args.missing_tiles,
+        admin_token: args.admin_token,
+        metrics: Metrics::default(),
+        access_log,
+        readiness: ReadinessCache::default(),
+    });
+    #[cfg(unix)]
+    if context.access_log.is_some() {
+        tokio::spawn(reopen_access_log(context.clone()));
+    }
+
+    let (stop, stopped) = watch::channel(false);
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    for (bind, listener) in listeners {
+        match (bind, &listener) {
+            (Bind::Tcp(addr), Listener::Tls(..)) => println!("Listening on https://{addr}"),
+            _ => println!("Listening on {bind}"),
+        }
+        let (context, stopped, sender) = (context.clone(), stopped.clone(), sender.clone());
+        tokio::spawn(async move {
+            let _ = sender.send(listener.serve(context, stopped).await);
+        });
+    }
+    drop(sender);
+
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            result = receiver.recv() => match result {
+                Some(result) => result?,
+                None => return Ok(()),
+            },
+            _ = &mut shutdown => break,
+        }
+    }
+
+    info!("Shutting down, waiting for requests in flight to complete");
+    let _ = stop.send(true);
+    let timeout = Duration::from_secs(args.shutdown_timeout);
+    let drained = tokio::time::timeout(timeout, async {
+        while let Some(result) = receiver.recv().await {
+            result?;
+        }
+        Ok::<_, BoxError>(())
+    });
+    let result = match drained.await {
+        Ok(result) => result,
+        Err(_) => Err(format!(
+            "Requests still in flight after {} seconds",
+            args.shutdown_timeout
+        )
+        .into()),
+    };
+    context.tilesets.close();
+    #[cfg(unix)]
+    for bind in &args.binds {
+        if let Bind::Unix(path) = bind {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+    if result.is_ok() {
+        info!("Shutdown complete");
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiles::discover_tilesets;
+    use std::path::{Path, PathBuf};
+    use tempdir::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn retry_accept_errors() {
+        use std::pin::Pin;
+        use std::task::Poll;
+
+        let mut results = vec![Ok(2), Err(io::Error::from_raw_os_error(24)), Ok(1)];
+        let mut incoming = retry_accept(move |_| Poll::Ready(results.pop().unwrap()));
+        let mut incoming = Pin::new(&mut incoming);
+        let start = std::time::Instant::now();
+        for expected in [1, 2] {
+            let accepted = std::future::poll_fn(|cx| incoming.as_mut().poll_accept(cx)).await;
+            assert_eq!(accepted.unwrap().unwrap(), expected);
+        }
+        assert!(start.elapsed() >= ACCEPT_ERROR_DELAY);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serve_unix_socket() {
+        use clap::Parser;
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("socket").unwrap();
+        let path = dir.path().join("mbtileserver.sock");
+        // A stale socket is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let bind = format!("unix:{}", path.display());
+        let args = Args::try_parse_from(["", "--bind", &bind, "--socket-mode", "600"])
+            .unwrap()
+            .post_parse()
+            .unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(start(args, async move {
+            let _ = stopped.await;
+        }));
+        for _ in 0..100 {
+            if tokio::net::UnixStream::connect(&path).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /services HTTP/1.0\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.0 200 OK"), "{response}");
+
+        // Idle keep-alive connections don't delay the shutdown, which removes the socket
+        let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+        let connection = tokio::spawn(connection);
+        let request = hyper::Request::get("/services")
+            .header("host", "localhost")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), 200);
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+        stop.send(()).unwrap();
+        connection.await.unwrap().unwrap();
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket_in_use() {
+        let dir = TempDir::new("socket").unwrap();
+        let path = dir.path().join("mbtileserver.sock");
+        let _listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let bind = Bind::Unix(path.clone());
+        match Listener::bind(&bind, None, None) {
+            Err(err) => assert!(err.to_string().contains("is in use"), "{err}"),
+            Ok(_) => panic!("bound to a socket in use"),
+        }
+        assert!(path.exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn shutdown_timeout() {
+        use clap::Parser;
+
+        let dir = TempDir::new("socket").unwrap();
+        let path = dir.path().join("mbtileserver.sock");
+        let bind = format!("unix:{}", path.display());
+        let args = Args::try_parse_from(["", "--bind", &bind, "--shutdown-timeout", "1"])
+            .unwrap()
+            .post_parse()
+            .unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(start(args, async move {
+            let _ = stopped.await;
+        }));
+        let mut stream = loop {
+            match tokio::net::UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        // A request that never completes
+        stream
+            .write_all(b"GET /services HTTP/1.1\r\nHost: localhost\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stop.send(()).unwrap();
+        let err = server.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("after 1 seconds"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn serve_tls() {
+        use hyper::client::conn::Builder;
+        use hyper::{body, Body, Request, Version};
+        use rustls::{ClientConfig, RootCertStore, ServerName};
+        use tokio_rustls::TlsConnector;
+
+        let resolver = CertificateResolver::new(
+            Path::new("./testdata/tls/cert.pem"),
+            Path::new("./testdata/tls/key.pem"),
+        )
+        .unwrap();
+        let acceptor = TlsAcceptor::from(server_config(Arc::new(resolver)));
+        let bind = Bind::Tcp("127.0.0.1:0".parse().unwrap());
+        let listener = Listener::bind(&bind, None, Some(&acceptor)).unwrap();
+        let addr = match &listener {
+            Listener::Tls(listener, _) => listener.local_addr().unwrap(),
+            _ => panic!("TCP connections should be encrypted"),
+        };
+        let context = Context {
+            tilesets: Registry::new(discover_tilesets(String::new(), &PathBuf::from("./tiles"))),
+            allowed_hosts: vec!["*".to_string()],
+            ..Default::default()
+        };
+        let (_stop, stopped) = watch::channel(false);
+        let server = tokio::spawn(listener.serve(Arc::new(context), stopped));
+
+        let mut roots = RootCertStore::empty();
+        let cert = rustls_pemfile::certs(&mut &std::fs::read("./testdata/tls/ca.pem").unwrap()[..])
+            .unwrap();
+        roots.add_parsable_certificates(&cert);
+        let connect = |alpn: &[u8]| {
+            let mut config = ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots.clone())
+                .with_no_client_auth();
+            config.alpn_protocols = vec![alpn.to_vec()];
+            let connector = TlsConnector::from(Arc::new(config));
+            async move {
+                let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+                let name = ServerName::try_from("localhost").unwrap();
+                connector.connect(name, stream).await.unwrap()
+            }
+        };
 
-    println!("Listening on http://{addr}");
-    server.serve(service).await?;
+        // HTTP/1.1 requests get https:// URLs in TileJSON
+        let stream = connect(b"http/1.1").await;
+        let (mut sender, connection) = Builder::new().handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let request = Request::get("/services/world_cities")
+            .header("host", "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        let tilejson: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let tiles = tilejson["tiles"][0].as_str().unwrap();
+        assert!(tiles.starts_with("https://localhost/services/"), "{tiles}");
 
-    Ok(())
+        // HTTP/2 is negotiated over ALPN
+        let stream = connect(b"h2").await;
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+        let (mut sender, connection) = Builder::new()
+            .http2_only(true)
+            .handshake(stream)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        let request = Request::get("https://localhost/services")
+            .body(Body::empty())
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.version(), Version::HTTP_2);
+        server.abort();
+    }
 }
@@ -1,60 +1,107 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
 
-use hyper::header::{CONTENT_ENCODING, CONTENT_TYPE, HOST};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{CONTENT_TYPE, HOST};
+use hyper::http::response::Builder;
 use hyper::{Body, Request, Response, StatusCode};
+use image::RgbaImage;
 use lazy_static::lazy_static;
+use log::{debug, error};
 use regex::Regex;
 use serde_json::json;
+use tilejson::TileJSON;
 
-use crate::errors::Result;
-use crate::tiles::{get_grid_data, get_tile_data, TileMeta, TileSummaryJSON};
-use crate::utils::{encode, get_blank_image, DataFormat};
+use crate::access_log::{AccessLog, Entry};
+use crate::admin;
+use crate::caching::conditional_response;
+use crate::composite::merge_tilejson;
+use crate::config::{MissingTile, MissingTiles, OutOfBounds};
+use crate::encoding::{encode_response, stored_encoding, transcode, ContentEncoding};
+use crate::errors::{Error, Result};
+use crate::health::{health_response, ready_response, ReadinessCache};
+use crate::metrics::{metrics_response, Metrics};
+use crate::mvt;
+use crate::raster::{
+    blend_tiles, convert_image, encode_image, is_raster_format, overzoom_image, stitch_tiles,
+    upsample_tile,
+};
+use crate::registry::Registry;
+use crate::tiles::{TileMeta, TileSummaryJSON};
+use crate::utils::{get_data_format, DataFormat};
 
 lazy_static! {
     static ref TILE_URL_RE: Regex =
-        Regex::new(r"^/services/(?P<tile_path>.*)/tiles/(?P<z>\d+)/(?P<x>\d+)/(?P<y>\d+)\.(?P<format>[a-zA-Z]+)/?(\?(?P<query>.*))?").unwrap();
+        Regex::new(r"^/services/(?P<tile_path>.*)/tiles/(?P<z>\d+)/(?P<x>\d+)/(?P<y>\d+)(?P<scale>@2x)?\.(?P<format>[a-zA-Z]+)/?(\?(?P<query>.*))?").unwrap();
 }
 
-#[allow(dead_code)]
-static INTERNAL_SERVER_ERROR: &[u8] = b"Internal Server Error";
-static FORBIDDEN: &[u8] = b"Forbidden";
-static NOT_FOUND: &[u8] = b"Not Found";
-static NO_CONTENT: &[u8] = b"";
+/// Request extension marking requests received over TLS
+#[derive(Clone, Copy, Debug)]
+pub struct Tls;
 
-fn not_found() -> Response<Body> {
+/// Settings and state shared by all requests
+#[derive(Debug, Default)]
+pub struct Context {
+    pub tilesets: Registry,
+    pub directory: PathBuf,
+    pub allowed_hosts: Vec<String>,
+    pub headers: Vec<(String, String)>,
+    pub disable_preview: bool,
+    pub out_of_bounds: OutOfBounds,
+    pub overzoom: u8,
+    pub missing_tiles: MissingTiles,
+    pub admin_token: Option<String>,
+    pub metrics: Metrics,
+    pub access_log: Option<AccessLog>,
+    pub readiness: ReadinessCache,
+}
+
+/// Build an RFC 7807 problem details response
+pub(crate) fn problem(status: StatusCode, detail: &str) -> Response<Body> {
+    let body = json!({
+        "type": "about:blank",
+        "title": status.canonical_reason().unwrap_or_default(),
+        "status": status.as_u16(),
+        "detail": detail,
+    });
     Response::builder()
-        .status(StatusCode::NOT_FOUND)
-        .body(NOT_FOUND.into())
+        .status(status)
+        .header(CONTENT_TYPE, "application/problem+json")
+        .body(Body::from(body.to_string()))
         .unwrap()
 }
 
-fn no_content() -> Response<Body> {
+pub(crate) fn error_response(err: &Error) -> Response<Body> {
+    problem(err.status_code(), &err.to_string())
+}
+
+pub(crate) fn not_found() -> Response<Body> {
+    problem(StatusCode::NOT_FOUND, "Not Found")
+}
+
+pub(crate) fn no_content() -> Response<Body> {
     Response::builder()
         .status(StatusCode::NO_CONTENT)
-        .body(NO_CONTENT.into())
+        .body(Body::empty())
         .unwrap()
 }
 
-fn forbidden() -> Response<Body> {
-    Response::builder()
-        .status(StatusCode::FORBIDDEN)
-        .body(FORBIDDEN.into())
-        .unwrap()
+/// Response for a missing tile which is not replaced by a blank tile or an image
+fn missing_tile(policy: &MissingTile) -> Response<Body> {
+    match policy {
+        MissingTile::NotFound => not_found(),
+        _ => no_content(),
+    }
 }
 
-#[allow(dead_code)]
-fn server_error() -> Response<Body> {
-    Response::builder()
-        .status(StatusCode::INTERNAL_SERVER_ERROR)
-        .body(INTERNAL_SERVER_ERROR.into())
-        .unwrap()
+fn forbidden() -> Response<Body> {
+    problem(StatusCode::FORBIDDEN, "Forbidden")
 }
 
-fn bad_request(msg: String) -> Response<Body> {
-    Response::builder()
-        .status(StatusCode::BAD_REQUEST)
-        .body(Body::from(msg))
-        .unwrap()
+pub(crate) fn bad_request(msg: String) -> Response<Body> {
+    problem(StatusCode::BAD_REQUEST, &msg)
 }
 
 pub fn tile_map() -> Response<Body> {
@@ -72,7 +119,7 @@ fn get_host(req: &Request<Body>) -> Option<&str> {
     }
 
     if let Some(host) = req.headers().get(HOST) {
-        return Some(host.to_str().unwrap());
+        return host.to_str().ok();
     }
 
     None
@@ -100,24 +147,318 @@ fn is_host_valid(host: &Option<&str>, allowed_hosts: &[String]) -> bool {
     false
 }
 
-pub async fn get_service(
-    request: Request<Body>,
-    tilesets: HashMap<String, TileMeta>,
-    allowed_hosts: Vec<String>,
-    headers: Vec<(String, String)>,
-    disable_preview: bool,
+/// Parse XYZ tile coordinates from the URL and check that they are inside the tile grid
+fn parse_tile_coordinates(z: &str, x: &str, y: &str) -> Result<(u32, u32, u32)> {
+    let parse = |name: &str, value: &str| {
+        value
+            .parse::<u32>()
+            .map_err(|_| Error::InvalidTileCoordinates(format!("{name}={value}")))
+    };
+    let (z, x, y) = (parse("z", z)?, parse("x", x)?, parse("y", y)?);
+    let size = 1u64
+        .checked_shl(z)
+        .filter(|_| z < 32)
+        .ok_or_else(|| Error::InvalidTileCoordinates(format!("zoom level {z} is out of range")))?;
+    if u64::from(x) >= size || u64::from(y) >= size {
+        return Err(Error::InvalidTileCoordinates(format!(
+            "{z}/{x}/{y} is outside of the tile grid"
+        )));
+    }
+    Ok((z, x, y))
+}
+
+/// Send TileJSON, compressed as negotiated and with validators for conditional requests
+fn json_response(
+    request: &Request<Body>,
+    response: Builder,
+    tilejson: &TileJSON,
+    modified: Option<SystemTime>,
+) -> Result<Response<Body>> {
+    let response = response.header(CONTENT_TYPE, "application/json");
+    // Going through a JSON value sorts the keys of `tilejson.other`, so the
+    // body and its ETag are the same for every request
+    let tilejson = serde_json::to_value(tilejson).unwrap();
+    let (response, data) = encode_response(
+        request.headers(),
+        response,
+        serde_json::to_vec(&tilejson).unwrap().into(),
+        Some(ContentEncoding::Identity),
+    )?;
+    Ok(conditional_response(request, response, data, modified))
+}
+
+/// Add the headers configured for tileset `id` to `response`
+fn tileset_headers(tilesets: &Registry, id: &str, mut response: Builder) -> Builder {
+    for (k, v) in tilesets.config(id).into_iter().flat_map(|c| &c.headers) {
+        response = response.header(k, v);
+    }
+    response
+}
+
+/// Add the headers configured for the tilesets of a composite to `response`. A header
+/// configured for several of them is taken from the first one listed.
+fn composite_headers(
+    tilesets: &Registry,
+    composite: &[(String, TileMeta)],
+    mut response: Builder,
+) -> Builder {
+    let mut headers = BTreeMap::new();
+    for (id, _) in composite {
+        for (k, v) in tilesets.config(id).into_iter().flat_map(|c| &c.headers) {
+            headers.entry(k.to_lowercase()).or_insert(v);
+        }
+    }
+    for (k, v) in headers {
+        response = response.header(k, v);
+    }
+    response
+}
+
+/// Look up the tilesets of a comma separated list of tileset ids. Either all of them are
+/// vector tilesets or all of them are raster tilesets.
+fn composite_tilesets(tilesets: &Registry, ids: &str) -> Result<Vec<(String, TileMeta)>> {
+    let composite = ids
+        .split(',')
+        .map(|id| match tilesets.get(id) {
+            Some(tile_meta) => Ok((id.to_string(), tile_meta)),
+            None => Err(Error::TilesetNotFound(id.to_string())),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for (i, (id, _)) in composite.iter().enumerate() {
+        let resolved = tilesets.resolve(id);
+        if composite[..i]
+            .iter()
+            .any(|(other, _)| tilesets.resolve(other) == resolved)
+        {
+            return Err(Error::InvalidComposite(format!(
+                "{ids} lists {resolved} more than once"
+            )));
+        }
+    }
+    let is_vector = |tile_meta: &TileMeta| tile_meta.tile_format == DataFormat::Pbf;
+    let vector = composite.iter().filter(|(_, t)| is_vector(t)).count();
+    let raster = composite
+        .iter()
+        .filter(|(_, t)| is_raster_format(t.tile_format))
+        .count();
+    if vector != composite.len() && raster != composite.len() {
+        return Err(Error::InvalidComposite(format!(
+            "{ids} are not all vector or all raster tilesets"
+        )));
+    }
+    Ok(composite)
+}
+
+/// Serve the tile at the given XYZ coordinates merged from several tilesets. Vector tiles
+/// are merged layer by layer, raster tiles are alpha-blended in the order of `ids` and
+/// encoded as `data_format`.
+fn composite_tile(
+    request: &Request<Body>,
+    context: &Context,
+    ids: &str,
+    z: u32,
+    x: u32,
+    y: u32,
+    data_format: &str,
 ) -> Result<Response<Body>> {
+    let composite = composite_tilesets(&context.tilesets, ids)?;
+    let requested = DataFormat::new(data_format);
+    let vector = composite[0].1.tile_format == DataFormat::Pbf;
+    if vector != (requested == DataFormat::Pbf) || !(vector || is_raster_format(requested)) {
+        return Err(Error::InvalidComposite(format!(
+            "{ids} tiles are not available as {data_format}"
+        )));
+    }
+    let tiles_in_bounds: Vec<&(String, TileMeta)> = composite
+        .iter()
+        .filter(|(_, tile_meta)| tile_meta.contains_tile(z, x, y))
+        .collect();
+    if tiles_in_bounds.is_empty() {
+        return Ok(match context.out_of_bounds {
+            OutOfBounds::NoContent => no_content(),
+            OutOfBounds::NotFound => not_found(),
+        });
+    }
+
+    let mut tiles = Vec::new();
+    for (id, tile_meta) in &tiles_in_bounds {
+        let read = || {
+            let id = context.tilesets.resolve(id);
+            context
+                .metrics
+                .time_read(id, "tile", || tile_meta.source.get_tile(z, x, y))
+        };
+        let data = context.tilesets.get_tile(id, z, x, y, read)?;
+        if let Some(data) = data {
+            tiles.push((data, tile_meta.tile_encoding));
+        }
+    }
+
+    if tiles.is_empty() {
+        return Ok(no_content());
+    }
+    let mut response = Response::builder();
+    for (k, v) in &context.headers {
+        response = response.header(k, v);
+    }
+    response = composite_headers(&context.tilesets, &composite, response);
+    response = response.header(CONTENT_TYPE, requested.content_type());
+    let modified = tiles_in_bounds
+        .iter()
+        .filter_map(|(_, t)| t.tiles_modified())
+        .max();
+    if !vector {
+        let tiles: Vec<(Vec<u8>, DataFormat)> = tiles
+            .into_iter()
+            .map(|(data, _)| (data.to_vec(), get_data_format(&data)))
+            .collect();
+        let data = encode_image(&blend_tiles(&tiles)?, requested, parse_quality(request)?)?;
+        return Ok(conditional_response(request, response, data, modified));
+    }
+
+    let tiles = tiles
+        .into_iter()
+        .map(|(data, tile_encoding)| {
+            let stored = stored_encoding(&data, tile_encoding);
+            transcode(&data, stored, ContentEncoding::Identity)
+        })
+        .collect::<Result<Vec<Vec<u8>>>>()?;
+    let (response, data) = encode_response(
+        request.headers(),
+        response,
+        mvt::merge_tiles(&tiles)?.into(),
+        Some(ContentEncoding::Identity),
+    )?;
+    Ok(conditional_response(request, response, data, modified))
+}
+
+/// Read the four children of a tile at the next zoom level, ordered top left, top right,
+/// bottom left and bottom right, or `None` unless all of them exist
+fn child_tiles(
+    tilesets: &Registry,
+    tile_path: &str,
+    tile_meta: &TileMeta,
+    z: u32,
+    x: u32,
+    y: u32,
+) -> Result<Option<[Vec<u8>; 4]>> {
+    if z >= 31 {
+        return Ok(None);
+    }
+    let mut children = Vec::with_capacity(4);
+    for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+        let (z, x, y) = (z + 1, x * 2 + dx, y * 2 + dy);
+        if !tile_meta.contains_tile(z, x, y) {
+            return Ok(None);
+        }
+        match tilesets.get_tile(tile_path, z, x, y, || tile_meta.source.get_tile(z, x, y))? {
+            Some(data) => children.push(data.to_vec()),
+            None => return Ok(None),
+        }
+    }
+    Ok(children.try_into().ok())
+}
+
+/// Parse the `quality` query parameter used when encoding JPEG and WebP tiles
+fn parse_quality(request: &Request<Body>) -> Result<Option<u8>> {
+    let value = request
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|parameter| parameter.strip_prefix("quality="));
+    match value {
+        None => Ok(None),
+        Some(value) => match value.parse::<u8>() {
+            Ok(quality) if (1..=100).contains(&quality) => Ok(Some(quality)),
+            _ => Err(Error::InvalidParameter(format!(
+                "quality={value}, expected a number from 1 to 100"
+            ))),
+        },
+    }
+}
+
+pub async fn get_service(request: Request<Body>, context: Arc<Context>) -> Result<Response<Body>> {
+    let mut entry = context.access_log.as_ref().map(|_| Entry::new(&request));
+    let tile = TILE_URL_RE.captures(request.uri().path()).map(|matches| {
+        // Composite tiles are counted under the resolved ids of all their tilesets
+        let id = matches["tile_path"]
+            .split(',')
+            .map(|id| context.tilesets.resolve(id))
+            .collect::<Vec<&str>>()
+            .join(",");
+        let coordinates = (
+            matches["z"].parse().ok(),
+            matches["x"].parse().ok(),
+            matches["y"].parse().ok(),
+        );
+        (id, DataFormat::new(&matches["format"]), coordinates)
+    });
+    let response = match route(request, context.clone()).await {
+        Ok(response) => response,
+        Err(err) => {
+            match err.status_code() {
+                s if s.is_server_error() => error!("{err}"),
+                _ => debug!("{err}"),
+            };
+            error_response(&err)
+        }
+    };
+    let status = response.status();
+    let bytes = response.body().size_hint().exact();
+    if let Some((id, format, coordinates)) = tile {
+        // Requests for tiles of registered tilesets are counted in the metrics
+        let registered = id.split(',').all(|id| context.tilesets.contains(id));
+        if !format.format().is_empty() && registered {
+            let size = bytes.filter(|_| status == StatusCode::OK);
+            context
+                .metrics
+                .record_request(&id, format.format(), status, size);
+        }
+        if let Some(entry) = &mut entry {
+            entry.tileset = Some(id);
+            if let (Some(z), Some(x), Some(y)) = coordinates {
+                entry.tile = Some((z, x, y));
+            }
+        }
+    }
+    if let (Some(access_log), Some(entry)) = (&context.access_log, &entry) {
+        access_log.log(entry, status, bytes);
+    }
+    Ok(response)
+}
+
+async fn route(request: Request<Body>, context: Arc<Context>) -> Result<Response<Body>> {
+    // Probes of orchestrators connect to an address rather than an allowed host name
+    match request.uri().path() {
+        "/health" => return Ok(health_response()),
+        "/ready" => return Ok(ready_response(&request, &context).await),
+        _ => (),
+    }
+
     let host = get_host(&request);
 
-    if !is_host_valid(&host, &allowed_hosts) {
+    if !is_host_valid(&host, &context.allowed_hosts) {
+        context.metrics.reject_host();
         return Ok(forbidden());
     };
 
+    if request.uri().path() == "/metrics" {
+        return Ok(metrics_response(&context.metrics, &context.tilesets));
+    }
+
+    if request.uri().path().starts_with("/admin/") {
+        return admin::get_admin_service(request, &context).await;
+    }
+
+    let tilesets = &context.tilesets;
+    let disable_preview = context.disable_preview;
     let host = host.unwrap();
     let uri = request.uri();
     let path = uri.path();
     let scheme = match uri.scheme_str() {
         Some(scheme) => format!("{scheme}://"),
+        None if request.extensions().get::<Tls>().is_some() => "https://".to_string(),
         None => "http://".to_string(),
     };
     let base_url = format!("{scheme}{host}/services");
@@ -125,64 +466,193 @@ pub async fn get_service(
     match TILE_URL_RE.captures(path) {
         Some(matches) => {
             let tile_path = matches.name("tile_path").unwrap().as_str();
-            let tile_meta = tilesets.get(tile_path).unwrap();
-            let z = matches.name("z").unwrap().as_str().parse::<u32>().unwrap();
-            let x = matches.name("x").unwrap().as_str().parse::<u32>().unwrap();
-            let y = matches.name("y").unwrap().as_str().parse::<u32>().unwrap();
-            let y: u32 = (1 << z) - 1 - y;
-            let data_format = matches.name("format").unwrap().as_str();
-            // For future use
-            let _query_string = match matches.name("query") {
-                Some(q) => q.as_str(),
-                None => "",
+            let (z, x, y) = parse_tile_coordinates(
+                matches.name("z").unwrap().as_str(),
+                matches.name("x").unwrap().as_str(),
+                matches.name("y").unwrap().as_str(),
+            )?;
+            let high_dpi = matches.name("scale").is_some();
+            if tile_path.contains(',') {
+                if high_dpi {
+                    return Err(Error::NotAcceptable(
+                        "@2x tiles are not available for composite tilesets".to_string(),
+                    ));
+                }
+                let data_format = matches.name("format").unwrap().as_str();
+                return composite_tile(&request, &context, tile_path, z, x, y, data_format);
+            }
+            let tile_path = tilesets.resolve(tile_path);
+            let tile_meta = tilesets
+                .get(tile_path)
+                .ok_or_else(|| Error::TilesetNotFound(tile_path.to_string()))?;
+            // Tiles past maxzoom are generated from their ancestor at maxzoom if enabled
+            let ancestor = match tile_meta.contains_tile(z, x, y) {
+                true => None,
+                false => match tile_meta.overzoom_ancestor(z, x, y, context.overzoom) {
+                    Some(ancestor) => Some(ancestor),
+                    None => {
+                        return Ok(match context.out_of_bounds {
+                            OutOfBounds::NoContent => no_content(),
+                            OutOfBounds::NotFound => not_found(),
+                        })
+                    }
+                },
             };
+            let data_format = matches.name("format").unwrap().as_str();
 
             let mut response = Response::builder();
-            for (k, v) in headers {
-                response = response.header(&k, &v);
+            for (k, v) in &context.headers {
+                response = response.header(k, v);
             }
+            response = tileset_headers(tilesets, tile_path, response);
 
-            return match data_format {
+            let metrics = &context.metrics;
+            let tile_data =
+                || metrics.time_read(tile_path, "tile", || tile_meta.source.get_tile(z, x, y));
+            if high_dpi && !is_raster_format(tile_meta.tile_format) {
+                return Err(Error::NotAcceptable(format!(
+                    "@2x tiles are only available for raster tilesets, not {tile_path}"
+                )));
+            }
+            let data: Bytes = match data_format {
+                "json" if ancestor.is_some() => return Ok(no_content()),
                 "json" => match tile_meta.grid_format {
-                    Some(grid_format) => match get_grid_data(
-                        &tile_meta.connection_pool.get().unwrap(),
-                        grid_format,
-                        z,
-                        x,
-                        y,
-                    ) {
-                        Ok(data) => {
+                    Some(grid_format) => match metrics.time_read(tile_path, "grid", || {
+                        tile_meta.source.get_grid(grid_format, z, x, y)
+                    })? {
+                        Some(data) => {
                             let data = serde_json::to_vec(&data).unwrap();
-                            Ok(response
-                                .header(CONTENT_TYPE, DataFormat::Json.content_type())
-                                .header(CONTENT_ENCODING, "gzip")
-                                .body(Body::from(encode(&data)))
-                                .unwrap())
+                            response =
+                                response.header(CONTENT_TYPE, DataFormat::Json.content_type());
+                            let (builder, data) = encode_response(
+                                request.headers(),
+                                response,
+                                data.into(),
+                                Some(ContentEncoding::Identity),
+                            )?;
+                            response = builder;
+                            data
+                        }
+                        None => {
+                            return Ok(missing_tile(
+                                &context.missing_tiles.policy(tile_path, &tile_meta),
+                            ))
                         }
-                        Err(_) => Ok(no_content()),
                     },
-                    None => Ok(not_found()),
+                    None => return Ok(not_found()),
                 },
-                "pbf" => match get_tile_data(&tile_meta.connection_pool.get().unwrap(), z, x, y) {
-                    Ok(data) => Ok(response
-                        .header(CONTENT_TYPE, DataFormat::Pbf.content_type())
-                        .header(CONTENT_ENCODING, "gzip")
-                        .body(Body::from(data))
-                        .unwrap()),
-                    Err(_) => Ok(no_content()),
+                "pbf" if tile_meta.tile_format != DataFormat::Pbf => {
+                    return Err(Error::NotAcceptable(format!(
+                        "{tile_path} is not a vector tileset"
+                    )))
+                }
+                "pbf" if ancestor.is_some() => {
+                    let (az, ax, ay) = ancestor.unwrap();
+                    let ancestor_data = || {
+                        metrics
+                            .time_read(tile_path, "tile", || tile_meta.source.get_tile(az, ax, ay))
+                    };
+                    let data = match tilesets.get_tile(tile_path, az, ax, ay, ancestor_data)? {
+                        Some(data) => {
+                            let stored = stored_encoding(&data, tile_meta.tile_encoding);
+                            let data = transcode(&data, stored, ContentEncoding::Identity)?;
+                            let dz = z - az;
+                            mvt::overzoom_tile(&data, dz, x - (ax << dz), y - (ay << dz))?
+                        }
+                        None => None,
+                    };
+                    response = response.header(CONTENT_TYPE, DataFormat::Pbf.content_type());
+                    match data {
+                        Some(data) => {
+                            let (builder, data) = encode_response(
+                                request.headers(),
+                                response,
+                                data.into(),
+                                Some(ContentEncoding::Identity),
+                            )?;
+                            response = builder;
+                            data
+                        }
+                        None => match context.missing_tiles.policy(tile_path, &tile_meta) {
+                            MissingTile::Blank => Bytes::new(),
+                            policy => return Ok(missing_tile(&policy)),
+                        },
+                    }
+                }
+                "pbf" => match tilesets.get_tile(tile_path, z, x, y, tile_data)? {
+                    Some(data) => {
+                        response = response.header(CONTENT_TYPE, DataFormat::Pbf.content_type());
+                        let stored = stored_encoding(&data, tile_meta.tile_encoding);
+                        let (builder, data) =
+                            encode_response(request.headers(), response, data, stored)?;
+                        response = builder;
+                        data
+                    }
+                    None => match context.missing_tiles.policy(tile_path, &tile_meta) {
+                        MissingTile::Blank => {
+                            response =
+                                response.header(CONTENT_TYPE, DataFormat::Pbf.content_type());
+                            Bytes::new()
+                        }
+                        policy => return Ok(missing_tile(&policy)),
+                    },
                 },
                 _ => {
-                    let data =
-                        match get_tile_data(&tile_meta.connection_pool.get().unwrap(), z, x, y) {
-                            Ok(data) => data,
-                            Err(_) => get_blank_image(),
-                        };
-                    Ok(response
-                        .header(CONTENT_TYPE, DataFormat::new(data_format).content_type())
-                        .body(Body::from(data))
-                        .unwrap())
+                    let requested = DataFormat::new(data_format);
+                    if !is_raster_format(tile_meta.tile_format) || !is_raster_format(requested) {
+                        return Err(Error::NotAcceptable(format!(
+                            "{tile_path} tiles can't be converted to {data_format}"
+                        )));
+                    }
+                    let quality = parse_quality(&request)?;
+                    response = response.header(CONTENT_TYPE, requested.content_type());
+                    let scale = if high_dpi { 2 } else { 1 };
+                    let (tz, tx, ty) = ancestor.unwrap_or((z, x, y));
+                    let stored_data = || {
+                        metrics
+                            .time_read(tile_path, "tile", || tile_meta.source.get_tile(tz, tx, ty))
+                    };
+                    // `None` if a blank tile is served in place of a missing tile
+                    let data = match tilesets.get_tile(tile_path, tz, tx, ty, stored_data)? {
+                        Some(data) => Some(data),
+                        None => match context.missing_tiles.policy(tile_path, &tile_meta) {
+                            MissingTile::Image(data) => Some(data),
+                            MissingTile::Blank => None,
+                            policy => return Ok(missing_tile(&policy)),
+                        },
+                    };
+                    match data {
+                        None => {
+                            let size = tile_meta.tile_size * scale;
+                            encode_image(&RgbaImage::new(size, size), requested, quality)?.into()
+                        }
+                        Some(data) if ancestor.is_some() => {
+                            let dz = z - tz;
+                            let image =
+                                overzoom_image(&data, dz, x - (tx << dz), y - (ty << dz), scale)?;
+                            encode_image(&image, requested, quality)?.into()
+                        }
+                        Some(data) if high_dpi => {
+                            let image = match child_tiles(tilesets, tile_path, &tile_meta, z, x, y)?
+                            {
+                                Some(children) => stitch_tiles(&children)?,
+                                None => upsample_tile(&data)?,
+                            };
+                            encode_image(&image, requested, quality)?.into()
+                        }
+                        Some(data) if get_data_format(&data) == requested && quality.is_none() => {
+                            data
+                        }
+                        Some(data) => convert_image(&data, requested, quality)?.into(),
+                    }
                 }
             };
+            return Ok(conditional_response(
+                &request,
+                response,
+                data,
+                tile_meta.tiles_modified(),
+            ));
         }
         None => {
             if path.starts_with("/services") {
@@ -190,7 +660,16 @@ pub async fn get_service(
                 if segments.len() == 1 {
                     // Root url (/services): show all services
                     let mut tiles_summary = Vec::new();
-                    for (tile_name, tile_meta) in tilesets {
+                    for (tile_name, tile_meta) in tilesets.snapshot() {
+                        let (tile_name, hidden) = match tilesets.config(&tile_name) {
+                            Some(config) => {
+                                (config.alias.clone().unwrap_or(tile_name), config.hidden)
+                            }
+                            None => (tile_name, false),
+                        };
+                        if hidden {
+                            continue;
+                        }
                         tiles_summary.push(TileSummaryJSON {
                             image_type: tile_meta.tile_format,
                             url: format!("{base_url}/{tile_name}"),
@@ -205,37 +684,63 @@ pub async fn get_service(
 
                 // Tileset details (/services/<tileset-path>)
                 let tile_name = segments[1..].join("/");
+                let query_string = match request.uri().query() {
+                    Some(q) => format!("?{q}"),
+                    None => String::new(),
+                };
+                if tile_name.contains(',') {
+                    // Composite of several tilesets (/services/<tileset-path>,<tileset-path>)
+                    let composite = composite_tilesets(tilesets, &tile_name)?;
+                    let response = composite_headers(tilesets, &composite, Response::builder());
+                    let composite: Vec<TileMeta> = composite
+                        .into_iter()
+                        .map(|(_, tile_meta)| tile_meta)
+                        .collect();
+                    let mut tilejson = merge_tilejson(&composite);
+                    // Raster composites are served in the format of the first tileset
+                    let tile_format = composite[0].tile_format;
+                    tilejson.tiles[0] = format!(
+                        "{base_url}/{tile_name}/tiles/{{z}}/{{x}}/{{y}}.{format}{query_string}",
+                        format = tile_format.format()
+                    );
+                    tilejson.other.insert("id".to_string(), json!(tile_name));
+                    tilejson
+                        .other
+                        .insert("format".to_string(), json!(tile_format));
+                    let modified = composite.iter().filter_map(|t| t.modified).max();
+                    return json_response(&request, response, &tilejson, modified);
+                }
                 let tile_meta = match tilesets.get(&tile_name) {
-                    Some(tile_meta) => tile_meta.clone(),
+                    Some(tile_meta) => tile_meta,
                     None => {
                         if segments[segments.len() - 1] == "map" {
                             // Tileset map preview (/services/<tileset-path>/map)
                             let tile_name = segments[1..segments.len() - 1].join("/");
-                            return match tilesets.get(&tile_name) {
-                                Some(_) => {
-                                    if disable_preview {
-                                        return Ok(not_found());
-                                    }
-                                    Ok(tile_map())
-                                }
-                                None => {
-                                    Ok(bad_request(format!("Tileset does not exist: {tile_name}")))
-                                }
-                            };
+                            if !tilesets.contains(&tile_name) {
+                                return Err(Error::TilesetNotFound(tile_name));
+                            }
+                            if disable_preview {
+                                return Ok(not_found());
+                            }
+                            return Ok(tile_map());
                         }
-                        return Ok(bad_request(format!("Tileset does not exist: {tile_name}")));
+                        return Err(Error::TilesetNotFound(tile_name));
                     }
                 };
-                let query_string = match request.uri().query() {
-                    Some(q) => format!("?{q}"),
-                    None => String::new(),
-                };
-
                 let mut tilejson = tile_meta.tilejson.clone();
                 tilejson.tiles[0] = format!(
                     "{base_url}/{tile_name}/tiles/{{z}}/{{x}}/{{y}}.{format}{query_string}",
                     format = tile_meta.tile_format.format()
                 );
+                if is_raster_format(tile_meta.tile_format) {
+                    tilejson.other.insert(
+                        "tiles@2x".to_string(),
+                        json!([format!(
+                            "{base_url}/{tile_name}/tiles/{{z}}/{{x}}/{{y}}@2x.{format}{query_string}",
+                            format = tile_meta.tile_format.format()
+                        )]),
+                    );
+                }
                 tilejson.other.insert("id".to_string(), json!(tile_meta.id));
                 tilejson
                     .other
@@ -249,8 +754,13 @@ pub async fn get_service(
                 tilejson
                     .other
                     .insert("type".to_string(), json!(tile_meta.layer_type));
-                if let Some(json_data) = tile_meta.json {
-                    for (k, v) in json_data.as_object().unwrap() {
+                if !tile_meta.warnings.is_empty() {
+                    tilejson
+                        .other
+                        .insert("warnings".to_string(), json!(tile_meta.warnings));
+                }
+                if let Some(json_data) = tile_meta.json.as_ref().and_then(|j| j.as_object()) {
+                    for (k, v) in json_data {
                         tilejson.other.insert(k.to_string(), v.clone());
                     }
                 }
@@ -261,10 +771,8 @@ pub async fn get_service(
                     );
                 }
 
-                return Ok(Response::builder()
-                    .header(CONTENT_TYPE, "application/json")
-                    .body(Body::from(serde_json::to_string(&tilejson).unwrap()))
-                    .unwrap()); // TODO handle error
+                let response = tileset_headers(tilesets, &tile_name, Response::builder());
+                return json_response(&request, response, &tilejson, tile_meta.modified);
             }
         }
     };
@@ -272,14 +780,26 @@ pub async fn get_service(
 }
 
 #[cfg(test)]
+#[allow(clippy::single_component_path_imports)]
 mod tests {
     use super::*;
-    use crate::tiles::discover_tilesets;
-    use crate::utils::decode;
+    use crate::config::TilesetConfig;
+    use crate::raster::decode_image;
+    use crate::tile_cache::TileCache;
+    use crate::tiles::{discover_tilesets, get_tile_details, TileSource};
+    use crate::utils::{decode, decompress};
+    use flate2::read::GzDecoder;
     use hyper::body;
+    use hyper::header::{
+        HeaderName, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING, ETAG, IF_MODIFIED_SINCE,
+        IF_NONE_MATCH, LAST_MODIFIED, VARY,
+    };
+    use r2d2_sqlite::SqliteConnectionManager;
     use serde_json;
     use serde_json::Value as JSONValue;
-    use std::path::PathBuf;
+    use std::collections::HashMap;
+    use std::io::Read;
+    use std::time::Duration;
 
     async fn setup(
         host: &str,
@@ -294,15 +814,15 @@ mod tests {
             .unwrap();
 
         let tilesets = discover_tilesets(String::new(), &PathBuf::from("./tiles"));
-        get_service(
-            request,
-            tilesets,
-            allowed_hosts.unwrap_or(vec!["*".to_string()]),
-            headers.unwrap_or(vec![]),
+        let context = Context {
+            tilesets: Registry::new(tilesets),
+            directory: PathBuf::from("./tiles"),
+            allowed_hosts: allowed_hosts.unwrap_or(vec!["*".to_string()]),
+            headers: headers.unwrap_or(vec![]),
             disable_preview,
-        )
-        .await
-        .unwrap()
+            ..Default::default()
+        };
+        get_service(request, Arc::new(context)).await.unwrap()
     }
 
     #[tokio::test]
@@ -418,32 +938,164 @@ mod tests {
     #[tokio::test]
     async fn get_non_existing_tile() {
         // Geography Class PNG has no tiles beyond zoom level 1 and should return a blank image
-        let response = setup(
-            "http://localhost",
+        // when the tileset doesn't declare its zoom range
+        let mut tilesets = discover_tilesets(String::new(), &PathBuf::from("./tiles"));
+        tilesets
+            .get_mut("geography-class-png")
+            .unwrap()
+            .tilejson
+            .maxzoom = None;
+        let response = setup_with_registry(
+            Registry::new(tilesets),
             "/services/geography-class-png/tiles/2/0/0.png",
-            None,
-            None,
-            false,
         )
         .await;
         assert_eq!(response.status(), 200);
-        assert_eq!(
-            body::to_bytes(response.into_body()).await.unwrap(),
-            get_blank_image()
-        );
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        let image = decode_image(&body, DataFormat::Png).unwrap();
+        assert_eq!(image.dimensions(), (256, 256));
+        assert!(image.pixels().all(|pixel| pixel[3] == 0));
+    }
+
+    #[tokio::test]
+    async fn get_missing_tiles() {
+        let context = |missing_tiles: MissingTiles| {
+            let mut tilesets = discover_tilesets(String::new(), &PathBuf::from("./tiles"));
+            for id in ["geography-class-jpg", "geography-class-png"] {
+                tilesets.get_mut(id).unwrap().tilejson.maxzoom = None;
+            }
+            Context {
+                tilesets: Registry::new(tilesets),
+                allowed_hosts: vec!["*".to_string()],
+                missing_tiles,
+                ..Default::default()
+            }
+        };
+        let path = "/services/geography-class-jpg/tiles/2/0/0.jpg";
+        let response = setup_with_context(context(MissingTiles::default()), path).await;
+        assert_eq!(response.status(), 200);
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(get_data_format(&body), DataFormat::Jpg);
+
+        let image = std::fs::read("./tiles/world.png").unwrap();
+        let missing_tiles = MissingTiles {
+            default: Some(MissingTile::NotFound),
+            tilesets: HashMap::from([(
+                "geography-class-jpg".to_string(),
+                MissingTile::Image(image.clone().into()),
+            )]),
+        };
+        let response = setup_with_context(context(missing_tiles.clone()), path).await;
+        assert_eq!(response.status(), 200);
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(get_data_format(&body), DataFormat::Jpg);
+        let path = "/services/geography-class-jpg/tiles/2/0/0.png";
+        let response = setup_with_context(context(missing_tiles.clone()), path).await;
+        assert_eq!(body::to_bytes(response.into_body()).await.unwrap(), image);
+
+        // Composites of missing tiles have no content, like vector composites
+        let path = "/services/geography-class-jpg,geography-class-png/tiles/2/0/0.png";
+        let response = setup_with_context(context(MissingTiles::default()), path).await;
+        assert_eq!(response.status(), 204);
+
+        let path = "/services/directory/world_cities/tiles/2/0/2.pbf";
+        let response = setup_with_context(context(missing_tiles), path).await;
+        assert_eq!(response.status(), 404);
+        let missing_tiles = MissingTiles {
+            default: Some(MissingTile::Blank),
+            ..Default::default()
+        };
+        let response = setup_with_context(context(missing_tiles), path).await;
+        assert_eq!(response.status(), 200);
+        assert!(body::to_bytes(response.into_body())
+            .await
+            .unwrap()
+            .is_empty());
+        let response = setup_with_context(context(MissingTiles::default()), path).await;
+        assert_eq!(response.status(), 204);
+    }
+
+    #[tokio::test]
+    async fn get_configured_tilesets() {
+        let config = HashMap::from([
+            (
+                "world_cities".to_string(),
+                TilesetConfig {
+                    alias: Some("cities".to_string()),
+                    headers: [("x-tileset".to_string(), "cities".to_string())].into(),
+                    ..Default::default()
+                },
+            ),
+            (
+                "geography-class-png".to_string(),
+                TilesetConfig {
+                    hidden: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                "directory/world_cities".to_string(),
+                TilesetConfig {
+                    headers: [
+                        ("X-Tileset".to_string(), "directory".to_string()),
+                        ("x-directory".to_string(), "yes".to_string()),
+                    ]
+                    .into(),
+                    ..Default::default()
+                },
+            ),
+        ]);
+        let context = || Context {
+            tilesets: Registry::new(discover_tilesets(String::new(), &PathBuf::from("./tiles")))
+                .with_config(config.clone()),
+            allowed_hosts: vec!["*".to_string()],
+            ..Default::default()
+        };
+        for path in ["/services/cities/tiles/0/0/0.pbf", "/services/cities"] {
+            let response = setup_with_context(context(), path).await;
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.headers().get("x-tileset").unwrap(), "cities");
+        }
+        let response = setup_with_context(context(), "/services/world_cities").await;
+        assert_eq!(response.status(), 200);
+        // Composites have the headers of all their tilesets, the first one listed wins
+        for path in [
+            "/services/cities,directory/world_cities/tiles/0/0/0.pbf",
+            "/services/cities,directory/world_cities",
+        ] {
+            let response = setup_with_context(context(), path).await;
+            assert_eq!(response.status(), 200);
+            let headers = response.headers();
+            assert_eq!(headers.get_all("x-tileset").iter().count(), 1);
+            assert_eq!(headers.get("x-tileset").unwrap(), "cities");
+            assert_eq!(headers.get("x-directory").unwrap(), "yes");
+        }
+
+        let response = setup_with_context(context(), "/services").await;
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        let services: JSONValue = serde_json::from_slice(&body).unwrap();
+        let urls: Vec<&str> = services
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| s["url"].as_str().unwrap())
+            .collect();
+        assert!(urls.contains(&"http://localhost/services/cities"));
+        assert!(!urls.contains(&"http://localhost/services/world_cities"));
+        assert!(!urls.contains(&"http://localhost/services/geography-class-png"));
+        let path = "/services/geography-class-png/tiles/0/0/0.png";
+        assert_eq!(setup_with_context(context(), path).await.status(), 200);
     }
 
     #[tokio::test]
     async fn get_existing_utfgrid_data() {
-        let response = setup(
-            "http://localhost",
+        let response = setup_with_headers(
             "/services/geography-class-png/tiles/0/0/0.json",
-            None,
-            None,
-            false,
+            &[(ACCEPT_ENCODING, "gzip")],
         )
         .await;
         assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get(CONTENT_ENCODING).unwrap(), "gzip");
         let data: JSONValue = serde_json::from_str(
             &decode(
                 body::to_bytes(response.into_body()).await.unwrap().to_vec(),
@@ -455,6 +1107,20 @@ mod tests {
         assert_ne!(data.get("data"), None);
         assert_ne!(data.get("grid"), None);
         assert_ne!(data.get("keys"), None);
+
+        // Uncompressed without Accept-Encoding
+        let response = setup(
+            "http://localhost",
+            "/services/geography-class-png/tiles/0/0/0.json",
+            None,
+            None,
+            false,
+        )
+        .await;
+        assert!(response.headers().get(CONTENT_ENCODING).is_none());
+        let data: JSONValue =
+            serde_json::from_slice(&body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_ne!(data.get("grid"), None);
     }
 
     #[tokio::test]
@@ -483,4 +1149,722 @@ mod tests {
         .await;
         assert_eq!(response.status(), 404);
     }
+
+    async fn setup_with_registry(tilesets: Registry, path: &str) -> Response<Body> {
+        let context = Context {
+            tilesets,
+            allowed_hosts: vec!["*".to_string()],
+            ..Default::default()
+        };
+        setup_with_context(context, path).await
+    }
+
+    async fn setup_with_context(context: Context, path: &str) -> Response<Body> {
+        let request = Request::builder()
+            .uri(format!("http://localhost{path}"))
+            .body(Body::from(""))
+            .unwrap();
+        get_service(request, Arc::new(context)).await.unwrap()
+    }
+
+    async fn setup_with_headers(path: &str, headers: &[(HeaderName, &str)]) -> Response<Body> {
+        let mut request = Request::builder().uri(format!("http://localhost{path}"));
+        for (k, v) in headers {
+            request = request.header(k, *v);
+        }
+        let context = Context {
+            tilesets: Registry::new(discover_tilesets(String::new(), &PathBuf::from("./tiles"))),
+            allowed_hosts: vec!["*".to_string()],
+            ..Default::default()
+        };
+        get_service(request.body(Body::from("")).unwrap(), Arc::new(context))
+            .await
+            .unwrap()
+    }
+
+    async fn problem_detail(response: Response<Body>) -> JSONValue {
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "application/problem+json"
+        );
+        serde_json::from_slice(&body::to_bytes(response.into_body()).await.unwrap()).unwrap()
+    }
+
+    fn tile_meta_with_pool(pool: r2d2::Pool<SqliteConnectionManager>) -> Registry {
+        let mut tile_meta = get_tile_details(
+            &PathBuf::from("./tiles/geography-class-png.mbtiles"),
+            "geography-class-png",
+        )
+        .unwrap();
+        tile_meta.source = TileSource::MBTiles(pool);
+        let mut tilesets = HashMap::new();
+        tilesets.insert("geography-class-png".to_string(), tile_meta);
+        Registry::new(tilesets)
+    }
+
+    #[tokio::test]
+    async fn unknown_tileset_in_tile_url() {
+        let response = setup(
+            "http://localhost",
+            "/services/does-not-exist/tiles/0/0/0.png",
+            None,
+            None,
+            false,
+        )
+        .await;
+        assert_eq!(response.status(), 404);
+        let data = problem_detail(response).await;
+        assert_eq!(data["status"], 404);
+        assert_eq!(data["detail"], "Tileset does not exist: does-not-exist");
+    }
+
+    #[tokio::test]
+    async fn unknown_tileset_details() {
+        let response = setup(
+            "http://localhost",
+            "/services/does-not-exist",
+            None,
+            None,
+            false,
+        )
+        .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn out_of_range_tile_coordinates() {
+        for path in [
+            "/services/geography-class-png/tiles/1/2/0.png",
+            "/services/geography-class-png/tiles/1/0/2.png",
+            "/services/geography-class-png/tiles/32/0/0.png",
+            "/services/geography-class-png/tiles/0/99999999999/0.png",
+        ] {
+            let response = setup("http://localhost", path, None, None, false).await;
+            assert_eq!(response.status(), 400, "{path}");
+            let data = problem_detail(response).await;
+            assert_eq!(data["status"], 400);
+        }
+    }
+
+    #[tokio::test]
+    async fn exhausted_connection_pool() {
+        let manager = SqliteConnectionManager::file("./tiles/geography-class-png.mbtiles");
+        let pool = r2d2::Pool::builder()
+            .max_size(1)
+            .connection_timeout(Duration::from_millis(10))
+            .build(manager)
+            .unwrap();
+        let _connection = pool.get().unwrap();
+        let response = setup_with_registry(
+            tile_meta_with_pool(pool),
+            "/services/geography-class-png/tiles/0/0/0.png",
+        )
+        .await;
+        assert_eq!(response.status(), 503);
+        assert_eq!(problem_detail(response).await["status"], 503);
+    }
+
+    #[tokio::test]
+    async fn database_error() {
+        // invalid.mbtiles has no tiles table
+        let manager = SqliteConnectionManager::file("./tiles/invalid.mbtiles");
+        let pool = r2d2::Pool::new(manager).unwrap();
+        let response = setup_with_registry(
+            tile_meta_with_pool(pool),
+            "/services/geography-class-png/tiles/0/0/0.png",
+        )
+        .await;
+        assert_eq!(response.status(), 500);
+        assert_eq!(problem_detail(response).await["status"], 500);
+    }
+
+    #[tokio::test]
+    async fn tile_outside_of_zoom_range() {
+        let response = setup(
+            "http://localhost",
+            "/services/geography-class-png/tiles/2/0/0.png",
+            None,
+            None,
+            false,
+        )
+        .await;
+        assert_eq!(response.status(), 204);
+    }
+
+    #[tokio::test]
+    async fn tile_outside_of_bounds() {
+        let context = Context {
+            tilesets: Registry::new(discover_tilesets(String::new(), &PathBuf::from("./tiles"))),
+            allowed_hosts: vec!["*".to_string()],
+            out_of_bounds: OutOfBounds::NotFound,
+            ..Default::default()
+        };
+        let response =
+            setup_with_context(context, "/services/open-streets-dc/tiles/12/0/0.png").await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn conditional_requests() {
+        for path in [
+            "/services/geography-class-png/tiles/0/0/0.png",
+            "/services/geography-class-png/tiles/0/0/0.json",
+            "/services/world_cities/tiles/0/0/0.pbf",
+            "/services/geography-class-png",
+        ] {
+            let response = setup_with_headers(path, &[]).await;
+            assert_eq!(response.status(), 200, "{path}");
+            let etag = response.headers().get(ETAG).unwrap().to_str().unwrap();
+            let last_modified = response.headers().get(LAST_MODIFIED).unwrap();
+            let last_modified = last_modified.to_str().unwrap().to_string();
+
+            let response = setup_with_headers(path, &[(IF_NONE_MATCH, etag)]).await;
+            assert_eq!(response.status(), 304, "{path}");
+            assert!(body::to_bytes(response.into_body())
+                .await
+                .unwrap()
+                .is_empty());
+
+            let response = setup_with_headers(path, &[(IF_NONE_MATCH, "\"stale\"")]).await;
+            assert_eq!(response.status(), 200, "{path}");
+
+            let response = setup_with_headers(path, &[(IF_MODIFIED_SINCE, &last_modified)]).await;
+            assert_eq!(response.status(), 304, "{path}");
+
+            let response = setup_with_headers(
+                path,
+                &[(IF_MODIFIED_SINCE, "Thu, 01 Jan 1970 00:00:00 GMT")],
+            )
+            .await;
+            assert_eq!(response.status(), 200, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn directory_tiles_have_no_last_modified() {
+        let path = "/services/directory/world_cities/tiles/0/0/0.pbf";
+        let response = setup_with_headers(path, &[]).await;
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().get(ETAG).is_some());
+        assert!(response.headers().get(LAST_MODIFIED).is_none());
+        let response = setup_with_headers(
+            path,
+            &[(IF_MODIFIED_SINCE, "Fri, 01 Jan 2100 00:00:00 GMT")],
+        )
+        .await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn etags_differ_between_tiles() {
+        let first = setup_with_headers("/services/geography-class-png/tiles/1/0/0.png", &[]).await;
+        let second = setup_with_headers("/services/geography-class-png/tiles/1/1/0.png", &[]).await;
+        assert_ne!(
+            first.headers().get(ETAG).unwrap(),
+            second.headers().get(ETAG).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn get_metrics() {
+        let context = Arc::new(Context {
+            tilesets: Registry::new(discover_tilesets(String::new(), &PathBuf::from("./tiles"))),
+            allowed_hosts: vec!["localhost".to_string()],
+            ..Default::default()
+        });
+        for (host, path) in [
+            ("localhost", "/services/geography-class-png/tiles/0/0/0.png"),
+            ("localhost", "/services/geography-class-png/tiles/0/0/0.png"),
+            (
+                "localhost",
+                "/services/geography-class-png/tiles/0/0/0.json",
+            ),
+            ("localhost", "/services/missing/tiles/0/0/0.png"),
+            (
+                "localhost",
+                "/services/geography-class-png,geography-class-jpg/tiles/0/0/0.png",
+            ),
+            (
+                "example.com",
+                "/services/geography-class-png/tiles/0/0/0.png",
+            ),
+        ] {
+            let request = Request::get(format!("http://{host}{path}"))
+                .body(Body::empty())
+                .unwrap();
+            get_service(request, context.clone()).await.unwrap();
+        }
+        let request = Request::get("http://localhost/metrics")
+            .body(Body::empty())
+            .unwrap();
+        let response = get_service(request, context.clone()).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(response.headers()[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain; version=0.0.4"));
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        let metrics = String::from_utf8(body.to_vec()).unwrap();
+        let lines: Vec<&str> = metrics.lines().collect();
+        for line in [
+            r#"mbtileserver_tile_requests_total{tileset="geography-class-png",format="png",status="200"} 2"#,
+            r#"mbtileserver_tile_requests_total{tileset="geography-class-png",format="png",status="403"} 1"#,
+            r#"mbtileserver_tile_requests_total{tileset="geography-class-png",format="json",status="200"} 1"#,
+            r#"mbtileserver_tile_size_bytes_count{tileset="geography-class-png",format="png"} 2"#,
+            r#"mbtileserver_tile_read_duration_seconds_count{tileset="geography-class-png",kind="tile"} 3"#,
+            r#"mbtileserver_tile_read_duration_seconds_count{tileset="geography-class-png",kind="grid"} 1"#,
+            // Composite tiles are counted on their own, and each of their reads
+            r#"mbtileserver_tile_requests_total{tileset="geography-class-png,geography-class-jpg",format="png",status="200"} 1"#,
+            r#"mbtileserver_tile_size_bytes_count{tileset="geography-class-png,geography-class-jpg",format="png"} 1"#,
+            r#"mbtileserver_tile_read_duration_seconds_count{tileset="geography-class-jpg",kind="tile"} 1"#,
+            "mbtileserver_rejected_hosts_total 1",
+        ] {
+            assert!(lines.contains(&line), "{line} not in {metrics}");
+        }
+        // Unknown tilesets are not counted
+        assert!(!metrics.contains(r#"tileset="missing""#));
+    }
+
+    #[tokio::test]
+    async fn log_requests() {
+        use crate::access_log::AccessLog;
+        use crate::config::AccessLogFormat;
+
+        let dir = tempdir::TempDir::new("access-log").unwrap();
+        let path = dir.path().join("access.log");
+        let access_log = AccessLog::open(path.to_str().unwrap(), AccessLogFormat::Json, 1);
+        let context = Context {
+            tilesets: Registry::new(discover_tilesets(String::new(), &PathBuf::from("./tiles"))),
+            allowed_hosts: vec!["*".to_string()],
+            access_log: Some(access_log.unwrap()),
+            ..Default::default()
+        };
+        let context = Arc::new(context);
+        for path in ["/services/world_cities/tiles/1/0/1.pbf", "/services"] {
+            let request = Request::get(format!("http://localhost{path}"))
+                .header("user-agent", "test")
+                .body(Body::empty())
+                .unwrap();
+            get_service(request, context.clone()).await.unwrap();
+        }
+        let log = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<JSONValue> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["method"], "GET");
+        assert_eq!(lines[0]["tileset"], "world_cities");
+        assert_eq!((&lines[0]["z"], &lines[0]["x"]), (&1.into(), &0.into()));
+        assert_eq!(lines[0]["user_agent"], "test");
+        assert_eq!(lines[0]["host"], "localhost");
+        assert!(lines[0]["bytes"].as_u64().unwrap() > 0);
+        assert_eq!(lines[1]["path"], "/services");
+        assert_eq!(lines[1]["tileset"], JSONValue::Null);
+        assert_eq!(lines[1]["status"], 200);
+    }
+
+    #[tokio::test]
+    async fn health_checks() {
+        let tilesets = Registry::new(discover_tilesets(String::new(), &PathBuf::from("./tiles")));
+        // Probes are answered regardless of the host
+        let context = Context {
+            tilesets: tilesets.clone(),
+            allowed_hosts: vec!["example.com".to_string()],
+            ..Default::default()
+        };
+        let response = setup_with_context(context, "/health").await;
+        assert_eq!(response.status(), 200);
+
+        let response = setup_with_registry(tilesets.clone(), "/ready").await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["cache-control"], "no-store");
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        let json: JSONValue = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json, json!({"ready": true}));
+
+        // The status of each tileset is only listed for the admin
+        let config = HashMap::from([("missing".to_string(), TilesetConfig::default())]);
+        let context = || Context {
+            tilesets: tilesets.clone().with_config(config.clone()),
+            admin_token: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let response = setup_with_context(context(), "/ready").await;
+        assert_eq!(response.status(), 503);
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        let json: JSONValue = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json, json!({"ready": false}));
+
+        let request = Request::get("http://localhost/ready")
+            .header(AUTHORIZATION, "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        let response = get_service(request, Arc::new(context())).await.unwrap();
+        assert_eq!(response.status(), 503);
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        let json: JSONValue = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["ready"], false);
+        assert_eq!(json["tilesets"]["missing"]["status"], "missing");
+        assert_eq!(json["tilesets"]["world_cities"]["status"], "ok");
+    }
+
+    #[tokio::test]
+    async fn tiles_served_from_cache() {
+        let tilesets = Registry::new(discover_tilesets(String::new(), &PathBuf::from("./tiles")))
+            .with_cache(TileCache::new(1_000_000));
+        let path = "/services/geography-class-png/tiles/0/0/0.png";
+        let first = setup_with_registry(tilesets.clone(), path).await;
+        let second = setup_with_registry(tilesets.clone(), path).await;
+        assert_eq!(
+            body::to_bytes(first.into_body()).await.unwrap(),
+            body::to_bytes(second.into_body()).await.unwrap()
+        );
+        let stats = tilesets.cache().unwrap().stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn get_pmtiles_tiles() {
+        let response = setup(
+            "http://localhost",
+            "/services/pmtiles/world_cities/tiles/0/0/0.pbf",
+            None,
+            None,
+            false,
+        )
+        .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get(CONTENT_ENCODING).unwrap(), "gzip");
+
+        let response = setup(
+            "http://localhost",
+            "/services/pmtiles/geography-class-png/tiles/1/0/0.png",
+            None,
+            None,
+            false,
+        )
+        .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            get_data_format(&body::to_bytes(response.into_body()).await.unwrap()),
+            DataFormat::Png
+        );
+
+        let response = setup(
+            "http://localhost",
+            "/services/pmtiles/world_cities",
+            None,
+            None,
+            false,
+        )
+        .await;
+        assert_eq!(response.status(), 200);
+        let tilejson: JSONValue =
+            serde_json::from_slice(&body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_eq!(
+            tilejson["tiles"][0],
+            "http://localhost/services/pmtiles/world_cities/tiles/{z}/{x}/{y}.pbf"
+        );
+        assert!(tilejson["vector_layers"].is_array());
+    }
+
+    #[tokio::test]
+    async fn get_directory_tiles() {
+        let response = setup(
+            "http://localhost",
+            "/services/directory/world_cities/tiles/1/0/0.pbf",
+            None,
+            None,
+            false,
+        )
+        .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get(CONTENT_ENCODING).unwrap(), "gzip");
+
+        // Beyond the maxzoom in metadata.json
+        let response = setup(
+            "http://localhost",
+            "/services/directory/world_cities/tiles/3/0/0.pbf",
+            None,
+            None,
+            false,
+        )
+        .await;
+        assert_eq!(response.status(), 204);
+    }
+
+    #[tokio::test]
+    async fn get_overzoomed_tiles() {
+        let context = || Context {
+            tilesets: Registry::new(discover_tilesets(String::new(), &PathBuf::from("./tiles"))),
+            allowed_hosts: vec!["*".to_string()],
+            overzoom: 2,
+            ..Default::default()
+        };
+        // The directory tileset has a maxzoom of 2
+        let path = "/services/directory/world_cities/tiles/3/2/3.pbf";
+        let response = setup_with_context(context(), path).await;
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().get(CONTENT_ENCODING).is_none());
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body[0], 0x1a);
+        // Only a limited number of zoom levels past maxzoom
+        let path = "/services/directory/world_cities/tiles/5/0/0.pbf";
+        assert_eq!(setup_with_context(context(), path).await.status(), 204);
+
+        // The raster tileset has a maxzoom of 1
+        let path = "/services/geography-class-png/tiles/3/1/1.png";
+        let response = setup_with_context(context(), path).await;
+        assert_eq!(response.status(), 200);
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        let image = decode_image(&body, DataFormat::Png).unwrap();
+        assert_eq!(image.dimensions(), (256, 256));
+        let path = "/services/geography-class-png/tiles/3/1/1@2x.jpg";
+        let response = setup_with_context(context(), path).await;
+        assert_eq!(response.status(), 200);
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        let image = decode_image(&body, DataFormat::Jpg).unwrap();
+        assert_eq!(image.dimensions(), (512, 512));
+
+        // Disabled by default
+        let path = "/services/geography-class-png/tiles/3/1/1.png";
+        let response = setup("http://localhost", path, None, None, false).await;
+        assert_eq!(response.status(), 204);
+    }
+
+    #[tokio::test]
+    async fn serve_uncompressed_vector_tiles() {
+        let dir = tempdir::TempDir::new("tiles").unwrap();
+        let path = dir.path().join("world_cities.mbtiles");
+        std::fs::copy("./tiles/world_cities.mbtiles", &path).unwrap();
+        let connection = rusqlite::Connection::open(&path).unwrap();
+        let tiles: Vec<(i64, Vec<u8>)> = connection
+            .prepare("SELECT rowid, tile_data FROM tiles")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        for (rowid, data) in tiles {
+            let data = decompress(&data, DataFormat::Gzip).unwrap();
+            connection
+                .execute(
+                    "UPDATE tiles SET tile_data = ?1 WHERE rowid = ?2",
+                    rusqlite::params![data, rowid],
+                )
+                .unwrap();
+        }
+        let context = || Context {
+            tilesets: Registry::new(discover_tilesets(String::new(), &dir.path().to_path_buf())),
+            allowed_hosts: vec!["*".to_string()],
+            ..Default::default()
+        };
+        let response = setup_with_context(context(), "/services").await;
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("world_cities"));
+
+        let path = "/services/world_cities/tiles/0/0/0.pbf";
+        let response = setup_with_context(context(), path).await;
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().get(CONTENT_ENCODING).is_none());
+        let identity = body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(get_data_format(&identity), DataFormat::Pbf);
+
+        let request = Request::builder()
+            .uri(format!("http://localhost{path}"))
+            .header(ACCEPT_ENCODING, "gzip")
+            .body(Body::from(""))
+            .unwrap();
+        let response = get_service(request, Arc::new(context())).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get(CONTENT_ENCODING).unwrap(), "gzip");
+        let gzipped = body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(decompress(&gzipped, DataFormat::Gzip).unwrap(), identity);
+    }
+
+    #[tokio::test]
+    async fn negotiate_vector_tile_encoding() {
+        let path = "/services/world_cities/tiles/0/0/0.pbf";
+        let response = setup_with_headers(path, &[(ACCEPT_ENCODING, "gzip, deflate, br")]).await;
+        assert_eq!(response.headers().get(CONTENT_ENCODING).unwrap(), "gzip");
+        assert_eq!(response.headers().get(VARY).unwrap(), "accept-encoding");
+        let gzipped = body::to_bytes(response.into_body()).await.unwrap();
+
+        let response = setup_with_headers(path, &[(ACCEPT_ENCODING, "identity")]).await;
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().get(CONTENT_ENCODING).is_none());
+        assert_eq!(response.headers().get(VARY).unwrap(), "accept-encoding");
+        let identity = body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(get_data_format(&identity), DataFormat::Pbf);
+        let mut decoded = Vec::new();
+        GzDecoder::new(&gzipped[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, identity);
+
+        let response = setup_with_headers(path, &[(ACCEPT_ENCODING, "br")]).await;
+        assert_eq!(response.headers().get(CONTENT_ENCODING).unwrap(), "br");
+
+        let response = setup_with_headers(path, &[(ACCEPT_ENCODING, "*;q=0")]).await;
+        assert_eq!(response.status(), 406);
+    }
+
+    #[tokio::test]
+    async fn compress_tilejson() {
+        let path = "/services/world_cities";
+        let response = setup_with_headers(path, &[(ACCEPT_ENCODING, "gzip, zstd")]).await;
+        assert_eq!(response.headers().get(CONTENT_ENCODING).unwrap(), "zstd");
+        assert_eq!(response.headers().get(VARY).unwrap(), "accept-encoding");
+        let data = body::to_bytes(response.into_body()).await.unwrap();
+        let tilejson: JSONValue =
+            serde_json::from_str(&decode(data.to_vec(), DataFormat::Zstd).unwrap()).unwrap();
+        assert_eq!(tilejson["id"], "world_cities");
+
+        let response = setup_with_headers(path, &[]).await;
+        assert!(response.headers().get(CONTENT_ENCODING).is_none());
+    }
+
+    #[tokio::test]
+    async fn get_composite_tiles() {
+        let tile = |path: &'static str| async move {
+            let response = setup_with_headers(path, &[(ACCEPT_ENCODING, "identity")]).await;
+            assert_eq!(response.status(), 200, "{path}");
+            body::to_bytes(response.into_body()).await.unwrap()
+        };
+        let cities = tile("/services/world_cities/tiles/1/0/0.pbf").await;
+        let directory = tile("/services/directory/world_cities/tiles/1/0/0.pbf").await;
+        let composite = tile("/services/world_cities,directory/world_cities/tiles/1/0/0.pbf").await;
+        let merged = mvt::merge_tiles(&[cities.to_vec(), directory.to_vec()]).unwrap();
+        assert_eq!(composite, merged);
+
+        // Tiles outside of one of the tilesets only contain the other one
+        let cities = tile("/services/world_cities/tiles/3/1/2.pbf").await;
+        let composite = tile("/services/world_cities,directory/world_cities/tiles/3/1/2.pbf").await;
+        assert_eq!(composite, cities);
+
+        let response = setup_with_headers(
+            "/services/world_cities,geography-class-png/tiles/0/0/0.pbf",
+            &[],
+        )
+        .await;
+        assert_eq!(response.status(), 400);
+        let response =
+            setup_with_headers("/services/world_cities,world_cities/tiles/0/0/0.pbf", &[]).await;
+        assert_eq!(response.status(), 400);
+        let response =
+            setup_with_headers("/services/world_cities,missing/tiles/0/0/0.pbf", &[]).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn get_composite_details() {
+        let response =
+            setup_with_headers("/services/world_cities,directory/world_cities", &[]).await;
+        assert_eq!(response.status(), 200);
+        let tilejson: JSONValue =
+            serde_json::from_slice(&body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_eq!(
+            tilejson["tiles"][0],
+            "http://localhost/services/world_cities,directory/world_cities/tiles/{z}/{x}/{y}.pbf"
+        );
+        assert_eq!(tilejson["maxzoom"], 6);
+        assert_eq!(tilejson["vector_layers"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn get_composite_raster_tiles() {
+        let path = "/services/geography-class-jpg,geography-class-png/tiles/1/0/0.webp";
+        let response = setup_with_headers(path, &[]).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), "image/webp");
+        let data = body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(get_data_format(&data), DataFormat::Webp);
+
+        // The opaque png tiles are drawn over the jpg tiles
+        let path = "/services/geography-class-jpg,geography-class-png/tiles/1/0/0.png";
+        let composite = setup_with_headers(path, &[]).await;
+        let composite = body::to_bytes(composite.into_body()).await.unwrap();
+        let png = setup_with_headers("/services/geography-class-png/tiles/1/0/0.png", &[]).await;
+        let png = body::to_bytes(png.into_body()).await.unwrap();
+        assert_eq!(
+            decode_image(&composite, DataFormat::Png).unwrap(),
+            decode_image(&png, DataFormat::Png).unwrap()
+        );
+
+        let path = "/services/geography-class-jpg,geography-class-png/tiles/1/0/0.pbf";
+        let response = setup_with_headers(path, &[]).await;
+        assert_eq!(response.status(), 400);
+
+        let response =
+            setup_with_headers("/services/geography-class-jpg,geography-class-png", &[]).await;
+        let tilejson: JSONValue =
+            serde_json::from_slice(&body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_eq!(tilejson["format"], "jpg");
+        assert!(tilejson.get("vector_layers").is_none());
+    }
+
+    #[tokio::test]
+    async fn convert_raster_tiles() {
+        let path = "/services/geography-class-png/tiles/1/0/0.webp";
+        let response = setup_with_headers(path, &[]).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), "image/webp");
+        let data = body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(get_data_format(&data), DataFormat::Webp);
+
+        let path = "/services/geography-class-png/tiles/1/0/0.jpg";
+        let high = setup_with_headers(&format!("{path}?quality=95"), &[]).await;
+        let high = body::to_bytes(high.into_body()).await.unwrap();
+        let low = setup_with_headers(&format!("{path}?quality=20"), &[]).await;
+        let low = body::to_bytes(low.into_body()).await.unwrap();
+        assert_eq!(get_data_format(&low), DataFormat::Jpg);
+        assert!(low.len() < high.len());
+
+        let response = setup_with_headers(&format!("{path}?quality=0"), &[]).await;
+        assert_eq!(response.status(), 400);
+        let response =
+            setup_with_headers("/services/geography-class-png/tiles/1/0/0.gif", &[]).await;
+        assert_eq!(response.status(), 406);
+        let response =
+            setup_with_headers("/services/geography-class-png/tiles/1/0/0.pbf", &[]).await;
+        assert_eq!(response.status(), 406);
+        let response = setup_with_headers("/services/world_cities/tiles/1/0/0.png", &[]).await;
+        assert_eq!(response.status(), 406);
+    }
+
+    #[tokio::test]
+    async fn get_high_dpi_tiles() {
+        // Zoom level 0 is stitched from the tiles at zoom level 1
+        let response = setup_with_headers("/services/geography-class-png/tiles/0/0/0@2x.png", &[]);
+        let response = response.await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), "image/png");
+        let data = body::to_bytes(response.into_body()).await.unwrap();
+        let image = decode_image(&data, DataFormat::Png).unwrap();
+        assert_eq!(image.dimensions(), (512, 512));
+        let child = setup_with_headers("/services/geography-class-png/tiles/1/1/1.png", &[]).await;
+        let child = body::to_bytes(child.into_body()).await.unwrap();
+        let child = decode_image(&child, DataFormat::Png).unwrap();
+        assert_eq!(image.get_pixel(300, 300), child.get_pixel(44, 44));
+
+        // Zoom level 1 is the maxzoom, so the tile is upsampled
+        let response =
+            setup_with_headers("/services/geography-class-png/tiles/1/1/1@2x.webp", &[]).await;
+        assert_eq!(response.status(), 200);
+        let data = body::to_bytes(response.into_body()).await.unwrap();
+        let image = decode_image(&data, DataFormat::Webp).unwrap();
+        assert_eq!(image.dimensions(), (512, 512));
+
+        let response = setup_with_headers("/services/world_cities/tiles/0/0/0@2x.pbf", &[]).await;
+        assert_eq!(response.status(), 406);
+
+        let response = setup_with_headers("/services/geography-class-png", &[]).await;
+        let tilejson: JSONValue =
+            serde_json::from_slice(&body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_eq!(
+            tilejson["tiles@2x"][0],
+            "http://localhost/services/geography-class-png/tiles/{z}/{x}/{y}@2x.png"
+        );
+    }
 }
@@ -0,0 +1,184 @@
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use hyper::body::Bytes;
+use lru::LruCache;
+use serde::Serialize;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TileKey {
+    pub tileset: String,
+    pub z: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl TileKey {
+    pub fn new(tileset: &str, z: u32, x: u32, y: u32) -> TileKey {
+        TileKey {
+            tileset: tileset.to_string(),
+            z,
+            x,
+            y,
+        }
+    }
+}
+
+/// Missing tiles are cached too, so each entry costs at least the size of the key
+fn entry_size(key: &TileKey, data: &Option<Bytes>) -> usize {
+    size_of::<TileKey>()
+        + size_of::<Option<Bytes>>()
+        + key.tileset.len()
+        + data.as_ref().map_or(0, |d| d.len())
+}
+
+#[derive(Debug)]
+struct Entries {
+    tiles: LruCache<TileKey, Option<Bytes>>,
+    size: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub size: usize,
+    pub capacity: usize,
+}
+
+/// Least recently used cache of tile data, bounded by the total size of the cached tiles
+#[derive(Debug)]
+pub struct TileCache {
+    entries: Mutex<Entries>,
+    capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl TileCache {
+    /// Create a cache holding at most `capacity` bytes
+    pub fn new(capacity: usize) -> TileCache {
+        TileCache {
+            entries: Mutex::new(Entries {
+                tiles: LruCache::unbounded(),
+                size: 0,
+            }),
+            capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Return the cached tile, `Some(None)` for a cached missing tile, or `None` on a cache miss
+    pub fn get(&self, key: &TileKey) -> Option<Option<Bytes>> {
+        let data = self.entries.lock().unwrap().tiles.get(key).cloned();
+        match data {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        data
+    }
+
+    pub fn insert(&self, key: TileKey, data: Option<Bytes>) {
+        let size = entry_size(&key, &data);
+        if size > self.capacity {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(previous) = entries.tiles.put(key.clone(), data) {
+            entries.size -= entry_size(&key, &previous);
+        }
+        entries.size += size;
+        while entries.size > self.capacity {
+            match entries.tiles.pop_lru() {
+                Some((key, data)) => entries.size -= entry_size(&key, &data),
+                None => break,
+            }
+        }
+    }
+
+    /// Drop all cached tiles of a tileset
+    pub fn invalidate(&self, tileset: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        let keys: Vec<TileKey> = entries
+            .tiles
+            .iter()
+            .filter(|(key, _)| key.tileset == tileset)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            if let Some(data) = entries.tiles.pop(&key) {
+                entries.size -= entry_size(&key, &data);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: entries.tiles.len(),
+            size: entries.size,
+            capacity: self.capacity,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(size: usize) -> Option<Bytes> {
+        Some(Bytes::from(vec![0; size]))
+    }
+
+    #[test]
+    fn evicts_least_recently_used_tiles() {
+        let key_size = entry_size(&TileKey::new("a", 0, 0, 0), &None);
+        let cache = TileCache::new(3 * (key_size + 100));
+        cache.insert(TileKey::new("a", 0, 0, 0), tile(100));
+        cache.insert(TileKey::new("a", 1, 0, 0), tile(100));
+        cache.insert(TileKey::new("a", 1, 1, 0), tile(100));
+        // Use the first tile so the second one is evicted next
+        assert!(cache.get(&TileKey::new("a", 0, 0, 0)).is_some());
+        cache.insert(TileKey::new("a", 1, 0, 1), tile(100));
+
+        assert!(cache.get(&TileKey::new("a", 1, 0, 0)).is_none());
+        assert!(cache.get(&TileKey::new("a", 0, 0, 0)).is_some());
+        assert!(cache.get(&TileKey::new("a", 1, 0, 1)).is_some());
+        let stats = cache.stats();
+        assert_eq!(stats.entries, 3);
+        assert!(stats.size <= stats.capacity);
+        assert_eq!(stats.hits, 3);
+        assert_eq!(stats.misses, 1);
+    }
+
+    #[test]
+    fn skips_tiles_larger_than_capacity() {
+        let cache = TileCache::new(100);
+        cache.insert(TileKey::new("a", 0, 0, 0), tile(200));
+        assert!(cache.get(&TileKey::new("a", 0, 0, 0)).is_none());
+        assert_eq!(cache.stats().size, 0);
+    }
+
+    #[test]
+    fn caches_missing_tiles() {
+        let cache = TileCache::new(1000);
+        cache.insert(TileKey::new("a", 0, 0, 0), None);
+        assert_eq!(cache.get(&TileKey::new("a", 0, 0, 0)), Some(None));
+    }
+
+    #[test]
+    fn invalidates_tileset() {
+        let cache = TileCache::new(10_000);
+        cache.insert(TileKey::new("a", 0, 0, 0), tile(100));
+        cache.insert(TileKey::new("b", 0, 0, 0), tile(100));
+        cache.invalidate("a");
+        assert!(cache.get(&TileKey::new("a", 0, 0, 0)).is_none());
+        assert!(cache.get(&TileKey::new("b", 0, 0, 0)).is_some());
+        assert_eq!(cache.stats().entries, 1);
+    }
+}
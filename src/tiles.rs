@@ -1,32 +1,216 @@
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
-use std::fs::read_dir;
+use std::fs::{metadata as file_metadata, read_dir};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use log::warn;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{params, OpenFlags};
+use rusqlite::types::ValueRef;
+use rusqlite::{params, OpenFlags, OptionalExtension};
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JSONValue;
 use tilejson::{tilejson, Bounds, Center, TileJSON};
 
+use crate::directory::{is_tile_directory, TileDirectory, METADATA_FILE};
+use crate::encoding::ContentEncoding;
 use crate::errors::{Error, Result};
+use crate::pmtiles::PMTiles;
+use crate::raster::{image_dimensions, is_raster_format, TILE_SIZE};
 
-use crate::utils::{decode, get_data_format, DataFormat};
+use crate::utils::{decode, get_data_format, is_brotli_vector_tile, DataFormat};
 
 type Connection = r2d2::PooledConnection<SqliteConnectionManager>;
 
+/// Storage backing a tileset
+#[derive(Clone, Debug)]
+pub enum TileSource {
+    MBTiles(r2d2::Pool<SqliteConnectionManager>),
+    PMTiles(Arc<PMTiles>),
+    Directory(TileDirectory),
+}
+
+impl TileSource {
+    /// Read the tile at the given XYZ coordinates, or `None` if there is no tile there
+    pub fn get_tile(&self, z: u32, x: u32, y: u32) -> Result<Option<Vec<u8>>> {
+        match self {
+            TileSource::MBTiles(pool) => {
+                let connection = pool.get().map_err(Error::Pool)?;
+                // mbtiles use the TMS scheme
+                get_tile_data(&connection, z, x, (1 << z) - 1 - y)
+            }
+            TileSource::PMTiles(archive) => archive.get_tile(z, x, y),
+            TileSource::Directory(directory) => directory.get_tile(z, x, y),
+        }
+    }
+
+    /// Read any tile of the tileset, e.g. to find out the size of its tiles
+    pub fn sample_tile(&self) -> Result<Option<Vec<u8>>> {
+        match self {
+            TileSource::MBTiles(pool) => {
+                let connection = pool.get().map_err(Error::Pool)?;
+                let mut statement = connection
+                    .prepare(r#"SELECT tile_data FROM tiles LIMIT 1"#)
+                    .map_err(Error::DBConnection)?;
+                statement
+                    .query_row([], |row| row.get(0))
+                    .optional()
+                    .map_err(Error::DBConnection)
+            }
+            TileSource::PMTiles(archive) => {
+                let header = &archive.header;
+                let z = u32::from(header.center_zoom);
+                let (x, y) = tile_at(header.center_longitude, header.center_latitude, z);
+                archive.get_tile(z, x, y)
+            }
+            TileSource::Directory(directory) => directory.sample_tile(),
+        }
+    }
+
+    /// Check that tiles can be read, waiting at most `timeout` for a database connection
+    pub fn check(&self, timeout: Duration) -> Result<()> {
+        match self {
+            TileSource::MBTiles(pool) => {
+                let connection = pool.get_timeout(timeout).map_err(Error::Pool)?;
+                connection
+                    .query_row(r#"SELECT 1 FROM tiles LIMIT 1"#, [], |_| Ok(()))
+                    .optional()
+                    .map(|_| ())
+                    .map_err(Error::DBConnection)
+            }
+            TileSource::PMTiles(archive) => archive.check(),
+            TileSource::Directory(directory) => directory.metadata().map(|_| ()),
+        }
+    }
+
+    /// Read the UTFGrid at the given XYZ coordinates. Only mbtiles contain grids.
+    pub fn get_grid(
+        &self,
+        data_format: DataFormat,
+        z: u32,
+        x: u32,
+        y: u32,
+    ) -> Result<Option<UTFGrid>> {
+        match self {
+            TileSource::MBTiles(pool) => {
+                let connection = pool.get().map_err(Error::Pool)?;
+                get_grid_data(&connection, data_format, z, x, (1 << z) - 1 - y)
+            }
+            TileSource::PMTiles(_) | TileSource::Directory(_) => Ok(None),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct TileMeta {
-    pub connection_pool: r2d2::Pool<SqliteConnectionManager>,
+    pub source: TileSource,
     pub path: PathBuf,
+    pub modified: Option<SystemTime>,
     pub tilejson: TileJSON,
     pub id: String,
     pub tile_format: DataFormat,
+    /// Width and height of raster tiles in pixels
+    pub tile_size: u32,
+    /// Compression of tiles which can't be recognized from their data (i.e. brotli)
+    pub tile_encoding: ContentEncoding,
     pub grid_format: Option<DataFormat>,
     pub layer_type: Option<String>,
     pub json: Option<JSONValue>,
+    /// Metadata values which were skipped or replaced because they are invalid
+    pub warnings: Vec<MetadataWarning>,
+}
+
+/// An invalid metadata value of a tileset
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct MetadataWarning {
+    pub field: String,
+    pub message: String,
+}
+
+impl TileMeta {
+    /// Check whether an XYZ tile is within the zoom range and bounds declared in the
+    /// tileset metadata. Missing metadata values don't restrict anything.
+    pub fn contains_tile(&self, z: u32, x: u32, y: u32) -> bool {
+        if let Some(minzoom) = self.tilejson.minzoom {
+            if z < u32::from(minzoom) {
+                return false;
+            }
+        }
+        if let Some(maxzoom) = self.tilejson.maxzoom {
+            if z > u32::from(maxzoom) {
+                return false;
+            }
+        }
+        if let Some(bounds) = &self.tilejson.bounds {
+            let tile = tile_bounds(z, x, y);
+            // Bounds crossing the antimeridian are only checked by latitude
+            if bounds.left <= bounds.right
+                && (tile.left >= bounds.right || tile.right <= bounds.left)
+            {
+                return false;
+            }
+            if tile.bottom >= bounds.top || tile.top <= bounds.bottom {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Return the XYZ coordinates of the tile at `maxzoom` containing a tile at most `levels`
+    /// zoom levels past it, so the tile can be generated from its ancestor. Returns `None`
+    /// for tiles that are not past `maxzoom`, too far past it, or outside of the bounds.
+    pub fn overzoom_ancestor(&self, z: u32, x: u32, y: u32, levels: u8) -> Option<(u32, u32, u32)> {
+        let maxzoom = u32::from(self.tilejson.maxzoom?);
+        if z <= maxzoom || z - maxzoom > u32::from(levels) {
+            return None;
+        }
+        let dz = z - maxzoom;
+        let ancestor = (maxzoom, x >> dz, y >> dz);
+        match self.contains_tile(ancestor.0, ancestor.1, ancestor.2) {
+            true => Some(ancestor),
+            false => None,
+        }
+    }
+
+    /// Modification time of the tiles, or `None` for tile directories, where every tile
+    /// file can change on its own without touching `metadata.json`
+    pub fn tiles_modified(&self) -> Option<SystemTime> {
+        match self.source {
+            TileSource::Directory(_) => None,
+            _ => self.modified,
+        }
+    }
+}
+
+/// Return the XYZ column and row of the tile at zoom level `z` containing a location
+pub fn tile_at(longitude: f64, latitude: f64, z: u32) -> (u32, u32) {
+    let n = f64::from(1u32 << z.min(31));
+    let latitude = latitude.clamp(-85.0511, 85.0511).to_radians();
+    let x = (longitude + 180.0) / 360.0 * n;
+    let y = (1.0 - latitude.tan().asinh() / std::f64::consts::PI) / 2.0 * n;
+    let clamp = |v: f64| (v.max(0.0) as u32).min(n as u32 - 1);
+    (clamp(x), clamp(y))
+}
+
+/// Longitude/latitude bounds of an XYZ tile in the web mercator grid
+pub fn tile_bounds(z: u32, x: u32, y: u32) -> Bounds {
+    let n = f64::from(1u32 << z.min(31));
+    let lon = |x: f64| x / n * 360.0 - 180.0;
+    let lat = |y: f64| {
+        (std::f64::consts::PI * (1.0 - 2.0 * y / n))
+            .sinh()
+            .atan()
+            .to_degrees()
+    };
+    Bounds::new(
+        lon(f64::from(x)),
+        lat(f64::from(y) + 1.0),
+        lon(f64::from(x) + 1.0),
+        lat(f64::from(y)),
+    )
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -45,7 +229,7 @@ struct UTFGridKeys {
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UTFGrid {
-    pub data: HashMap<String, JSONValue>,
+    pub data: BTreeMap<String, JSONValue>,
     pub grid: Vec<String>,
     pub keys: Vec<String>,
 }
@@ -66,12 +250,172 @@ pub fn get_data_format_via_query(
     };
     let data_format: DataFormat = statement
         .query_row([], |row| {
-            Ok(get_data_format(&row.get::<_, Vec<u8>>(0).unwrap()))
+            let data = row.get::<_, Vec<u8>>(0)?;
+            Ok(match get_data_format(&data) {
+                DataFormat::Unknown | DataFormat::Pbf
+                    if category == "tile" && is_brotli_vector_tile(&data) =>
+                {
+                    DataFormat::Brotli
+                }
+                data_format => data_format,
+            })
         })
         .unwrap_or(DataFormat::Unknown);
     Ok(data_format)
 }
 
+impl TileMeta {
+    fn warn(&mut self, field: &str, message: String) {
+        self.warnings.push(MetadataWarning {
+            field: field.to_string(),
+            message,
+        });
+    }
+
+    fn has_warning(&self, field: &str) -> bool {
+        self.warnings.iter().any(|w| w.field == field)
+    }
+}
+
+fn is_location(longitude: f64, latitude: f64) -> bool {
+    (-180.0..=180.0).contains(&longitude) && (-90.0..=90.0).contains(&latitude)
+}
+
+/// Apply an mbtiles style metadata value, where every value is a string.
+/// Invalid values are skipped and recorded in the warnings of the tileset.
+fn set_metadata_value(metadata: &mut TileMeta, label: &str, value: String) {
+    let zoom = |value: &str| match value.parse::<u8>() {
+        Ok(zoom) if zoom <= 30 => Ok(zoom),
+        _ => Err(format!("{value:?} is not a zoom level from 0 to 30")),
+    };
+    match label {
+        "name" => metadata.tilejson.name = Some(value),
+        "version" => metadata.tilejson.version = Some(value),
+        "bounds" => match Bounds::from_str(value.as_str()) {
+            Ok(b) if is_location(b.left, b.bottom) && is_location(b.right, b.top) => {
+                metadata.tilejson.bounds = Some(b)
+            }
+            Ok(_) => metadata.warn(label, format!("{value:?} is out of range")),
+            Err(err) => metadata.warn(label, format!("{value:?} is invalid: {err}")),
+        },
+        "center" => match Center::from_str(value.as_str()) {
+            Ok(c) if is_location(c.longitude, c.latitude) => metadata.tilejson.center = Some(c),
+            Ok(_) => metadata.warn(label, format!("{value:?} is out of range")),
+            Err(err) => metadata.warn(label, format!("{value:?} is invalid: {err}")),
+        },
+        "minzoom" => match zoom(&value) {
+            Ok(z) => metadata.tilejson.minzoom = Some(z),
+            Err(message) => metadata.warn(label, message),
+        },
+        "maxzoom" => match zoom(&value) {
+            Ok(z) => metadata.tilejson.maxzoom = Some(z),
+            Err(message) => metadata.warn(label, message),
+        },
+        "description" => metadata.tilejson.description = Some(value),
+        "attribution" => metadata.tilejson.attribution = Some(value),
+        "type" => metadata.layer_type = Some(value),
+        "legend" => metadata.tilejson.legend = Some(value),
+        "template" => metadata.tilejson.template = Some(value),
+        "json" => match serde_json::from_str(&value) {
+            Ok(json @ JSONValue::Object(_)) => metadata.json = Some(json),
+            Ok(_) => metadata.warn(label, "not an object".to_string()),
+            Err(err) => metadata.warn(label, format!("{err}")),
+        },
+        _ => (),
+    };
+}
+
+/// Drop a zoom range where `minzoom` is above `maxzoom`
+fn check_zoom_range(metadata: &mut TileMeta) {
+    if let (Some(min), Some(max)) = (metadata.tilejson.minzoom, metadata.tilejson.maxzoom) {
+        if min > max {
+            metadata.tilejson.minzoom = None;
+            metadata.tilejson.maxzoom = None;
+            metadata.warn("minzoom", format!("{min} is above maxzoom {max}"));
+            metadata.warn("maxzoom", format!("{max} is below minzoom {min}"));
+        }
+    }
+}
+
+/// Return the minimum and maximum zoom levels of the tiles in an mbtiles file, and the
+/// bounds of its tiles at the minimum zoom level
+fn get_tiles_extent(connection: &Connection) -> Result<Option<(u8, u8, Bounds)>> {
+    let zooms: (Option<u32>, Option<u32>) = connection
+        .query_row(
+            r#"SELECT MIN(zoom_level), MAX(zoom_level) FROM tiles"#,
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .map_err(Error::DBConnection)?;
+    let (minzoom, maxzoom) = match zooms {
+        (Some(min), Some(max)) if max <= 30 => (min, max),
+        _ => return Ok(None),
+    };
+    let extent: (u32, u32, u32, u32) = connection
+        .query_row(
+            r#"SELECT MIN(tile_column), MAX(tile_column), MIN(tile_row), MAX(tile_row)
+                 FROM tiles
+                WHERE zoom_level = ?1"#,
+            params![minzoom],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .map_err(Error::DBConnection)?;
+    let (min_column, max_column, min_row, max_row) = extent;
+    let n = 1 << minzoom;
+    if max_column >= n || max_row >= n {
+        return Ok(None);
+    }
+    // mbtiles use the TMS scheme, so the maximum row is the northernmost
+    let top_left = tile_bounds(minzoom, min_column, n - 1 - max_row);
+    let bottom_right = tile_bounds(minzoom, max_column, n - 1 - min_row);
+    let bounds = Bounds::new(
+        top_left.left,
+        bottom_right.bottom,
+        bottom_right.right,
+        top_left.top,
+    );
+    Ok(Some((minzoom as u8, maxzoom as u8, bounds)))
+}
+
+/// Replace invalid bounds and zoom levels of an mbtiles file by the extent of its tiles
+fn repair_metadata(metadata: &mut TileMeta, connection: &Connection) -> Result<()> {
+    let fields = ["bounds", "minzoom", "maxzoom"];
+    if !fields.iter().any(|field| metadata.has_warning(field)) {
+        return Ok(());
+    }
+    let (minzoom, maxzoom, bounds) = match get_tiles_extent(connection)? {
+        Some(extent) => extent,
+        None => return Ok(()),
+    };
+    for warning in metadata.warnings.iter_mut() {
+        let replacement = match warning.field.as_str() {
+            "bounds" => {
+                metadata.tilejson.bounds = Some(bounds);
+                let Bounds {
+                    left,
+                    bottom,
+                    right,
+                    top,
+                } = bounds;
+                format!("{left},{bottom},{right},{top}")
+            }
+            "minzoom" => {
+                metadata.tilejson.minzoom = Some(minzoom);
+                minzoom.to_string()
+            }
+            "maxzoom" => {
+                metadata.tilejson.maxzoom = Some(maxzoom);
+                maxzoom.to_string()
+            }
+            _ => continue,
+        };
+        warning
+            .message
+            .push_str(&format!("; using {replacement} from the tiles table"));
+    }
+    Ok(())
+}
+
 pub fn get_tile_details(path: &Path, tile_name: &str) -> Result<TileMeta> {
     let manager = SqliteConnectionManager::file(path).with_flags(OpenFlags::SQLITE_OPEN_READ_ONLY);
     let connection_pool = match r2d2::Pool::new(manager) {
@@ -79,7 +423,7 @@ pub fn get_tile_details(path: &Path, tile_name: &str) -> Result<TileMeta> {
         Err(err) => return Err(Error::Pool(err)),
     };
 
-    let connection = connection_pool.get().unwrap();
+    let connection = connection_pool.get().map_err(Error::Pool)?;
 
     // 'tiles', 'metadata' tables or views must be present
     let query = r#"SELECT count(*) FROM sqlite_master WHERE name IN ('tiles', 'metadata')"#;
@@ -96,90 +440,299 @@ pub fn get_tile_details(path: &Path, tile_name: &str) -> Result<TileMeta> {
         Err(err) => return Err(Error::DBConnection(err)),
     };
 
+    let mut tile_encoding = ContentEncoding::Identity;
     let tile_format = match get_data_format_via_query(tile_name, &connection, "tile") {
         Ok(tile_format) => match tile_format {
             DataFormat::Unknown => return Err(Error::UnknownTileFormat(tile_name.to_string())),
-            DataFormat::Gzip => DataFormat::Pbf, // GZIP masks PBF format too
+            // Compression masks PBF format too
+            DataFormat::Gzip | DataFormat::Zlib | DataFormat::Zstd => DataFormat::Pbf,
+            DataFormat::Brotli => {
+                tile_encoding = ContentEncoding::Brotli;
+                DataFormat::Pbf
+            }
             _ => tile_format,
         },
         Err(err) => return Err(err),
     };
 
     let mut metadata = TileMeta {
-        connection_pool,
+        source: TileSource::MBTiles(connection_pool.clone()),
         path: PathBuf::from(path),
+        modified: file_metadata(path).and_then(|m| m.modified()).ok(),
         tilejson: tilejson! {
             tilejson: "2.1.0".to_string(),
             tiles: vec!["".to_string()],
         },
         id: tile_name.to_string(),
         tile_format,
+        tile_size: TILE_SIZE,
+        tile_encoding,
         grid_format: get_grid_info(tile_name, &connection),
         layer_type: None,
         json: None,
+        warnings: Vec::new(),
     };
 
     let mut statement = connection
         .prepare(r#"SELECT name, value FROM metadata WHERE value IS NOT ''"#)
-        .unwrap();
-    let mut metadata_rows = statement.query([]).unwrap();
-
-    while let Some(row) = metadata_rows.next().unwrap() {
-        let label: String = row.get(0).unwrap();
-        let value: String = row.get(1).unwrap();
-        match label.as_ref() {
-            "name" => metadata.tilejson.name = Some(value),
-            "version" => metadata.tilejson.version = Some(value),
-            "bounds" => metadata.tilejson.bounds = Some(Bounds::from_str(value.as_str()).unwrap()),
-            "center" => metadata.tilejson.center = Some(Center::from_str(value.as_str()).unwrap()),
-            "minzoom" => metadata.tilejson.minzoom = Some(value.parse().unwrap()),
-            "maxzoom" => metadata.tilejson.maxzoom = Some(value.parse().unwrap()),
-            "description" => metadata.tilejson.description = Some(value),
-            "attribution" => metadata.tilejson.attribution = Some(value),
-            "type" => metadata.layer_type = Some(value),
-            "legend" => metadata.tilejson.legend = Some(value),
-            "template" => metadata.tilejson.template = Some(value),
-            "json" => metadata.json = Some(serde_json::from_str(&value).unwrap()),
-            _ => (),
+        .map_err(Error::DBConnection)?;
+    let mut metadata_rows = statement.query([]).map_err(Error::DBConnection)?;
+
+    while let Some(row) = metadata_rows.next().map_err(Error::DBConnection)? {
+        let label: String = match row.get(0) {
+            Ok(label) => label,
+            Err(_) => continue,
         };
+        // Values are meant to be text, but numbers are common too
+        let value = match row.get_ref(1).map_err(Error::DBConnection)? {
+            ValueRef::Text(text) => String::from_utf8_lossy(text).into_owned(),
+            ValueRef::Integer(value) => value.to_string(),
+            ValueRef::Real(value) => value.to_string(),
+            ValueRef::Null | ValueRef::Blob(_) => {
+                metadata.warn(&label, "not a text value".to_string());
+                continue;
+            }
+        };
+        set_metadata_value(&mut metadata, &label, value);
     }
+    check_zoom_range(&mut metadata);
+    repair_metadata(&mut metadata, &connection)?;
 
     Ok(metadata)
 }
 
-/// Walk through the given path and its subfolders, find all valid mbtiles and create
-/// and return a map of mbtiles file names to their absolute path
-pub fn discover_tilesets(parent_dir: String, path: &PathBuf) -> HashMap<String, TileMeta> {
-    let mut tiles = HashMap::new();
-    for p in read_dir(path).unwrap() {
-        let p = p.unwrap().path();
-        if p.is_dir() {
-            let dir_name = p.file_stem().unwrap().to_str().unwrap();
-            let mut parent_dir_cloned = parent_dir.clone();
-            parent_dir_cloned.push_str(dir_name);
-            parent_dir_cloned.push('/');
-            tiles.extend(discover_tilesets(parent_dir_cloned, &p));
-        } else if p.extension().and_then(OsStr::to_str) == Some("mbtiles") {
-            let file_name = p.file_stem().and_then(OsStr::to_str).unwrap();
-            let mut parent_dir_cloned = parent_dir.clone();
-            parent_dir_cloned.push_str(file_name);
-            match get_tile_details(&p, file_name) {
-                Ok(tile_meta) => tiles.insert(parent_dir_cloned, tile_meta),
-                Err(err) => {
-                    warn!("{err}");
-                    None
+pub fn get_pmtiles_details(path: &Path, tile_name: &str) -> Result<TileMeta> {
+    let archive = Arc::new(PMTiles::open(path, tile_name)?);
+    let header = &archive.header;
+    let mut metadata = TileMeta {
+        source: TileSource::PMTiles(archive.clone()),
+        path: PathBuf::from(path),
+        modified: file_metadata(path).and_then(|m| m.modified()).ok(),
+        tilejson: tilejson! {
+            tilejson: "2.1.0".to_string(),
+            tiles: vec!["".to_string()],
+            bounds: Bounds::new(
+                header.min_longitude,
+                header.min_latitude,
+                header.max_longitude,
+                header.max_latitude,
+            ),
+            center: Center::new(
+                header.center_longitude,
+                header.center_latitude,
+                header.center_zoom,
+            ),
+            minzoom: header.min_zoom,
+            maxzoom: header.max_zoom,
+        },
+        id: tile_name.to_string(),
+        tile_format: header.tile_type,
+        tile_size: TILE_SIZE,
+        tile_encoding: header.tile_compression.content_encoding(),
+        grid_format: None,
+        layer_type: None,
+        json: None,
+        warnings: Vec::new(),
+    };
+
+    let mut json = serde_json::Map::new();
+    if let JSONValue::Object(values) = archive.metadata()? {
+        for (label, value) in values {
+            match (label.as_ref(), value) {
+                ("name", JSONValue::String(value)) => metadata.tilejson.name = Some(value),
+                ("version", JSONValue::String(value)) => metadata.tilejson.version = Some(value),
+                ("description", JSONValue::String(value)) => {
+                    metadata.tilejson.description = Some(value)
+                }
+                ("attribution", JSONValue::String(value)) => {
+                    metadata.tilejson.attribution = Some(value)
                 }
+                ("type", JSONValue::String(value)) => metadata.layer_type = Some(value),
+                ("legend", JSONValue::String(value)) => metadata.tilejson.legend = Some(value),
+                ("template", JSONValue::String(value)) => metadata.tilejson.template = Some(value),
+                // The header is authoritative for these
+                ("bounds" | "center" | "minzoom" | "maxzoom" | "format", _) => (),
+                (_, value) => {
+                    json.insert(label, value);
+                }
+            };
+        }
+    }
+    if !json.is_empty() {
+        metadata.json = Some(JSONValue::Object(json));
+    }
+
+    Ok(metadata)
+}
+
+/// Load a directory of tiles. Its `metadata.json` holds the same keys as the metadata
+/// table of an mbtiles file; non-string values are used as their JSON representation.
+pub fn get_directory_details(path: &Path, tile_name: &str) -> Result<TileMeta> {
+    let directory = TileDirectory::open(path, tile_name)?;
+    let tile_format = match directory.tile_format() {
+        // Vector tiles can have other extensions, e.g. `.mvt`
+        DataFormat::Unknown => match directory.sample_tile()?.as_deref().map(get_data_format) {
+            Some(DataFormat::Pbf | DataFormat::Gzip | DataFormat::Zlib | DataFormat::Zstd) => {
+                DataFormat::Pbf
+            }
+            _ => return Err(Error::UnknownTileFormat(tile_name.to_string())),
+        },
+        tile_format => tile_format,
+    };
+    let mut metadata = TileMeta {
+        source: TileSource::Directory(directory.clone()),
+        path: PathBuf::from(path),
+        modified: file_metadata(path.join(METADATA_FILE))
+            .and_then(|m| m.modified())
+            .ok(),
+        tilejson: tilejson! {
+            tilejson: "2.1.0".to_string(),
+            tiles: vec!["".to_string()],
+        },
+        id: tile_name.to_string(),
+        tile_format,
+        tile_size: TILE_SIZE,
+        tile_encoding: ContentEncoding::Identity,
+        grid_format: None,
+        layer_type: None,
+        json: None,
+        warnings: Vec::new(),
+    };
+
+    if let JSONValue::Object(values) = directory.metadata()? {
+        for (label, value) in values {
+            let value = match value {
+                JSONValue::String(value) => value,
+                value => value.to_string(),
             };
+            set_metadata_value(&mut metadata, &label, value);
+        }
+    }
+    check_zoom_range(&mut metadata);
+
+    Ok(metadata)
+}
+
+/// Open a tileset file or directory, based on its extension
+pub fn load_tileset(path: &Path, tile_name: &str) -> Result<TileMeta> {
+    let mut tile_meta = if path.is_dir() {
+        get_directory_details(path, tile_name)?
+    } else {
+        match path.extension().and_then(OsStr::to_str) {
+            Some("pmtiles") => get_pmtiles_details(path, tile_name)?,
+            _ => get_tile_details(path, tile_name)?,
+        }
+    };
+    if is_raster_format(tile_meta.tile_format) {
+        let sample = tile_meta.source.sample_tile()?;
+        if let Some((width, _)) = sample.and_then(|data| image_dimensions(&data).ok()) {
+            tile_meta.tile_size = width;
         }
     }
+    for warning in &tile_meta.warnings {
+        warn!(
+            "{}: invalid {}: {}",
+            tile_meta.id, warning.field, warning.message
+        );
+    }
+    Ok(tile_meta)
+}
+
+/// Walk through the given path and its subfolders, find all valid mbtiles, pmtiles and
+/// tile directories and return a map of tileset ids to their metadata
+pub fn discover_tilesets(parent_dir: String, path: &PathBuf) -> HashMap<String, TileMeta> {
+    let mut tiles = HashMap::new();
+    for (tile_name, p) in discover_tileset_paths(parent_dir, path) {
+        let file_name = tileset_name(&p).unwrap();
+        match load_tileset(&p, file_name) {
+            Ok(tile_meta) => {
+                tiles.insert(tile_name, tile_meta);
+            }
+            Err(err) => warn!("{err}"),
+        };
+    }
     tiles
 }
 
+/// Return the name of the tileset file or directory at `path`: the name of a directory,
+/// or the name of a file without its extension
+pub fn tileset_name(path: &Path) -> Option<&str> {
+    match path.is_dir() {
+        true => path.file_name()?.to_str(),
+        false => path.file_stem()?.to_str(),
+    }
+}
+
+/// Walk through the given path and its subfolders and return a map of tileset ids
+/// to the paths of the mbtiles and pmtiles files and tile directories, without opening them.
+/// Directories containing a `metadata.json` are tilesets and are not searched further.
+/// Paths whose id is already taken, e.g. `foo.pmtiles` next to `foo.mbtiles`, are skipped
+/// with a warning.
+pub fn discover_tileset_paths(parent_dir: String, path: &PathBuf) -> HashMap<String, PathBuf> {
+    let (paths, duplicates) = find_tileset_paths(parent_dir, path);
+    for (id, duplicate) in duplicates {
+        warn_duplicate(&id, &duplicate, &paths[&id]);
+    }
+    paths
+}
+
+pub fn warn_duplicate(id: &str, duplicate: &Path, path: &Path) {
+    warn!(
+        "Skipping {}: tileset id {id} is already used by {}",
+        duplicate.display(),
+        path.display()
+    );
+}
+
+/// Like `discover_tileset_paths`, but return the skipped paths with their ids instead of
+/// logging them. Entries are visited in order of their paths, so the first path with an
+/// id keeps it.
+pub fn find_tileset_paths(
+    parent_dir: String,
+    path: &PathBuf,
+) -> (HashMap<String, PathBuf>, Vec<(String, PathBuf)>) {
+    let mut paths = HashMap::new();
+    let mut duplicates = Vec::new();
+    let mut entries: Vec<PathBuf> = match read_dir(path) {
+        Ok(entries) => entries.flatten().map(|entry| entry.path()).collect(),
+        Err(err) => {
+            warn!("Unable to read {}: {err}", path.display());
+            return (paths, duplicates);
+        }
+    };
+    entries.sort();
+    for p in entries {
+        let id = match tileset_name(&p) {
+            Some(name) => format!("{parent_dir}{name}"),
+            None => continue,
+        };
+        if p.is_dir() && !is_tile_directory(&p) {
+            let (nested, nested_duplicates) = find_tileset_paths(format!("{id}/"), &p);
+            paths.extend(nested);
+            duplicates.extend(nested_duplicates);
+            continue;
+        }
+        let is_tileset = p.is_dir()
+            || matches!(
+                p.extension().and_then(OsStr::to_str),
+                Some("mbtiles" | "pmtiles")
+            );
+        if !is_tileset {
+            continue;
+        }
+        match paths.entry(id) {
+            Entry::Occupied(entry) => duplicates.push((entry.key().clone(), p)),
+            Entry::Vacant(entry) => {
+                entry.insert(p);
+            }
+        }
+    }
+    (paths, duplicates)
+}
+
 fn get_grid_info(tile_name: &str, connection: &Connection) -> Option<DataFormat> {
-    let mut statement = connection.prepare(r#"SELECT count(*) FROM sqlite_master WHERE name IN ('grids', 'grid_data', 'grid_utfgrid', 'keymap', 'grid_key')"#).unwrap();
-    let count: u8 = statement
-        .query_row([], |row| Ok(row.get(0).unwrap()))
-        .unwrap();
+    let mut statement = connection.prepare(r#"SELECT count(*) FROM sqlite_master WHERE name IN ('grids', 'grid_data', 'grid_utfgrid', 'keymap', 'grid_key')"#).ok()?;
+    let count: u8 = statement.query_row([], |row| row.get(0)).ok()?;
     if count == 5 {
         return match get_data_format_via_query(tile_name, connection, "grid") {
             Ok(grid_format) => Some(grid_format),
@@ -192,13 +745,14 @@ fn get_grid_info(tile_name: &str, connection: &Connection) -> Option<DataFormat>
     None
 }
 
+/// Return the UTFGrid at the given TMS coordinates, or `None` if there is no grid there
 pub fn get_grid_data(
     connection: &Connection,
     data_format: DataFormat,
     z: u32,
     x: u32,
     y: u32,
-) -> Result<UTFGrid> {
+) -> Result<Option<UTFGrid>> {
     let mut statement = connection
         .prepare(
             r#"SELECT grid
@@ -208,17 +762,16 @@ pub fn get_grid_data(
                   AND tile_row = ?3
             "#,
         )
-        .unwrap();
-    let grid_data = match statement.query_row(params![z, x, y], |row| {
-        Ok(row.get::<_, Vec<u8>>(0).unwrap())
-    }) {
+        .map_err(Error::DBConnection)?;
+    let grid_data = match statement.query_row(params![z, x, y], |row| row.get::<_, Vec<u8>>(0)) {
         Ok(d) => d,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
         Err(err) => return Err(Error::DBConnection(err)),
     };
-    let grid_key_json: UTFGridKeys =
-        serde_json::from_str(&decode(grid_data, data_format).unwrap()).unwrap();
+    let grid_key_json: UTFGridKeys = serde_json::from_str(&decode(grid_data, data_format)?)
+        .map_err(|err| Error::InvalidDataFormat(format!("UTFGrid: {err}")))?;
     let mut grid_data = UTFGrid {
-        data: HashMap::new(),
+        data: BTreeMap::new(),
         grid: grid_key_json.grid,
         keys: grid_key_json.keys,
     };
@@ -232,25 +785,24 @@ pub fn get_grid_data(
                   AND tile_row = ?3
             "#,
         )
-        .unwrap(); // TODO handle error
+        .map_err(Error::DBConnection)?;
     let grid_data_iter = statement
         .query_map(params![z, x, y], |row| {
-            Ok((
-                row.get::<_, String>(0).unwrap(),
-                row.get::<_, String>(1).unwrap(),
-            ))
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
         })
-        .unwrap();
+        .map_err(Error::DBConnection)?;
     for gd in grid_data_iter {
-        let (key, value) = gd.unwrap();
-        let value: JSONValue = serde_json::from_str(&value).unwrap();
+        let (key, value) = gd.map_err(Error::DBConnection)?;
+        let value: JSONValue = serde_json::from_str(&value)
+            .map_err(|err| Error::InvalidDataFormat(format!("UTFGrid key {key}: {err}")))?;
         grid_data.data.insert(key, value);
     }
 
-    Ok(grid_data)
+    Ok(Some(grid_data))
 }
 
-pub fn get_tile_data(connection: &Connection, z: u32, x: u32, y: u32) -> Result<Vec<u8>> {
+/// Return the tile at the given TMS coordinates, or `None` if there is no tile there
+pub fn get_tile_data(connection: &Connection, z: u32, x: u32, y: u32) -> Result<Option<Vec<u8>>> {
     let mut statement = connection
         .prepare(
             r#"SELECT tile_data
@@ -260,9 +812,10 @@ pub fn get_tile_data(connection: &Connection, z: u32, x: u32, y: u32) -> Result<
                   AND tile_row = ?3
             "#,
         )
-        .unwrap(); // TODO handle error
-    match statement.query_row(params![z, x, y], |row| Ok(row.get(0).unwrap())) {
-        Ok(data) => Ok(data),
+        .map_err(Error::DBConnection)?;
+    match statement.query_row(params![z, x, y], |row| row.get(0)) {
+        Ok(data) => Ok(Some(data)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
         Err(err) => Err(Error::DBConnection(err)),
     }
 }
@@ -270,17 +823,72 @@ pub fn get_tile_data(connection: &Connection, z: u32, x: u32, y: u32) -> Result<
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::utils::{compress, decompress};
+    use std::fs::copy;
+    use tempdir::TempDir;
 
     #[test]
     fn get_list_of_valid_tilesets() {
         let tilesets = discover_tilesets(String::new(), &PathBuf::from("./tiles"));
-        // 2 out of 7 tilesets in ./tiles directory are invalid
-        assert_eq!(tilesets.len(), 5);
+        // 2 out of 10 tilesets in ./tiles directory are invalid
+        assert_eq!(tilesets.len(), 8);
+        assert!(tilesets.contains_key("pmtiles/world_cities"));
+        assert!(tilesets.contains_key("directory/world_cities"));
 
         assert!(!tilesets.contains_key("invalid"));
         assert!(!tilesets.contains_key("invalid-tile-format"));
     }
 
+    #[test]
+    fn skip_duplicate_tileset_ids() {
+        let dir = TempDir::new("tiles").unwrap();
+        let mbtiles = dir.path().join("world_cities.mbtiles");
+        let pmtiles = dir.path().join("world_cities.pmtiles");
+        copy("./tiles/pmtiles/world_cities.pmtiles", &pmtiles).unwrap();
+        copy("./tiles/world_cities.mbtiles", &mbtiles).unwrap();
+
+        let (paths, duplicates) = find_tileset_paths(String::new(), &dir.path().to_path_buf());
+        assert_eq!(
+            paths,
+            HashMap::from([("world_cities".to_string(), mbtiles)])
+        );
+        assert_eq!(duplicates, [("world_cities".to_string(), pmtiles)]);
+
+        let tilesets = discover_tilesets(String::new(), &dir.path().to_path_buf());
+        assert_eq!(tilesets.len(), 1);
+        assert!(matches!(
+            tilesets["world_cities"].source,
+            TileSource::MBTiles(_)
+        ));
+    }
+
+    #[test]
+    fn name_tilesets_like_their_ids() {
+        let dir = TempDir::new("tiles").unwrap();
+        let tileset = dir.path().join("nested.v1/world.v2");
+        std::fs::create_dir_all(&tileset).unwrap();
+        std::fs::write(tileset.join(METADATA_FILE), r#"{"format": "pbf"}"#).unwrap();
+        copy(
+            "./tiles/world_cities.mbtiles",
+            dir.path().join("cities.v3.mbtiles"),
+        )
+        .unwrap();
+
+        let tilesets = discover_tilesets(String::new(), &dir.path().to_path_buf());
+        let mut names: Vec<(&str, &str)> = tilesets
+            .iter()
+            .map(|(id, tile_meta)| (id.as_str(), tile_meta.id.as_str()))
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                ("cities.v3", "cities.v3"),
+                ("nested.v1/world.v2", "world.v2")
+            ]
+        );
+    }
+
     #[test]
     fn get_tileset_metadata() {
         let tileset_details = get_tile_details(
@@ -326,4 +934,202 @@ mod tests {
         );
         assert_eq!(tileset_details.tile_format, DataFormat::Pbf);
     }
+
+    #[test]
+    fn get_tileset_with_invalid_metadata() {
+        let dir = TempDir::new("tiles").unwrap();
+        let path = dir.path().join("world_cities.mbtiles");
+        copy("./tiles/world_cities.mbtiles", &path).unwrap();
+        let connection = rusqlite::Connection::open(&path).unwrap();
+        connection
+            .execute_batch(
+                r#"
+                DELETE FROM tiles WHERE zoom_level < 2;
+                UPDATE metadata SET value = 2 WHERE name = 'minzoom';
+                UPDATE metadata SET value = 'x' WHERE name = 'maxzoom';
+                UPDATE metadata SET value = '200,0,1' WHERE name = 'center';
+                UPDATE metadata SET value = '-180,-90' WHERE name = 'bounds';
+                UPDATE metadata SET value = '[]' WHERE name = 'json';
+                "#,
+            )
+            .unwrap();
+        drop(connection);
+
+        let tileset_details = get_tile_details(&path, "world_cities").unwrap();
+        let warnings: Vec<String> = tileset_details
+            .warnings
+            .iter()
+            .map(|w| format!("{}: {}", w.field, w.message))
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                r#"maxzoom: "x" is not a zoom level from 0 to 30; using 6 from the tiles table"#,
+                r#"center: "200,0,1" is out of range"#,
+                r#"bounds: "-180,-90" is invalid: Incorrect number of values. Bounds expects four f64 values.; using -180,-66.51326044311186,180,66.51326044311186 from the tiles table"#,
+                "json: not an object",
+            ]
+        );
+        let tilejson = tileset_details.tilejson;
+        assert_eq!((tilejson.minzoom, tilejson.maxzoom), (Some(2), Some(6)));
+        assert!(tilejson.center.is_none());
+        assert!(tileset_details.json.is_none());
+        assert!((tilejson.bounds.unwrap().top - 66.5133).abs() < 1e-4);
+
+        // Inverted zoom levels are replaced too
+        let connection = rusqlite::Connection::open(&path).unwrap();
+        connection
+            .execute_batch(
+                r#"
+                UPDATE metadata SET value = '5' WHERE name = 'minzoom';
+                UPDATE metadata SET value = '3' WHERE name = 'maxzoom';
+                "#,
+            )
+            .unwrap();
+        drop(connection);
+        let tileset_details = get_tile_details(&path, "world_cities").unwrap();
+        let tilejson = tileset_details.tilejson;
+        assert_eq!((tilejson.minzoom, tilejson.maxzoom), (Some(2), Some(6)));
+        assert!(tileset_details.warnings.contains(&MetadataWarning {
+            field: "minzoom".to_string(),
+            message: "5 is above maxzoom 3; using 2 from the tiles table".to_string(),
+        }));
+    }
+
+    #[test]
+    fn get_pmtiles_metadata() {
+        let tileset_details = get_pmtiles_details(
+            &PathBuf::from("./tiles/pmtiles/world_cities.pmtiles"),
+            "world_cities",
+        )
+        .unwrap();
+        assert_eq!(
+            tileset_details.tilejson.name.unwrap(),
+            "Major cities from Natural Earth data"
+        );
+        assert_eq!(tileset_details.tilejson.minzoom.unwrap(), 0);
+        assert_eq!(tileset_details.tilejson.maxzoom.unwrap(), 6);
+        assert_eq!(
+            tileset_details.tilejson.bounds.unwrap(),
+            Bounds::new(-123.123590, -37.818085, 174.763027, 59.352706)
+        );
+        assert_eq!(tileset_details.tile_format, DataFormat::Pbf);
+        assert_eq!(tileset_details.layer_type.unwrap(), "overlay");
+        assert!(tileset_details.json.unwrap()["vector_layers"].is_array());
+
+        let tileset_details = get_pmtiles_details(
+            &PathBuf::from("./tiles/pmtiles/geography-class-png.pmtiles"),
+            "geography-class-png",
+        )
+        .unwrap();
+        assert_eq!(tileset_details.tile_format, DataFormat::Png);
+        assert!(tileset_details.source.get_tile(1, 1, 1).unwrap().is_some());
+        assert!(tileset_details.source.get_tile(2, 1, 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn get_directory_metadata() {
+        let tileset_details = get_directory_details(
+            &PathBuf::from("./tiles/directory/world_cities"),
+            "world_cities",
+        )
+        .unwrap();
+        assert_eq!(
+            tileset_details.tilejson.name.unwrap(),
+            "Major cities from Natural Earth data"
+        );
+        assert_eq!(tileset_details.tilejson.maxzoom.unwrap(), 2);
+        assert_eq!(
+            tileset_details.tilejson.bounds.unwrap(),
+            Bounds::new(-123.123590, -37.818085, 174.763027, 59.352706)
+        );
+        assert_eq!(tileset_details.tile_format, DataFormat::Pbf);
+        assert!(tileset_details.json.unwrap()["vector_layers"].is_array());
+        assert!(tileset_details.modified.is_some());
+    }
+
+    /// Replace each tile of an mbtiles file with `rewrite` of its data
+    fn rewrite_tiles<F: Fn(Vec<u8>) -> Vec<u8>>(path: &Path, rewrite: F) {
+        let connection = rusqlite::Connection::open(path).unwrap();
+        let tiles: Vec<(u32, u32, u32, Vec<u8>)> = connection
+            .prepare("SELECT zoom_level, tile_column, tile_row, tile_data FROM tiles")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        for (z, x, y, data) in tiles {
+            connection
+                .execute(
+                    "UPDATE tiles SET tile_data = ?4 \
+                     WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                    params![z, x, y, rewrite(data)],
+                )
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn get_brotli_tileset() {
+        let dir = TempDir::new("tiles").unwrap();
+        let path = dir.path().join("world_cities.mbtiles");
+        copy("./tiles/world_cities.mbtiles", &path).unwrap();
+        rewrite_tiles(&path, |data| {
+            let data = decompress(&data, DataFormat::Gzip).unwrap();
+            compress(&data, DataFormat::Brotli).unwrap()
+        });
+
+        let tileset_details = get_tile_details(&path, "world_cities").unwrap();
+        assert_eq!(tileset_details.tile_format, DataFormat::Pbf);
+        assert_eq!(tileset_details.tile_encoding, ContentEncoding::Brotli);
+    }
+
+    #[test]
+    fn get_uncompressed_tileset() {
+        let dir = TempDir::new("tiles").unwrap();
+        let path = dir.path().join("world_cities.mbtiles");
+        copy("./tiles/world_cities.mbtiles", &path).unwrap();
+        rewrite_tiles(&path, |data| decompress(&data, DataFormat::Gzip).unwrap());
+
+        let tileset_details = get_tile_details(&path, "world_cities").unwrap();
+        assert_eq!(tileset_details.tile_format, DataFormat::Pbf);
+        assert_eq!(tileset_details.tile_encoding, ContentEncoding::Identity);
+    }
+
+    #[test]
+    fn get_mvt_directory() {
+        let dir = TempDir::new("tiles").unwrap();
+        let path = dir.path().join("cities");
+        let tile = path.join("0/0");
+        std::fs::create_dir_all(&tile).unwrap();
+        std::fs::write(path.join(METADATA_FILE), "{}").unwrap();
+        std::fs::write(tile.join("0.mvt"), b"\x1a\x05layer").unwrap();
+        let tileset_details = get_directory_details(&path, "cities").unwrap();
+        assert_eq!(tileset_details.tile_format, DataFormat::Pbf);
+    }
+
+    #[test]
+    fn tile_inside_tileset() {
+        let tileset_details = get_tile_details(
+            &PathBuf::from("./tiles/openstreetmap/open-streets-dc.mbtiles"),
+            "open-streets-dc",
+        )
+        .unwrap();
+        // Washington DC at zoom levels 7 to 12
+        assert!(tileset_details.contains_tile(7, 36, 48));
+        assert!(tileset_details.contains_tile(12, 1171, 1566));
+        assert!(!tileset_details.contains_tile(6, 18, 24));
+        assert!(!tileset_details.contains_tile(13, 2343, 3133));
+        assert!(!tileset_details.contains_tile(12, 0, 0));
+
+        let tileset_details = get_tile_details(
+            &PathBuf::from("./tiles/geography-class-png-no-bounds.mbtiles"),
+            "geography-class-png-no-bounds",
+        )
+        .unwrap();
+        assert!(tileset_details.contains_tile(1, 1, 1));
+        assert!(!tileset_details.contains_tile(2, 0, 0));
+    }
 }
@@ -0,0 +1,175 @@
+use std::fs::{metadata, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use log::{info, warn};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use rustls_pemfile::Item;
+
+use crate::errors::{Error, Result};
+
+/// Interval between checks of the certificate and key files for changes
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Modification times and sizes of the certificate and key files
+type Fingerprint = [(Option<SystemTime>, u64); 2];
+
+fn read_pem(path: &Path) -> Result<Vec<Item>> {
+    let file = File::open(path)
+        .map_err(|err| Error::Config(format!("Cannot read {}: {err}", path.display())))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(|err| Error::Config(format!("Invalid PEM file {}: {err}", path.display())))
+}
+
+/// Load a PEM certificate chain and private key (PKCS#8, PKCS#1 or SEC1)
+pub fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey> {
+    let certs: Vec<Certificate> = read_pem(cert_path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(cert) => Some(Certificate(cert)),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        return Err(Error::Config(format!(
+            "No certificate found in {}",
+            cert_path.display()
+        )));
+    }
+    let key = read_pem(key_path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| Error::Config(format!("No private key found in {}", key_path.display())))?;
+    let key = any_supported_type(&key)
+        .map_err(|err| Error::Config(format!("{}: {err}", key_path.display())))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Serves the current certificate and reloads it when its files change
+pub struct CertificateResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    certified_key: RwLock<Arc<CertifiedKey>>,
+    fingerprint: Mutex<Option<Fingerprint>>,
+}
+
+impl CertificateResolver {
+    pub fn new(cert_path: &Path, key_path: &Path) -> Result<CertificateResolver> {
+        let resolver = CertificateResolver {
+            cert_path: PathBuf::from(cert_path),
+            key_path: PathBuf::from(key_path),
+            certified_key: RwLock::new(Arc::new(load_certified_key(cert_path, key_path)?)),
+            fingerprint: Mutex::new(None),
+        };
+        *resolver.fingerprint.lock().unwrap() = resolver.current_fingerprint();
+        Ok(resolver)
+    }
+
+    fn current_fingerprint(&self) -> Option<Fingerprint> {
+        let file = |path: &Path| {
+            let meta = metadata(path).ok()?;
+            Some((meta.modified().ok(), meta.len()))
+        };
+        Some([file(&self.cert_path)?, file(&self.key_path)?])
+    }
+
+    /// Reload the certificate if its files changed since the previous check. If loading
+    /// fails, e.g. because only one of the files was replaced yet, the current certificate
+    /// is kept until the files change again. Returns whether the certificate was replaced.
+    pub fn reload(&self) -> bool {
+        let fingerprint = self.current_fingerprint();
+        let mut previous = self.fingerprint.lock().unwrap();
+        if fingerprint.is_none() || fingerprint == *previous {
+            return false;
+        }
+        *previous = fingerprint;
+        match load_certified_key(&self.cert_path, &self.key_path) {
+            Ok(certified_key) => {
+                *self.certified_key.write().unwrap() = Arc::new(certified_key);
+                info!("Reloaded TLS certificate {}", self.cert_path.display());
+                true
+            }
+            Err(err) => {
+                warn!("Keeping the current TLS certificate: {err}");
+                false
+            }
+        }
+    }
+
+    /// Check the certificate files for changes every `interval`
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let resolver = self.clone();
+            tokio::task::spawn_blocking(move || resolver.reload())
+                .await
+                .unwrap();
+        }
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.certified_key.read().unwrap().clone())
+    }
+}
+
+/// Build a TLS configuration offering HTTP/2 and HTTP/1.1 over ALPN
+pub fn server_config(resolver: Arc<CertificateResolver>) -> Arc<ServerConfig> {
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Arc::new(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::copy;
+    use tempdir::TempDir;
+
+    #[test]
+    fn reload_certificate() {
+        let dir = TempDir::new("tls").unwrap();
+        let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        copy("./testdata/tls/cert.pem", &cert).unwrap();
+        copy("./testdata/tls/key.pem", &key).unwrap();
+        let resolver = CertificateResolver::new(&cert, &key).unwrap();
+        let current = || resolver.certified_key.read().unwrap().cert[0].clone();
+        let before = current();
+        assert!(!resolver.reload());
+
+        // A broken key is not loaded
+        std::fs::write(&key, "not a key").unwrap();
+        assert!(!resolver.reload());
+        assert_eq!(current(), before);
+
+        copy("./testdata/tls/cert2.pem", &cert).unwrap();
+        copy("./testdata/tls/key2.pem", &key).unwrap();
+        assert!(resolver.reload());
+        assert_ne!(current(), before);
+    }
+
+    #[test]
+    fn invalid_certificate() {
+        let cert = Path::new("./testdata/tls/cert.pem");
+        let key = Path::new("./testdata/tls/key.pem");
+        assert!(load_certified_key(cert, key).is_ok());
+        let err = load_certified_key(key, key).err().unwrap();
+        assert!(err.to_string().contains("No certificate found"), "{err}");
+        let err = load_certified_key(cert, cert).err().unwrap();
+        assert!(err.to_string().contains("No private key found"), "{err}");
+        assert!(load_certified_key(Path::new("./missing.pem"), key).is_err());
+    }
+}
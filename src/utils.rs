@@ -1,12 +1,20 @@
 use std::io::prelude::*;
 
 use flate2::read::{GzDecoder, ZlibDecoder};
-use flate2::write::GzEncoder;
+use flate2::write::{GzEncoder, ZlibEncoder};
 use flate2::Compression;
 use serde::{Deserialize, Serialize};
 
 use crate::errors::{Error, Result};
 
+/// Compression settings favouring speed, as responses are compressed on the fly
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW_SIZE: u32 = 22;
+const ZSTD_LEVEL: i32 = 3;
+
+/// First byte of a vector tile: field 3 (`layers`), length delimited
+const VECTOR_TILE_LAYER_TAG: u8 = 0x1a;
+
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum DataFormat {
@@ -17,6 +25,8 @@ pub enum DataFormat {
     Pbf,
     Gzip,
     Zlib,
+    Brotli,
+    Zstd,
     Unknown,
 }
 
@@ -30,6 +40,8 @@ impl DataFormat {
             "pbf" => DataFormat::Pbf,
             "gzip" => DataFormat::Gzip,
             "zlib" => DataFormat::Zlib,
+            "br" | "brotli" => DataFormat::Brotli,
+            "zstd" => DataFormat::Zstd,
             _ => DataFormat::Unknown,
         }
     }
@@ -43,6 +55,8 @@ impl DataFormat {
             DataFormat::Pbf => "pbf",
             DataFormat::Gzip => "",
             DataFormat::Zlib => "",
+            DataFormat::Brotli => "",
+            DataFormat::Zstd => "",
             DataFormat::Unknown => "",
         }
     }
@@ -56,51 +70,101 @@ impl DataFormat {
             DataFormat::Pbf => "application/x-protobuf",
             DataFormat::Gzip => "",
             DataFormat::Zlib => "",
+            DataFormat::Brotli => "",
+            DataFormat::Zstd => "",
             DataFormat::Unknown => "",
         }
     }
 }
 
-pub fn decode(data: Vec<u8>, data_type: DataFormat) -> Result<String> {
+/// Decompress `data` compressed with `data_type` (gzip, zlib, brotli or zstd)
+pub fn decompress(data: &[u8], data_type: DataFormat) -> Result<Vec<u8>> {
+    decompress_limited(data, data_type, u64::MAX)
+}
+
+/// Decompress `data` like [`decompress`], failing once it decodes to more than `limit` bytes
+pub fn decompress_limited(data: &[u8], data_type: DataFormat, limit: u64) -> Result<Vec<u8>> {
+    let io_error = |err: std::io::Error| Error::InvalidDataFormat(format!("{data_type:?}: {err}"));
+    let decoder: Box<dyn Read + '_> = match data_type {
+        DataFormat::Gzip => Box::new(GzDecoder::new(data)),
+        DataFormat::Zlib => Box::new(ZlibDecoder::new(data)),
+        DataFormat::Brotli => Box::new(brotli::Decompressor::new(data, 4096)),
+        DataFormat::Zstd => Box::new(zstd::stream::read::Decoder::new(data).map_err(io_error)?),
+        _ => return Err(Error::InvalidDataFormat(data_type.format().to_string())),
+    };
+    let mut decoded = Vec::new();
+    decoder
+        .take(limit.saturating_add(1))
+        .read_to_end(&mut decoded)
+        .map_err(io_error)?;
+    if decoded.len() as u64 > limit {
+        return Err(Error::InvalidDataFormat(format!(
+            "{data_type:?}: decompressed data exceeds {limit} bytes"
+        )));
+    }
+    Ok(decoded)
+}
+
+/// Compress `data` with `data_type` (gzip, zlib, brotli or zstd)
+pub fn compress(data: &[u8], data_type: DataFormat) -> Result<Vec<u8>> {
+    let io_error = |err: std::io::Error| Error::InvalidDataFormat(format!("{data_type:?}: {err}"));
     match data_type {
         DataFormat::Gzip => {
-            let mut z = GzDecoder::new(&data[..]);
-            let mut s = String::new();
-            z.read_to_string(&mut s).unwrap();
-            Ok(s)
+            let mut e = GzEncoder::new(Vec::new(), Compression::default());
+            e.write_all(data).map_err(io_error)?;
+            e.finish().map_err(io_error)
         }
         DataFormat::Zlib => {
-            let mut z = ZlibDecoder::new(&data[..]);
-            let mut s = String::new();
-            z.read_to_string(&mut s).unwrap();
-            Ok(s)
+            let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
+            e.write_all(data).map_err(io_error)?;
+            e.finish().map_err(io_error)
+        }
+        DataFormat::Brotli => {
+            let mut encoded = Vec::new();
+            let mut e = brotli::CompressorWriter::new(
+                &mut encoded,
+                4096,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW_SIZE,
+            );
+            e.write_all(data).map_err(io_error)?;
+            drop(e);
+            Ok(encoded)
         }
+        DataFormat::Zstd => zstd::encode_all(data, ZSTD_LEVEL).map_err(io_error),
         _ => Err(Error::InvalidDataFormat(data_type.format().to_string())),
     }
 }
 
-pub fn encode(data: &[u8]) -> Vec<u8> {
-    let mut e = GzEncoder::new(Vec::new(), Compression::default());
-    e.write_all(data).unwrap();
-    e.finish().unwrap()
+pub fn decode(data: Vec<u8>, data_type: DataFormat) -> Result<String> {
+    String::from_utf8(decompress(&data, data_type)?)
+        .map_err(|err| Error::InvalidDataFormat(format!("{data_type:?}: {err}")))
 }
 
+/// Check whether `data` is a brotli compressed vector tile. Brotli streams have no magic
+/// number, so the data is decompressed and checked for a leading vector tile layer.
+pub fn is_brotli_vector_tile(data: &[u8]) -> bool {
+    match decompress(data, DataFormat::Brotli) {
+        Ok(decoded) => decoded.first() == Some(&VECTOR_TILE_LAYER_TAG),
+        Err(_) => false,
+    }
+}
+
+/// Detect the format of `data` from its first bytes. Uncompressed vector tiles are `Pbf`,
+/// brotli compressed data is `Unknown`.
 pub fn get_data_format(data: &[u8]) -> DataFormat {
     match data {
-        v if &v[0..2] == b"\x1f\x8b" => DataFormat::Gzip,
-        v if &v[0..2] == b"\x78\x9c" => DataFormat::Zlib,
-        v if &v[0..8] == b"\x89\x50\x4E\x47\x0D\x0A\x1A\x0A" => DataFormat::Png,
-        v if &v[0..3] == b"\xFF\xD8\xFF" => DataFormat::Jpg,
-        v if &v[0..4] == b"RIFF" && &v[8..12] == b"WEBP" => DataFormat::Webp,
+        v if v.starts_with(b"\x1f\x8b") => DataFormat::Gzip,
+        v if v.starts_with(b"\x78\x9c") => DataFormat::Zlib,
+        v if v.starts_with(b"\x28\xb5\x2f\xfd") => DataFormat::Zstd,
+        v if v.starts_with(b"\x89\x50\x4E\x47\x0D\x0A\x1A\x0A") => DataFormat::Png,
+        v if v.starts_with(b"\xFF\xD8\xFF") => DataFormat::Jpg,
+        v if v.starts_with(b"RIFF") && v.get(8..12) == Some(b"WEBP") => DataFormat::Webp,
+        v if v.first() == Some(&VECTOR_TILE_LAYER_TAG) => DataFormat::Pbf,
         _ => DataFormat::Unknown,
     }
 }
 
-pub fn get_blank_image() -> Vec<u8> {
-    let image = b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR\x00\x00\x01\x00\x00\x00\x01\x00\x01\x03\x00\x00\x00f\xbc:%\x00\x00\x00\x03PLTE\x00\x00\x00\xa7z=\xda\x00\x00\x00\x01tRNS\x00@\xe6\xd8f\x00\x00\x00\x1fIDATh\xde\xed\xc1\x01\r\x00\x00\x00\xc2 \xfb\xa76\xc77`\x00\x00\x00\x00\x00\x00\x00\x00q\x07!\x00\x00\x01\xa7W)\xd7\x00\x00\x00\x00IEND\xaeB`\x82";
-    image.to_vec()
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -122,6 +186,12 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_data_format_short_data() {
+        assert_eq!(get_data_format(b""), DataFormat::Unknown);
+        assert_eq!(get_data_format(b"RIFF"), DataFormat::Unknown);
+    }
+
     #[test]
     fn test_data_format_webp() {
         assert_eq!(
@@ -129,4 +199,34 @@ mod tests {
             DataFormat::Webp
         );
     }
+
+    #[test]
+    fn test_data_format_pbf() {
+        assert_eq!(get_data_format(b"\x1a\x05layer"), DataFormat::Pbf);
+    }
+
+    #[test]
+    fn test_compression_round_trip() {
+        let data = b"\x1a\x05layer".to_vec();
+        for data_type in [
+            DataFormat::Gzip,
+            DataFormat::Zlib,
+            DataFormat::Brotli,
+            DataFormat::Zstd,
+        ] {
+            let compressed = compress(&data, data_type).unwrap();
+            assert_eq!(decompress(&compressed, data_type).unwrap(), data);
+            let limit = data.len() as u64;
+            assert!(decompress_limited(&compressed, data_type, limit).is_ok());
+            assert!(decompress_limited(&compressed, data_type, limit - 1).is_err());
+        }
+        assert_eq!(
+            get_data_format(&compress(&data, DataFormat::Zstd).unwrap()),
+            DataFormat::Zstd
+        );
+        assert!(is_brotli_vector_tile(
+            &compress(&data, DataFormat::Brotli).unwrap()
+        ));
+        assert!(!is_brotli_vector_tile(&data));
+    }
 }
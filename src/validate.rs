@@ -0,0 +1,486 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
+use serde_json::Value as JSONValue;
+use tilejson::{Bounds, Center};
+
+use crate::tiles::discover_tileset_paths;
+use crate::utils::{get_data_format, DataFormat};
+
+/// Maximum number of problems reported by `PRAGMA integrity_check`
+const MAX_INTEGRITY_ERRORS: u32 = 10;
+
+/// Columns of the tables (or views) required by the MBTiles 1.3 specification
+const SCHEMA: [(&str, &[&str]); 2] = [
+    ("metadata", &["name", "value"]),
+    (
+        "tiles",
+        &["zoom_level", "tile_column", "tile_row", "tile_data"],
+    ),
+];
+
+#[derive(Debug, Serialize)]
+pub struct Issue {
+    /// Name of the check which found the issue
+    pub check: &'static str,
+    pub message: String,
+}
+
+/// Result of validating an mbtiles file. The file is valid if there are no errors;
+/// warnings are deviations from the specification which don't keep it from being served.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub path: PathBuf,
+    pub valid: bool,
+    pub errors: Vec<Issue>,
+    pub warnings: Vec<Issue>,
+    /// Number of tiles by format, detected from their contents
+    pub formats: BTreeMap<&'static str, u64>,
+    /// Number of tiles by zoom level
+    pub zoom_levels: BTreeMap<u32, u64>,
+}
+
+impl Report {
+    fn error(&mut self, check: &'static str, message: String) {
+        self.errors.push(Issue { check, message });
+    }
+
+    fn warning(&mut self, check: &'static str, message: String) {
+        self.warnings.push(Issue { check, message });
+    }
+}
+
+/// Name of the format of a tile, from its first bytes. Brotli compressed tiles can't be
+/// told apart from other data without decompressing them and are `unknown`.
+fn format_name(data: &[u8]) -> &'static str {
+    match get_data_format(data) {
+        DataFormat::Png => "png",
+        DataFormat::Jpg => "jpg",
+        DataFormat::Webp => "webp",
+        DataFormat::Gzip => "gzip",
+        DataFormat::Zlib => "zlib",
+        DataFormat::Zstd => "zstd",
+        DataFormat::Pbf => "pbf",
+        _ => "unknown",
+    }
+}
+
+/// Read metadata values, which are text in valid files but can have any type in SQLite
+fn read_metadata(connection: &Connection, report: &mut Report) -> BTreeMap<String, String> {
+    let mut metadata = BTreeMap::new();
+    let result = connection
+        .prepare(r#"SELECT name, value FROM metadata"#)
+        .and_then(|mut statement| {
+            let mut rows = statement.query([])?;
+            while let Some(row) = rows.next()? {
+                let name: String = match row.get_ref(0)? {
+                    ValueRef::Text(name) => String::from_utf8_lossy(name).to_string(),
+                    _ => {
+                        report.error("metadata", "metadata name is not text".to_string());
+                        continue;
+                    }
+                };
+                let value = match row.get_ref(1)? {
+                    ValueRef::Text(value) => String::from_utf8_lossy(value).to_string(),
+                    ValueRef::Integer(value) => value.to_string(),
+                    ValueRef::Real(value) => value.to_string(),
+                    ValueRef::Null => continue,
+                    ValueRef::Blob(_) => {
+                        report.warning("metadata", format!("{name}: value is a blob"));
+                        continue;
+                    }
+                };
+                if metadata.insert(name.clone(), value).is_some() {
+                    report.warning("metadata", format!("{name}: duplicate entry"));
+                }
+            }
+            Ok(())
+        });
+    if let Err(err) = result {
+        report.error("metadata", err.to_string());
+    }
+    metadata
+}
+
+/// Check the types and ranges of metadata values. Returns the declared zoom range.
+fn check_metadata(
+    metadata: &BTreeMap<String, String>,
+    report: &mut Report,
+) -> (Option<u8>, Option<u8>) {
+    for name in ["name", "format"] {
+        if !metadata.contains_key(name) {
+            report.warning("metadata", format!("{name} is missing"));
+        }
+    }
+    if let Some(bounds) = metadata.get("bounds") {
+        match Bounds::from_str(bounds) {
+            Ok(b) => {
+                let longitudes =
+                    (-180.0..=180.0).contains(&b.left) && (-180.0..=180.0).contains(&b.right);
+                let latitudes =
+                    (-90.0..=90.0).contains(&b.bottom) && (-90.0..=90.0).contains(&b.top);
+                if !longitudes || !latitudes {
+                    report.error("metadata", format!("bounds {bounds} are out of range"));
+                } else if b.bottom > b.top {
+                    report.error("metadata", format!("bounds {bounds}: bottom is above top"));
+                } else if b.left > b.right {
+                    report.warning(
+                        "metadata",
+                        format!("bounds {bounds} cross the antimeridian"),
+                    );
+                }
+            }
+            Err(err) => report.error("metadata", format!("bounds {bounds}: {err:?}")),
+        }
+    }
+    if let Some(center) = metadata.get("center") {
+        match Center::from_str(center) {
+            Ok(c)
+                if (-180.0..=180.0).contains(&c.longitude)
+                    && (-90.0..=90.0).contains(&c.latitude) => {}
+            Ok(_) => report.error("metadata", format!("center {center} is out of range")),
+            Err(err) => report.error("metadata", format!("center {center}: {err:?}")),
+        }
+    }
+    let mut zoom = |name: &str| {
+        let value = metadata.get(name)?;
+        match value.parse::<u8>() {
+            Ok(zoom) if zoom <= 30 => Some(zoom),
+            _ => {
+                let message = format!("{name} {value} is not a zoom level from 0 to 30");
+                report.error("metadata", message);
+                None
+            }
+        }
+    };
+    let (minzoom, maxzoom) = (zoom("minzoom"), zoom("maxzoom"));
+    if let (Some(min), Some(max)) = (minzoom, maxzoom) {
+        if min > max {
+            report.error("metadata", format!("minzoom {min} is above maxzoom {max}"));
+        }
+    }
+    let vector = metadata.get("format").map(String::as_str) == Some("pbf");
+    match metadata.get("json").map(|json| serde_json::from_str(json)) {
+        Some(Ok(JSONValue::Object(json)))
+            if vector && !json.get("vector_layers").is_some_and(JSONValue::is_array) =>
+        {
+            report.warning("metadata", "json has no vector_layers".to_string());
+        }
+        Some(Ok(JSONValue::Object(_))) => (),
+        Some(Ok(_)) => report.error("metadata", "json is not an object".to_string()),
+        Some(Err(err)) => report.error("metadata", format!("json: {err}")),
+        None if vector => {
+            report.warning("metadata", "json is missing".to_string());
+        }
+        None => (),
+    }
+    if let Some(format) = metadata.get("format") {
+        if !matches!(format.as_str(), "png" | "jpg" | "webp" | "pbf") {
+            report.warning("metadata", format!("unknown format {format}"));
+        }
+    }
+    (minzoom, maxzoom)
+}
+
+/// Count the tiles by format and zoom level, and check their coordinates
+fn scan_tiles(connection: &Connection, report: &mut Report) {
+    let mut empty = 0;
+    let mut outside_grid = 0;
+    let result = connection
+        .prepare(r#"SELECT zoom_level, tile_column, tile_row, substr(tile_data, 1, 16) FROM tiles"#)
+        .and_then(|mut statement| {
+            let mut rows = statement.query([])?;
+            while let Some(row) = rows.next()? {
+                let z: i64 = row.get(0)?;
+                let (x, y): (i64, i64) = (row.get(1)?, row.get(2)?);
+                if !(0..=30).contains(&z) || !(0..1 << z).contains(&x) || !(0..1 << z).contains(&y)
+                {
+                    outside_grid += 1;
+                    continue;
+                }
+                match row.get_ref(3)? {
+                    ValueRef::Blob(data) if !data.is_empty() => {
+                        *report.formats.entry(format_name(data)).or_default() += 1
+                    }
+                    _ => empty += 1,
+                }
+                *report.zoom_levels.entry(z as u32).or_default() += 1;
+            }
+            Ok(())
+        });
+    if let Err(err) = result {
+        report.error("tiles", err.to_string());
+    }
+    if outside_grid > 0 {
+        let message = format!("{outside_grid} tiles have coordinates outside of the tile grid");
+        report.error("tiles", message);
+    }
+    if empty > 0 {
+        report.warning("tiles", format!("{empty} tiles are empty"));
+    }
+}
+
+/// Check that all tiles share one format, which matches the declared format
+fn check_formats(declared: Option<&str>, report: &mut Report) {
+    let formats: Vec<&str> = report.formats.keys().copied().collect();
+    match formats[..] {
+        [] => report.warning("tiles", "there are no tiles".to_string()),
+        [format] => {
+            let matches = match declared {
+                Some("pbf") => !matches!(format, "png" | "jpg" | "webp"),
+                Some(declared @ ("png" | "jpg" | "webp")) => format == declared,
+                _ => true,
+            };
+            if !matches {
+                let message = format!("format is {}, but tiles are {format}", declared.unwrap());
+                report.error("formats", message);
+            }
+        }
+        _ => {
+            let counts: Vec<String> = report
+                .formats
+                .iter()
+                .map(|(format, count)| format!("{count} {format}"))
+                .collect();
+            let message = format!("tiles have several formats: {}", counts.join(", "));
+            report.error("formats", message);
+        }
+    }
+}
+
+/// Check that the declared zoom range matches the zoom levels of the tiles
+fn check_zoom_levels(minzoom: Option<u8>, maxzoom: Option<u8>, report: &mut Report) {
+    if report.zoom_levels.is_empty() {
+        return;
+    }
+    let levels: Vec<u32> = report.zoom_levels.keys().copied().collect();
+    for (name, zoom) in [("minzoom", minzoom), ("maxzoom", maxzoom)] {
+        if let Some(zoom) = zoom {
+            if !report.zoom_levels.contains_key(&u32::from(zoom)) {
+                report.error("zoom", format!("{name} {zoom} has no tiles"));
+            }
+        }
+    }
+    let min = minzoom.map_or(levels[0], u32::from);
+    let max = maxzoom.map_or(levels[levels.len() - 1], u32::from);
+    let outside: Vec<String> = levels
+        .iter()
+        .filter(|&&z| z < min || z > max)
+        .map(u32::to_string)
+        .collect();
+    if !outside.is_empty() {
+        let message = format!(
+            "zoom levels {} are outside of the declared zoom range, their tiles are not served",
+            outside.join(", ")
+        );
+        report.warning("zoom", message);
+    }
+    let missing: Vec<String> = (min..=max)
+        .filter(|z| !report.zoom_levels.contains_key(z))
+        .map(|z| z.to_string())
+        .collect();
+    if !missing.is_empty() && min <= max {
+        let message = format!("zoom levels {} have no tiles", missing.join(", "));
+        report.warning("zoom", message);
+    }
+}
+
+/// Validate the mbtiles file at `path`: the integrity of the database, the MBTiles 1.3
+/// schema, the types of metadata values, the formats of the tiles and the declared zoom
+/// levels. Later checks are skipped if the database or its schema are broken.
+pub fn validate_mbtiles(path: &Path) -> Report {
+    let mut report = Report {
+        path: PathBuf::from(path),
+        ..Default::default()
+    };
+    let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    let connection = match Connection::open_with_flags(path, flags) {
+        Ok(connection) => connection,
+        Err(err) => {
+            report.error("open", err.to_string());
+            return report;
+        }
+    };
+
+    let query = format!("PRAGMA integrity_check({MAX_INTEGRITY_ERRORS})");
+    let result = connection.prepare(&query).and_then(|mut statement| {
+        let rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+        rows.collect::<rusqlite::Result<Vec<String>>>()
+    });
+    match result {
+        Ok(rows) if rows == ["ok"] => (),
+        Ok(rows) => {
+            for row in rows {
+                report.error("integrity", row);
+            }
+        }
+        Err(err) => report.error("integrity", err.to_string()),
+    }
+    if !report.errors.is_empty() {
+        return report;
+    }
+
+    for (table, columns) in SCHEMA {
+        let result = connection
+            .prepare(&format!("PRAGMA table_info({table})"))
+            .and_then(|mut statement| {
+                let rows = statement.query_map([], |row| row.get::<_, String>(1))?;
+                rows.collect::<rusqlite::Result<Vec<String>>>()
+            });
+        match result {
+            Ok(found) if found.is_empty() => report.error("schema", format!("{table} is missing")),
+            Ok(found) => {
+                for column in columns.iter().filter(|c| !found.iter().any(|f| f == *c)) {
+                    report.error("schema", format!("{table} has no {column} column"));
+                }
+            }
+            Err(err) => report.error("schema", err.to_string()),
+        }
+    }
+    if !report.errors.is_empty() {
+        return report;
+    }
+
+    let metadata = read_metadata(&connection, &mut report);
+    let (minzoom, maxzoom) = check_metadata(&metadata, &mut report);
+    scan_tiles(&connection, &mut report);
+    check_formats(metadata.get("format").map(String::as_str), &mut report);
+    check_zoom_levels(minzoom, maxzoom, &mut report);
+    report.valid = report.errors.is_empty();
+    report
+}
+
+/// Validate the mbtiles files at `paths`, or in `directory` if no paths are given, and
+/// print the reports as JSON. Returns the exit status: 0 if all files are valid, or 1.
+pub fn run(paths: &[PathBuf], directory: &Path) -> i32 {
+    let paths = match paths.is_empty() {
+        true => vec![PathBuf::from(directory)],
+        false => paths.to_vec(),
+    };
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let mut found: Vec<PathBuf> = discover_tileset_paths(String::new(), &path)
+                .into_values()
+                .filter(|p| p.extension().and_then(OsStr::to_str) == Some("mbtiles"))
+                .collect();
+            found.sort();
+            files.append(&mut found);
+        } else {
+            files.push(path);
+        }
+    }
+    let reports: Vec<Report> = files.iter().map(|path| validate_mbtiles(path)).collect();
+    println!("{}", serde_json::to_string_pretty(&reports).unwrap());
+    match reports.iter().all(|report| report.valid) {
+        true => 0,
+        false => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn messages(issues: &[Issue]) -> Vec<String> {
+        issues
+            .iter()
+            .map(|issue| format!("{}: {}", issue.check, issue.message))
+            .collect()
+    }
+
+    #[test]
+    fn validate_valid_files() {
+        let report = validate_mbtiles(Path::new("./tiles/world_cities.mbtiles"));
+        assert!(report.valid, "{:?}", report.errors);
+        assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+        assert_eq!(report.formats, BTreeMap::from([("gzip", 196)]));
+        assert_eq!(report.zoom_levels[&6], 72);
+
+        // Raster tilesets without a format entry are valid
+        let report = validate_mbtiles(Path::new("./tiles/geography-class-png.mbtiles"));
+        assert!(report.valid, "{:?}", report.errors);
+        assert_eq!(messages(&report.warnings), ["metadata: format is missing"]);
+        assert_eq!(report.formats, BTreeMap::from([("png", 5)]));
+    }
+
+    #[test]
+    fn validate_invalid_files() {
+        let report = validate_mbtiles(Path::new("./tiles/invalid.mbtiles"));
+        assert!(!report.valid);
+        assert_eq!(
+            messages(&report.errors),
+            ["schema: metadata is missing", "schema: tiles is missing"]
+        );
+
+        let dir = TempDir::new("validate").unwrap();
+        let path = dir.path().join("corrupt.mbtiles");
+        std::fs::write(&path, vec![1; 4096]).unwrap();
+        let report = validate_mbtiles(&path);
+        assert!(!report.valid);
+        assert_eq!(report.errors[0].check, "integrity");
+    }
+
+    #[test]
+    fn validate_contents() {
+        let dir = TempDir::new("validate").unwrap();
+        let path = dir.path().join("tiles.mbtiles");
+        std::fs::copy("./tiles/geography-class-png.mbtiles", &path).unwrap();
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .execute_batch(
+                r#"
+                UPDATE metadata SET value = '-180,-85,180' WHERE name = 'bounds';
+                UPDATE metadata SET value = 'a' WHERE name = 'minzoom';
+                UPDATE metadata SET value = '3' WHERE name = 'maxzoom';
+                INSERT INTO metadata VALUES ('json', '{');
+                INSERT INTO metadata VALUES ('format', 'png');
+                INSERT INTO images VALUES (X'FFD8FF00', 'jpg');
+                INSERT INTO map VALUES (2, 0, 0, 'jpg', NULL);
+                INSERT INTO map VALUES (1, 5, 0, 'jpg', NULL);
+                "#,
+            )
+            .unwrap();
+        let report = validate_mbtiles(&path);
+        assert!(!report.valid);
+        let errors = messages(&report.errors);
+        assert!(
+            errors[0].starts_with("metadata: bounds -180,-85,180"),
+            "{errors:?}"
+        );
+        assert_eq!(
+            errors[1..],
+            [
+                "metadata: minzoom a is not a zoom level from 0 to 30",
+                "metadata: json: EOF while parsing an object at line 1 column 1",
+                "tiles: 1 tiles have coordinates outside of the tile grid",
+                "formats: tiles have several formats: 1 jpg, 5 png",
+                "zoom: maxzoom 3 has no tiles",
+            ]
+        );
+        assert_eq!(BTreeMap::from([(0, 1), (1, 4), (2, 1)]), report.zoom_levels);
+    }
+
+    #[test]
+    fn validate_directory() {
+        let dir = TempDir::new("validate").unwrap();
+        std::fs::create_dir(dir.path().join("nested")).unwrap();
+        let path = dir.path().join("nested/world_cities.mbtiles");
+        std::fs::copy("./tiles/world_cities.mbtiles", path).unwrap();
+        std::fs::copy(
+            "./tiles/pmtiles/world_cities.pmtiles",
+            dir.path().join("a.pmtiles"),
+        )
+        .unwrap();
+        assert_eq!(run(&[], dir.path()), 0);
+        std::fs::write(dir.path().join("broken.mbtiles"), vec![1; 4096]).unwrap();
+        assert_eq!(run(&[], dir.path()), 1);
+        assert_eq!(run(&[dir.path().join("nested")], Path::new("./missing")), 0);
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::metadata;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use log::{info, warn};
+
+use crate::directory::METADATA_FILE;
+use crate::registry::Registry;
+use crate::tiles::{find_tileset_paths, load_tileset, tileset_name, warn_duplicate, TileMeta};
+
+/// Modification time and size of a tileset file, or of the metadata of a tile directory
+type Fingerprint = (Option<SystemTime>, u64);
+
+/// Keeps track of the tileset files found in a directory and reports which of them
+/// were added, modified or removed since the previous scan
+pub struct Watcher {
+    directory: PathBuf,
+    known: HashMap<String, (PathBuf, Fingerprint)>,
+    pending: HashMap<String, (PathBuf, Fingerprint)>,
+    /// Paths skipped because their id is taken, so each is only reported once
+    duplicates: HashSet<PathBuf>,
+}
+
+fn fingerprint(path: &Path) -> Option<Fingerprint> {
+    let meta = if path.is_dir() {
+        metadata(path.join(METADATA_FILE)).ok()?
+    } else {
+        metadata(path).ok()?
+    };
+    Some((meta.modified().ok(), meta.len()))
+}
+
+impl Watcher {
+    /// Create a watcher that considers the current contents of `directory` as already loaded
+    pub fn new(directory: PathBuf) -> Watcher {
+        let mut watcher = Watcher {
+            directory,
+            known: HashMap::new(),
+            pending: HashMap::new(),
+            duplicates: HashSet::new(),
+        };
+        watcher.known = watcher.scan();
+        watcher
+    }
+
+    fn scan(&mut self) -> HashMap<String, (PathBuf, Fingerprint)> {
+        let (paths, duplicates) = find_tileset_paths(String::new(), &self.directory);
+        let mut reported = HashSet::new();
+        for (id, duplicate) in duplicates {
+            if !self.duplicates.contains(&duplicate) {
+                warn_duplicate(&id, &duplicate, &paths[&id]);
+            }
+            reported.insert(duplicate);
+        }
+        self.duplicates = reported;
+        paths
+            .into_iter()
+            .filter_map(|(id, path)| fingerprint(&path).map(|f| (id, (path, f))))
+            .collect()
+    }
+
+    /// Scan the directory and apply the changes to the registry.
+    ///
+    /// A new or modified file is only loaded once its size and modification time are the
+    /// same in two consecutive scans, so files that are still being copied are not opened.
+    pub fn poll(&mut self, registry: &Registry) {
+        let current = self.scan();
+        let mut loaded: Vec<(String, TileMeta)> = Vec::new();
+        let mut removed: Vec<(String, PathBuf)> = Vec::new();
+
+        for (id, (path, _)) in &self.known {
+            if !current.contains_key(id) {
+                removed.push((id.clone(), path.clone()));
+            }
+        }
+        self.pending.retain(|id, _| current.contains_key(id));
+
+        for (id, (path, fingerprint)) in &current {
+            if self.known.get(id).map(|(_, f)| f) == Some(fingerprint) {
+                self.pending.remove(id);
+                continue;
+            }
+            match self.pending.get(id) {
+                Some((_, pending)) if pending == fingerprint => {
+                    self.pending.remove(id);
+                    let file_name = tileset_name(path).unwrap_or_default();
+                    match load_tileset(path, file_name) {
+                        Ok(tile_meta) => loaded.push((id.clone(), tile_meta)),
+                        Err(err) => {
+                            warn!("{err}");
+                            removed.push((id.clone(), path.clone()));
+                        }
+                    }
+                    self.known.insert(id.clone(), (path.clone(), *fingerprint));
+                }
+                _ => {
+                    self.pending
+                        .insert(id.clone(), (path.clone(), *fingerprint));
+                }
+            }
+        }
+        self.known.retain(|id, _| current.contains_key(id));
+
+        if loaded.is_empty() && removed.is_empty() {
+            return;
+        }
+        registry.update(|tilesets| {
+            for (id, path) in removed {
+                // Only drop the entry if it still refers to the file that went away
+                if tilesets.get(&id).map(|t| t.path == path) == Some(true) {
+                    tilesets.remove(&id);
+                    info!("Removed tileset {id}");
+                }
+            }
+            for (id, tile_meta) in loaded {
+                info!("Loaded tileset {id}");
+                tilesets.insert(id, tile_meta);
+            }
+        });
+    }
+}
+
+/// Poll the tiles directory every `interval` and keep the registry in sync with it
+pub async fn watch(directory: PathBuf, registry: Registry, interval: Duration) {
+    let mut watcher = tokio::task::spawn_blocking(move || Watcher::new(directory))
+        .await
+        .unwrap();
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let registry = registry.clone();
+        watcher = tokio::task::spawn_blocking(move || {
+            watcher.poll(&registry);
+            watcher
+        })
+        .await
+        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiles::discover_tilesets;
+    use std::fs::{copy, remove_file};
+    use tempdir::TempDir;
+
+    #[test]
+    fn reload_changed_tilesets() {
+        let dir = TempDir::new("tiles").unwrap();
+        copy(
+            "./tiles/geography-class-png.mbtiles",
+            dir.path().join("geography-class-png.mbtiles"),
+        )
+        .unwrap();
+        let directory = dir.path().to_path_buf();
+        let registry = Registry::new(discover_tilesets(String::new(), &directory));
+        let mut watcher = Watcher::new(directory);
+
+        copy(
+            "./tiles/world_cities.mbtiles",
+            dir.path().join("world_cities.mbtiles"),
+        )
+        .unwrap();
+        // New files are loaded once they are seen unchanged in two scans
+        watcher.poll(&registry);
+        assert!(!registry.contains("world_cities"));
+        watcher.poll(&registry);
+        assert!(registry.contains("world_cities"));
+
+        remove_file(dir.path().join("geography-class-png.mbtiles")).unwrap();
+        watcher.poll(&registry);
+        assert!(!registry.contains("geography-class-png"));
+        assert!(registry.contains("world_cities"));
+    }
+}